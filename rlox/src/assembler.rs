@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use chunk::*;
+use compiler::Upvalue;
+use object::Function;
+use object::LoxString;
+use object::FunctionType;
+use object::ObjType;
+use value::Unpacked;
+use value::Value;
+
+// The textual form of a compiled script is the disassembler's listing of every
+// function, outermost first, each nested function following its parent in the
+// order of its constants. The script also names the global slots its code uses:
+//
+//   == <script> ==
+//   global      0 "clock"
+//   global      1 "fib"
+//   constant    0 function fib/1
+//   0000    1 CLOSURE             0 '<fn fib/1>'
+//   0004    | DEFINE_GLOBAL       1 'fib'
+//   ...
+//
+//   == fib/1 ==
+//   ...
+//
+// Hand-written listings may leave out the offset and line columns, use `name:`
+// labels as jump targets (`JUMP -> name`), and add `//` comments. Constant indexes
+// decide between the short and the `*_LONG` instructions, so the listing of a
+// chunk assembles back to the very same bytes.
+
+pub fn disassemble(function: &Function, globals: &[String]) -> String {
+    let mut out = String::new();
+    listing(function, globals, &mut out);
+    out
+}
+
+fn listing(function: &Function, globals: &[String], out: &mut String) {
+    match &function.name {
+        Some(name) => out.push_str(&format!("== {}/{} ==\n", name, function.arity)),
+        None => {
+            out.push_str("== <script> ==\n");
+            for (slot, name) in globals.iter().enumerate() {
+                out.push_str(&format!("global {:6} {:?}\n", slot, name));
+            }
+        }
+    }
+    for (index, value) in function.chunk.values.iter().enumerate() {
+        out.push_str(&format!("constant {:4} {}\n", index, constant(value)));
+    }
+    out.push_str(&function.chunk.listing(globals));
+
+    for value in function.chunk.values.iter() {
+        if let Some(ObjType::Function(nested)) = value.as_object() {
+            out.push('\n');
+            listing(nested, globals, out);
+        }
+    }
+}
+
+fn constant(value: &Value) -> String {
+    match value.unpack() {
+        Unpacked::Nil => String::from("nil"),
+        Unpacked::Bool(b) => b.to_string(),
+        // `{:?}` keeps every digit, so the number reads back exactly
+        Unpacked::Number(n) => format!("number {:?}", n),
+        Unpacked::Object(ObjType::String(s)) => format!("string {:?}", s.as_str()),
+        Unpacked::Object(ObjType::Function(f)) => {
+            format!("function {}/{}", f.name.as_deref().unwrap_or("<script>"), f.arity)
+        }
+        _ => format!("unsupported {}", value.fmt()),
+    }
+}
+
+// A function as written in the listing, with its nested functions not linked in yet.
+struct Block {
+    line: usize,
+    function: Function,
+    // listing line, constant index, name and arity of every function constant
+    nested: Vec<(usize, usize, String, u32)>,
+}
+
+enum Target {
+    Offset(usize),
+    Label(String),
+}
+
+// A jump whose target is resolved once the whole block is read.
+struct Fixup {
+    line: usize,
+    offset: usize,
+    target: Target,
+}
+
+// Returns the script together with the names of its global slots.
+pub fn assemble(source: &str) -> Result<(Function, Vec<String>), String> {
+    let mut blocks = Vec::new();
+    let mut globals = Vec::new();
+    let mut assembler: Option<Assembler> = None;
+
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let trimmed = text.trim();
+        if let Some(header) = trimmed.strip_prefix("==").and_then(|t| t.strip_suffix("==")) {
+            if let Some(done) = assembler.take() {
+                blocks.push(done.finish()?);
+            }
+            assembler = Some(Assembler::new(line, header.trim())?);
+        } else if trimmed.is_empty() || trimmed.starts_with("//") {
+            continue;
+        } else if let Some(rest) = trimmed.strip_prefix("global ") {
+            match assembler.as_ref().map(|a| a.block.function.tpe == FunctionType::Script) {
+                Some(true) => global(rest.trim(), &mut globals).map_err(|message| format!("[line {}] {}", line, message))?,
+                Some(false) => return Err(format!("[line {}] Globals must be listed under <script>.", line)),
+                None => return Err(format!("[line {}] Expected a function header.", line)),
+            }
+        } else {
+            match assembler.as_mut() {
+                Some(assembler) => assembler.line(line, trimmed)?,
+                None => return Err(format!("[line {}] Expected a function header.", line)),
+            }
+        }
+    }
+    match assembler {
+        Some(done) => blocks.push(done.finish()?),
+        None => return Err(String::from("[line 1] Expected a function header.")),
+    }
+
+    let mut blocks = blocks.into_iter();
+    let script = link(&mut blocks)?;
+    if let Some(extra) = blocks.next() {
+        return Err(format!("[line {}] Function is not a constant of any other function.", extra.line));
+    }
+    if script.tpe != FunctionType::Script {
+        return Err(String::from("[line 1] The first function must be <script>."));
+    }
+    Ok((script, globals))
+}
+
+fn global(text: &str, globals: &mut Vec<String>) -> Result<(), String> {
+    let (slot, name) = match text.split_once(' ') {
+        Some((slot, name)) => (slot, unquote(name.trim())?),
+        None => return Err(String::from("Expected a slot and a quoted name after 'global'.")),
+    };
+    if slot.parse::<usize>().ok() != Some(globals.len()) {
+        return Err(format!("Expected global {}.", globals.len()));
+    }
+    if globals.contains(&name) {
+        return Err(format!("Global '{}' is already listed.", name));
+    }
+    globals.push(name);
+    Ok(())
+}
+
+// Takes the next block and, recursively, the blocks of the functions among its constants.
+fn link(blocks: &mut dyn Iterator<Item = Block>) -> Result<Function, String> {
+    let mut block = match blocks.next() {
+        Some(block) => block,
+        None => return Err(String::from("Missing function listing.")),
+    };
+    for (line, index, name, arity) in block.nested {
+        let nested = link(blocks)?;
+        if nested.name.as_deref() != Some(name.as_str()) || nested.arity != arity {
+            return Err(format!(
+                "[line {}] Expected the listing of {}/{} for constant {}.",
+                line, name, arity, index
+            ));
+        }
+        block.function.chunk.values[index] = Value::object(ObjType::Function(nested));
+    }
+    Ok(block.function)
+}
+
+struct Assembler {
+    block: Block,
+    labels: HashMap<String, usize>,
+    fixups: Vec<Fixup>,
+    // a closure waiting for the upvalue descriptors on the lines that follow it
+    closure: Option<(usize, usize, Vec<Upvalue>)>,
+    previous_line: usize,
+}
+
+impl Assembler {
+    fn new(line: usize, header: &str) -> Result<Assembler, String> {
+        let function = if header == "<script>" {
+            Function::main()
+        } else {
+            let parsed = header.rsplit_once('/')
+                .and_then(|(name, arity)| arity.parse().ok().map(|arity| (name, arity)));
+            match parsed {
+                Some((name, arity)) if !name.is_empty() => Function::named(name.to_string(), arity),
+                _ => return Err(format!("[line {}] Expected '<script>' or 'name/arity' in header.", line)),
+            }
+        };
+        Ok(Assembler {
+            block: Block { line, function, nested: Vec::new() },
+            labels: HashMap::new(),
+            fixups: Vec::new(),
+            closure: None,
+            previous_line: 1,
+        })
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.block.function.chunk
+    }
+
+    fn line(&mut self, line: usize, text: &str) -> Result<(), String> {
+        let error = |message: String| Err(format!("[line {}] {}", line, message));
+
+        if let Some(rest) = text.strip_prefix("constant ") {
+            self.flush_closure();
+            return match self.constant(rest.trim(), line) {
+                Ok(()) => Ok(()),
+                Err(message) => error(message),
+            };
+        }
+        if let Some(label) = text.strip_suffix(':') {
+            self.flush_closure();
+            let offset = self.chunk().code.len();
+            if self.labels.insert(label.to_string(), offset).is_some() {
+                return error(format!("Label '{}' is already defined.", label));
+            }
+            return Ok(());
+        }
+
+        let mut words = text.split_whitespace().peekable();
+        let mut columns = Vec::new();
+        while let Some(word) = words.peek() {
+            if *word == "|" || word.chars().all(|c| c.is_ascii_digit()) {
+                columns.push(*word);
+                words.next();
+            } else {
+                break;
+            }
+        }
+        // either offset and line, or just the line; the offset is recomputed anyway
+        let source_line = match columns.last() {
+            Some(&"|") | None => self.previous_line,
+            Some(n) if columns.len() <= 2 => n.parse().unwrap_or(self.previous_line),
+            Some(_) => return error(String::from("Expected an offset and a line before the instruction.")),
+        };
+        let mnemonic = match words.next() {
+            Some(mnemonic) => mnemonic,
+            None => return error(String::from("Expected an instruction.")),
+        };
+        let operands = words.take_while(|w| !w.starts_with("//")).collect::<Vec<_>>();
+
+        if mnemonic == "local" || mnemonic == "upvalue" {
+            return match (&mut self.closure, operands.first().and_then(|o| o.parse().ok())) {
+                (Some((_, _, upvalues)), Some(index)) => {
+                    upvalues.push(if mnemonic == "local" { Upvalue::Local(index) } else { Upvalue::Nonlocal(index) });
+                    Ok(())
+                }
+                (None, _) => error(format!("'{}' must follow a CLOSURE instruction.", mnemonic)),
+                (_, None) => error(String::from("Expected a slot index.")),
+            };
+        }
+
+        self.flush_closure();
+        self.previous_line = source_line;
+        match self.instruction(mnemonic, &operands, source_line, line) {
+            Ok(()) => Ok(()),
+            Err(message) => error(message),
+        }
+    }
+
+    fn constant(&mut self, text: &str, line: usize) -> Result<(), String> {
+        let (index, value) = match text.split_once(' ') {
+            Some((index, value)) => (index, value.trim()),
+            None => (text, ""),
+        };
+        let expected = self.chunk().values.len();
+        if index.parse::<usize>().ok() != Some(expected) {
+            return Err(format!("Expected constant {}.", expected));
+        }
+        let (kind, rest) = match value.split_once(' ') {
+            Some((kind, rest)) => (kind, rest.trim()),
+            None => (value, ""),
+        };
+        let value = match (kind, rest) {
+            ("nil", "") => Value::nil(),
+            ("true", "") => Value::boolean(true),
+            ("false", "") => Value::boolean(false),
+            ("number", n) => match n.parse() {
+                Ok(n) => Value::number(n),
+                Err(_) => return Err(format!("Invalid number '{}'.", n)),
+            },
+            ("string", s) => Value::object(ObjType::String(Rc::new(LoxString::new(unquote(s)?)))),
+            ("function", f) => {
+                let parsed = f.rsplit_once('/')
+                    .and_then(|(name, arity)| arity.parse().ok().map(|arity| (name, arity)));
+                match parsed {
+                    Some((name, arity)) => self.block.nested.push((line, expected, name.to_string(), arity)),
+                    None => return Err(String::from("Expected 'name/arity' after 'function'.")),
+                }
+                // replaced by the nested function once every block is read
+                Value::nil()
+            }
+            _ => return Err(format!("Invalid constant '{}'.", value)),
+        };
+        self.chunk().write_constant(value);
+        Ok(())
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operands: &[&str], line: usize, listing_line: usize) -> Result<(), String> {
+        let tag = match (0..=u8::MAX).find(|&tag| opcode_name(tag) == mnemonic) {
+            Some(tag) => tag,
+            None => return Err(format!("Unknown instruction '{}'.", mnemonic)),
+        };
+        let operand = |at: usize| -> Result<usize, String> {
+            match operands.get(at).map(|o| o.parse::<usize>()) {
+                Some(Ok(n)) => Ok(n),
+                _ => Err(format!("Expected a number after '{}'.", mnemonic)),
+            }
+        };
+        let byte = |at: usize| -> Result<usize, String> {
+            match operand(at)? {
+                n if n <= u8::MAX as usize => Ok(n),
+                n => Err(format!("Operand {} does not fit in a byte.", n)),
+            }
+        };
+        let index = |short: u8| -> Result<usize, String> {
+            let index = operand(0)?;
+            let long = index > u8::MAX as usize;
+            if index > MAX_LONG_INDEX {
+                Err(format!("Index {} is too large.", index))
+            } else if long != (tag != short) {
+                Err(format!("Index {} needs {}.", index, opcode_name(if long { short + 1 } else { short })))
+            } else {
+                Ok(index)
+            }
+        };
+
+        let op = match tag {
+            OP_CONSTANT | OP_CONSTANT_LONG => OpCode::Constant { index: index(OP_CONSTANT)? },
+            OP_GET_GLOBAL | OP_GET_GLOBAL_LONG => OpCode::GetGlobal { index: index(OP_GET_GLOBAL)? },
+            OP_DEFINE_GLOBAL | OP_DEFINE_GLOBAL_LONG => OpCode::DefineGlobal { index: index(OP_DEFINE_GLOBAL)? },
+            OP_SET_GLOBAL | OP_SET_GLOBAL_LONG => OpCode::SetGlobal { index: index(OP_SET_GLOBAL)? },
+            OP_CLOSURE | OP_CLOSURE_LONG => {
+                // written once its upvalue descriptors are read
+                self.closure = Some((index(OP_CLOSURE)?, line, Vec::new()));
+                return Ok(());
+            }
+            OP_GET_LOCAL => OpCode::GetLocal { index: byte(0)? },
+            OP_SET_LOCAL => OpCode::SetLocal { index: byte(0)? },
+            OP_GET_UPVALUE => OpCode::GetUpvalue { index: byte(0)? },
+            OP_SET_UPVALUE => OpCode::SetUpvalue { index: byte(0)? },
+            OP_CALL => OpCode::Call { argc: byte(0)? as u32 },
+            OP_JUMP | OP_JUMP_IF_FALSE | OP_LOOP => {
+                let target = match operands.iter().position(|o| *o == "->").and_then(|at| operands.get(at + 1)) {
+                    Some(target) => match target.parse() {
+                        Ok(offset) => Target::Offset(offset),
+                        Err(_) => Target::Label(target.to_string()),
+                    },
+                    None => return Err(format!("Expected '-> target' after '{}'.", mnemonic)),
+                };
+                let offset = self.chunk().code.len();
+                self.fixups.push(Fixup { line: listing_line, offset, target });
+                match tag {
+                    OP_JUMP => OpCode::Jump { jump: 0 },
+                    OP_JUMP_IF_FALSE => OpCode::JumpIfFalse { jump: 0 },
+                    _ => OpCode::Loop { jump: 0 },
+                }
+            }
+            _ => {
+                if let Some(extra) = operands.first() {
+                    return Err(format!("Unexpected '{}' after '{}'.", extra, mnemonic));
+                }
+                match tag {
+                    OP_NIL => OpCode::Nil,
+                    OP_TRUE => OpCode::True,
+                    OP_FALSE => OpCode::False,
+                    OP_POP => OpCode::Pop,
+                    OP_EQUAL => OpCode::Equal,
+                    OP_GREATER => OpCode::Greater,
+                    OP_LESS => OpCode::Less,
+                    OP_NEGATE => OpCode::Negate,
+                    OP_PRINT => OpCode::Print,
+                    OP_CLOSE_UPVALUE => OpCode::CloseUpvalue,
+                    OP_RETURN => OpCode::Return,
+                    OP_ADD => OpCode::Add,
+                    OP_SUBTRACT => OpCode::Subtract,
+                    OP_MULTIPLY => OpCode::Multiply,
+                    OP_DIVIDE => OpCode::Divide,
+                    _ => OpCode::Not,
+                }
+            }
+        };
+        self.chunk().write(op, line);
+        Ok(())
+    }
+
+    fn flush_closure(&mut self) {
+        if let Some((index, line, upvalues)) = self.closure.take() {
+            self.chunk().write(OpCode::Closure { index, upvalues }, line);
+        }
+    }
+
+    fn finish(mut self) -> Result<Block, String> {
+        self.flush_closure();
+        for fixup in std::mem::take(&mut self.fixups) {
+            let error = |message: String| Err(format!("[line {}] {}", fixup.line, message));
+            let target = match &fixup.target {
+                Target::Offset(offset) => *offset,
+                Target::Label(label) => match self.labels.get(label) {
+                    Some(offset) => *offset,
+                    None => return error(format!("Undefined label '{}'.", label)),
+                },
+            };
+            let (op, next) = self.chunk().decode(fixup.offset);
+            let op = match op {
+                OpCode::Loop { .. } if target <= next => OpCode::Loop { jump: next - target },
+                OpCode::Loop { .. } => return error(String::from("LOOP can only jump backwards.")),
+                _ if target < next => return error(String::from("Jumps can only go forwards, use LOOP.")),
+                OpCode::Jump { .. } => OpCode::Jump { jump: target - next },
+                _ => OpCode::JumpIfFalse { jump: target - next },
+            };
+            let jump = match &op {
+                OpCode::Jump { jump } | OpCode::JumpIfFalse { jump } | OpCode::Loop { jump } => *jump,
+                _ => 0,
+            };
+            if jump > u16::MAX as usize {
+                return error(String::from("Too much code to jump over."));
+            }
+            self.chunk().patch(fixup.offset, op);
+        }
+        Ok(self.block)
+    }
+}
+
+// Reads back a string constant written with `{:?}`.
+fn unquote(text: &str) -> Result<String, String> {
+    let inner = match text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return Err(String::from("Expected a quoted string.")),
+    };
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('0') => out.push('\0'),
+            Some('\\') => out.push('\\'),
+            Some('"') => out.push('"'),
+            Some('\'') => out.push('\''),
+            Some('u') => {
+                let code = chars.by_ref()
+                    .skip_while(|&c| c == '{')
+                    .take_while(|&c| c != '}')
+                    .collect::<String>();
+                match u32::from_str_radix(&code, 16).ok().and_then(std::char::from_u32) {
+                    Some(c) => out.push(c),
+                    None => return Err(format!("Invalid escape '\\u{{{}}}'.", code)),
+                }
+            }
+            other => return Err(format!("Invalid escape '\\{}'.", other.map_or(String::new(), |c| c.to_string()))),
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,134 @@
+use std::rc::Rc;
+
+use scanner::Token;
+
+// The first and last tokens of a piece of the source. Code is attributed to the
+// lines of these tokens, as it was when the compiler emitted it while parsing.
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub first: Token,
+    pub last: Token,
+}
+
+impl Span {
+    pub fn new(first: Token, last: Token) -> Span {
+        Span { first, last }
+    }
+
+    // The character offset just past the span.
+    pub fn end(&self) -> usize {
+        self.last.start + self.last.length
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    Number(f64),
+    String(String),
+    True,
+    False,
+    Nil,
+    Variable(Token),
+    Assign {
+        name: Token,
+        value: Box<Expr>,
+    },
+    Unary {
+        operator: Token,
+        operand: Box<Expr>,
+    },
+    Binary {
+        operator: Token,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    // `and` and `or`, which only evaluate their right operand if they need it
+    Logical {
+        operator: Token,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Grouping(Box<Expr>),
+    Call {
+        callee: Box<Expr>,
+        arguments: Vec<Expr>,
+    },
+    // where a syntax error left no expression
+    Invalid,
+}
+
+#[derive(Debug, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+// Statements that are broken by a syntax error keep the tokens the parser found in
+// place of the ones it expected, such as `paren` for a missing `)`.
+#[derive(Debug, Clone)]
+pub enum StmtKind {
+    Expression(Expr),
+    Print(Expr),
+    Block(Vec<Decl>),
+    If {
+        condition: Expr,
+        // the `)` after the condition
+        paren: Token,
+        then: Box<Stmt>,
+        otherwise: Option<Box<Stmt>>,
+    },
+    While {
+        condition: Expr,
+        paren: Token,
+        body: Box<Stmt>,
+    },
+    For {
+        initializer: Option<Box<Decl>>,
+        condition: Option<Box<Expr>>,
+        // the `;` after the condition
+        semicolon: Token,
+        increment: Option<Box<Expr>>,
+        // the `)` after the clauses
+        paren: Token,
+        body: Box<Stmt>,
+    },
+    Return(Option<Expr>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Decl {
+    pub kind: DeclKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum DeclKind {
+    Var {
+        name: Token,
+        initializer: Option<Box<Expr>>,
+    },
+    // shared with the closures the tree-walking interpreter makes of it
+    Fun(Rc<Function>),
+    Statement(Box<Stmt>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name: Token,
+    pub params: Vec<Token>,
+    // the `{` that starts the body
+    pub open: Token,
+    pub body: Vec<Decl>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub declarations: Vec<Decl>,
+    pub eof: Token,
+}
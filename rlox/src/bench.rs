@@ -0,0 +1,238 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Instant;
+
+use dap::Captured;
+use json;
+use json::Json;
+use limits::Limits;
+use vm::InterpretResult;
+use vm::VM;
+
+// How `rlox bench` runs and what it compares against.
+pub struct Options {
+    pub runs: usize,
+    pub optimize: bool,
+    pub limits: Limits,
+    // a previous `--save`, to compare with
+    pub baseline: Option<String>,
+    pub save: Option<String>,
+    // slowdown of the median, in percent, that counts as a regression
+    pub threshold: f64,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            runs: 5,
+            optimize: false,
+            limits: Limits::new(),
+            baseline: None,
+            save: None,
+            threshold: 10.0,
+        }
+    }
+}
+
+struct Measurement {
+    name: String,
+    // wall times in milliseconds, sorted
+    times: Vec<f64>,
+    instructions: u64,
+}
+
+// A benchmark whose run ended in an error, and the diagnostics it printed.
+struct Failure {
+    name: String,
+    reason: &'static str,
+    errors: String,
+}
+
+impl Measurement {
+    fn min(&self) -> f64 {
+        self.times[0]
+    }
+
+    fn max(&self) -> f64 {
+        self.times[self.times.len() - 1]
+    }
+
+    fn median(&self) -> f64 {
+        let n = self.times.len();
+        if n % 2 == 1 {
+            self.times[n / 2]
+        } else {
+            (self.times[n / 2 - 1] + self.times[n / 2]) / 2.0
+        }
+    }
+}
+
+// Runs every benchmark, prints a report and returns whether any failed or regressed.
+pub fn run(paths: &[&str], options: &Options) -> Result<bool, String> {
+    let files = benchmark_files(paths)?;
+    if files.is_empty() {
+        return Err(String::from("No benchmarks found."));
+    }
+    let baseline = match &options.baseline {
+        Some(path) => Some(read_baseline(path)?),
+        None => None,
+    };
+
+    println!("{:<20} {:>10} {:>10} {:>10} {:>14}", "benchmark", "min", "median", "max", "instructions");
+    let mut results = Vec::new();
+    let mut failed = false;
+    let mut regressed = false;
+    for file in files {
+        let name = Path::new(&file).file_stem().unwrap().to_string_lossy().to_string();
+        let source = fs::read_to_string(&file).map_err(|e| format!("Could not read \"{}\": {}", file, e))?;
+        let measurement = match measure(&name, &source, options) {
+            Ok(measurement) => measurement,
+            Err(failure) => {
+                println!("{:<20} failed with a {}", name, failure.reason);
+                eprint!("{}", failure.errors);
+                failed = true;
+                results.push(Err(failure));
+                continue;
+            }
+        };
+        print!(
+            "{:<20} {:>8.1}ms {:>8.1}ms {:>8.1}ms {:>14}",
+            name, measurement.min(), measurement.median(), measurement.max(), measurement.instructions
+        );
+        if let Some(previous) = baseline.as_ref().and_then(|b| b.iter().find(|m| m.name == name)) {
+            let change = (measurement.median() - previous.median()) / previous.median() * 100.0;
+            print!("  {:+6.1}% vs {:.1}ms", change, previous.median());
+            if previous.instructions != measurement.instructions {
+                print!(", {:+} instructions", measurement.instructions as i64 - previous.instructions as i64);
+            }
+            if change > options.threshold {
+                print!("  REGRESSION");
+                regressed = true;
+            }
+        }
+        println!();
+        results.push(Ok(measurement));
+    }
+    println!("Times are of running each benchmark once it is compiled.");
+
+    if let Some(path) = &options.save {
+        fs::write(path, format!("{}\n", to_json(&results, options)))
+            .map_err(|e| format!("Could not write \"{}\": {}", path, e))?;
+    }
+    Ok(failed || regressed)
+}
+
+// The `.lox` files among the paths, looking inside directories.
+fn benchmark_files(paths: &[&str]) -> Result<Vec<String>, String> {
+    let mut files = Vec::new();
+    for path in paths {
+        if Path::new(path).is_dir() {
+            let entries = fs::read_dir(path).map_err(|e| format!("Could not read \"{}\": {}", path, e))?;
+            let mut found = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+                .map(|path| path.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            found.sort();
+            files.extend(found);
+        } else {
+            files.push(path.to_string());
+        }
+    }
+    Ok(files)
+}
+
+// Compiles and runs a benchmark in a fresh VM each time, with its output thrown away
+// and only the run timed. The first run to fail ends the measurement.
+fn measure(name: &str, source: &str, options: &Options) -> Result<Measurement, Failure> {
+    let mut times = Vec::new();
+    let mut instructions = 0;
+    for _ in 0..options.runs.max(1) {
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let mut vm = VM::new();
+        vm.set_limits(options.limits);
+        vm.set_optimize(options.optimize);
+        vm.set_output(Box::new(io::sink()));
+        vm.set_error_output(Box::new(Captured(errors.clone())));
+        let failure = |reason| Failure {
+            name: name.to_string(),
+            reason,
+            errors: String::from_utf8_lossy(&errors.borrow()).into_owned(),
+        };
+        let function = match vm.compile(source) {
+            Some(function) => function,
+            None => return Err(failure("compile error")),
+        };
+        let start = Instant::now();
+        let result = vm.execute(function);
+        let elapsed = start.elapsed();
+        if let InterpretResult::CompileError | InterpretResult::RuntimeError = result {
+            return Err(failure("runtime error"));
+        }
+        times.push(elapsed.as_secs_f64() * 1000.0);
+        instructions = vm.instruction_count();
+    }
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    Ok(Measurement { name: name.to_string(), times, instructions })
+}
+
+// A baseline keeps the summary of each benchmark, or how it failed:
+//
+//   {"runs":5,"optimize":false,"benchmarks":{"fib":{"min_ms":..,"median_ms":..,"max_ms":..,"instructions":..},
+//    "broken":{"failed":"runtime error"}}}
+fn to_json(results: &[Result<Measurement, Failure>], options: &Options) -> Json {
+    let benchmarks = results
+        .iter()
+        .map(|result| match result {
+            Ok(m) => {
+                let summary = Json::Object(vec![
+                    (String::from("min_ms"), Json::Number(m.min())),
+                    (String::from("median_ms"), Json::Number(m.median())),
+                    (String::from("max_ms"), Json::Number(m.max())),
+                    (String::from("instructions"), Json::Number(m.instructions as f64)),
+                ]);
+                (m.name.clone(), summary)
+            }
+            Err(failure) => {
+                let summary = Json::Object(vec![(String::from("failed"), Json::String(failure.reason.to_string()))]);
+                (failure.name.clone(), summary)
+            }
+        })
+        .collect();
+    Json::Object(vec![
+        (String::from("runs"), Json::Number(options.runs as f64)),
+        (String::from("optimize"), Json::Bool(options.optimize)),
+        (String::from("benchmarks"), Json::Object(benchmarks)),
+    ])
+}
+
+fn read_baseline(path: &str) -> Result<Vec<Measurement>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Could not read \"{}\": {}", path, e))?;
+    let invalid = |message: &str| format!("Invalid baseline \"{}\": {}", path, message);
+    let root = json::parse(&text).map_err(|e| invalid(&e))?;
+    let benchmarks = match root.get("benchmarks").and_then(Json::members) {
+        Some(benchmarks) => benchmarks,
+        None => return Err(invalid("missing \"benchmarks\".")),
+    };
+    let mut measurements = Vec::new();
+    for (name, summary) in benchmarks {
+        // a benchmark that failed has no times to compare with
+        if summary.get("failed").is_some() {
+            continue;
+        }
+        let field = |key: &str| summary.get(key).and_then(Json::as_f64);
+        match (field("min_ms"), field("median_ms"), field("max_ms"), field("instructions")) {
+            (Some(min), Some(median), Some(max), Some(instructions)) => measurements.push(Measurement {
+                name: name.clone(),
+                // enough to give back the same min, median and max
+                times: vec![min, median, max],
+                instructions: instructions as u64,
+            }),
+            _ => return Err(invalid(&format!("incomplete entry for \"{}\".", name))),
+        }
+    }
+    Ok(measurements)
+}
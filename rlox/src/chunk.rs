@@ -1,8 +1,9 @@
 use compiler::Upvalue;
-use compiler::Upvalue::{Local, Nonlocal};
 use value::Value;
 
-#[derive(Debug, Clone)]
+// Instructions as the compiler builds them and `Chunk::decode` returns them.
+// In the chunk they are encoded as a tag byte followed by their operands.
+#[derive(Debug, Clone, PartialEq)]
 pub enum OpCode {
     Constant { index: usize },
     Nil,
@@ -35,11 +36,85 @@ pub enum OpCode {
     Not,
 }
 
+pub const OP_CONSTANT: u8 = 0;
+pub const OP_CONSTANT_LONG: u8 = 1;
+pub const OP_NIL: u8 = 2;
+pub const OP_TRUE: u8 = 3;
+pub const OP_FALSE: u8 = 4;
+pub const OP_POP: u8 = 5;
+pub const OP_GET_LOCAL: u8 = 6;
+pub const OP_SET_LOCAL: u8 = 7;
+pub const OP_GET_GLOBAL: u8 = 8;
+pub const OP_GET_GLOBAL_LONG: u8 = 9;
+pub const OP_DEFINE_GLOBAL: u8 = 10;
+pub const OP_DEFINE_GLOBAL_LONG: u8 = 11;
+pub const OP_SET_GLOBAL: u8 = 12;
+pub const OP_SET_GLOBAL_LONG: u8 = 13;
+pub const OP_GET_UPVALUE: u8 = 14;
+pub const OP_SET_UPVALUE: u8 = 15;
+pub const OP_EQUAL: u8 = 16;
+pub const OP_GREATER: u8 = 17;
+pub const OP_LESS: u8 = 18;
+pub const OP_NEGATE: u8 = 19;
+pub const OP_PRINT: u8 = 20;
+pub const OP_JUMP: u8 = 21;
+pub const OP_JUMP_IF_FALSE: u8 = 22;
+pub const OP_LOOP: u8 = 23;
+pub const OP_CALL: u8 = 24;
+pub const OP_CLOSURE: u8 = 25;
+pub const OP_CLOSURE_LONG: u8 = 26;
+pub const OP_CLOSE_UPVALUE: u8 = 27;
+pub const OP_RETURN: u8 = 28;
+pub const OP_ADD: u8 = 29;
+pub const OP_SUBTRACT: u8 = 30;
+pub const OP_MULTIPLY: u8 = 31;
+pub const OP_DIVIDE: u8 = 32;
+pub const OP_NOT: u8 = 33;
+
+// Largest constant index or global slot that fits the one-byte operand of the short forms.
+const SHORT_INDEX: usize = u8::MAX as usize;
+// Largest constant index or global slot that fits the three-byte operand of the `*_LONG` forms.
+pub const MAX_LONG_INDEX: usize = (1 << 24) - 1;
+
+impl OpCode {
+    // Number of bytes the instruction takes once encoded.
+    pub fn size(&self) -> usize {
+        match self {
+            OpCode::Constant { index }
+            | OpCode::GetGlobal { index }
+            | OpCode::DefineGlobal { index }
+            | OpCode::SetGlobal { index } => if *index > SHORT_INDEX { 4 } else { 2 },
+            OpCode::GetLocal { .. }
+            | OpCode::SetLocal { .. }
+            | OpCode::GetUpvalue { .. }
+            | OpCode::SetUpvalue { .. }
+            | OpCode::Call { .. } => 2,
+            OpCode::Jump { .. } | OpCode::JumpIfFalse { .. } | OpCode::Loop { .. } => 3,
+            OpCode::Closure { index, upvalues } =>
+                (if *index > SHORT_INDEX { 4 } else { 2 }) + 2 + 2 * upvalues.len(),
+            _ => 1,
+        }
+    }
+}
+
 #[derive(Clone,Debug)]
 pub struct Chunk {
-    pub code: Vec<OpCode>,
+    pub code: Vec<u8>,
     pub values: Vec<Value>,
     lines: Vec<usize>,
+    // names for the debugger; neither `.loxc` files nor optimized code keep them
+    locals: Vec<LocalName>,
+    upvalues: Vec<String>,
+}
+
+// A local variable, which lives in `slot` of the frame while the instructions from
+// `start` up to `end` run.
+#[derive(Clone,Debug)]
+pub struct LocalName {
+    pub name: String,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
 }
 
 impl Chunk {
@@ -48,9 +123,39 @@ impl Chunk {
             code: Vec::new(),
             values: Vec::new(),
             lines: Vec::new(),
+            locals: Vec::new(),
+            upvalues: Vec::new(),
         }
     }
 
+    // Rebuilds a chunk from its parts, as stored in a `.loxc` file.
+    pub fn from_parts(code: Vec<u8>, values: Vec<Value>, lines: Vec<usize>) -> Chunk {
+        Chunk { code, values, lines, locals: Vec::new(), upvalues: Vec::new() }
+    }
+
+    pub fn add_local(&mut self, local: LocalName) {
+        self.locals.push(local);
+    }
+
+    // The locals that hold a value while the instruction at `ip` runs.
+    pub fn locals_at(&self, ip: usize) -> Vec<&LocalName> {
+        self.locals.iter().filter(|local| local.start <= ip && ip < local.end).collect()
+    }
+
+    pub fn add_upvalue(&mut self, name: &str) {
+        self.upvalues.push(name.to_string());
+    }
+
+    // Names of the variables the function captures, in the order of its upvalues.
+    pub fn upvalue_names(&self) -> &[String] {
+        &self.upvalues
+    }
+
+    // Source line of every byte in `code`.
+    pub fn lines(&self) -> &[usize] {
+        &self.lines
+    }
+
     pub fn write_constant(&mut self, value: Value) -> usize {
         let idx = self.values.len();
         self.values.push(value);
@@ -62,93 +167,493 @@ impl Chunk {
     }
 
     pub fn write(&mut self, op: OpCode, line: usize) {
-        self.code.push(op);
-        self.lines.push(line);
+        let bytes = Chunk::encode(&op);
+        for byte in bytes {
+            self.code.push(byte);
+            self.lines.push(line);
+        }
+    }
+
+    // Re-encodes the instruction at `offset`, which must keep the same size.
+    pub fn patch(&mut self, offset: usize, op: OpCode) {
+        let bytes = Chunk::encode(&op);
+        let (old, _) = self.decode(offset);
+        assert_eq!(old.size(), bytes.len(), "Patched {:?} with {:?}", old, op);
+        self.code[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+
+    // Re-encodes every instruction through `f`, which may change operands and therefore
+    // sizes; jumps are retargeted to the instructions they pointed at before.
+    pub fn rewrite<F>(&self, mut f: F) -> Chunk where F: FnMut(OpCode) -> OpCode {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let (op, next) = self.decode(offset);
+            instructions.push((offset, f(op), next));
+            offset = next;
+        }
+
+        let mut moved = vec![0; self.code.len() + 1];
+        let mut at = 0;
+        for (offset, op, _) in instructions.iter() {
+            moved[*offset] = at;
+            at += op.size();
+        }
+        moved[self.code.len()] = at;
+
+        let mut chunk = Chunk::from_parts(Vec::new(), self.values.clone(), Vec::new());
+        chunk.upvalues = self.upvalues.clone();
+        for local in self.locals.iter() {
+            chunk.add_local(LocalName { start: moved[local.start], end: moved[local.end], ..local.clone() });
+        }
+        for (offset, op, next) in instructions {
+            let after = moved[offset] + op.size();
+            let op = match op {
+                OpCode::Jump { jump } => OpCode::Jump { jump: moved[next + jump] - after },
+                OpCode::JumpIfFalse { jump } => OpCode::JumpIfFalse { jump: moved[next + jump] - after },
+                OpCode::Loop { jump } => OpCode::Loop { jump: after - moved[next - jump] },
+                op => op,
+            };
+            chunk.write(op, self.lines[offset]);
+        }
+        chunk
+    }
+
+    fn encode(op: &OpCode) -> Vec<u8> {
+        fn indexed(short: u8, long: u8, index: usize) -> Vec<u8> {
+            assert!(index <= MAX_LONG_INDEX, "Constant index {} does not fit in three bytes", index);
+            if index > SHORT_INDEX {
+                vec![long, (index >> 16) as u8, (index >> 8) as u8, index as u8]
+            } else {
+                vec![short, index as u8]
+            }
+        }
+        fn byte(tag: u8, operand: usize) -> Vec<u8> {
+            assert!(operand <= u8::MAX as usize, "Operand {} does not fit in a byte", operand);
+            vec![tag, operand as u8]
+        }
+        fn short(tag: u8, operand: usize) -> Vec<u8> {
+            assert!(operand <= u16::MAX as usize, "Operand {} does not fit in two bytes", operand);
+            vec![tag, (operand >> 8) as u8, operand as u8]
+        }
+
+        match op {
+            OpCode::Constant { index } => indexed(OP_CONSTANT, OP_CONSTANT_LONG, *index),
+            OpCode::Nil => vec![OP_NIL],
+            OpCode::True => vec![OP_TRUE],
+            OpCode::False => vec![OP_FALSE],
+            OpCode::Pop => vec![OP_POP],
+            OpCode::GetLocal { index } => byte(OP_GET_LOCAL, *index),
+            OpCode::SetLocal { index } => byte(OP_SET_LOCAL, *index),
+            OpCode::GetGlobal { index } => indexed(OP_GET_GLOBAL, OP_GET_GLOBAL_LONG, *index),
+            OpCode::DefineGlobal { index } => indexed(OP_DEFINE_GLOBAL, OP_DEFINE_GLOBAL_LONG, *index),
+            OpCode::SetGlobal { index } => indexed(OP_SET_GLOBAL, OP_SET_GLOBAL_LONG, *index),
+            OpCode::GetUpvalue { index } => byte(OP_GET_UPVALUE, *index),
+            OpCode::SetUpvalue { index } => byte(OP_SET_UPVALUE, *index),
+            OpCode::Equal => vec![OP_EQUAL],
+            OpCode::Greater => vec![OP_GREATER],
+            OpCode::Less => vec![OP_LESS],
+            OpCode::Negate => vec![OP_NEGATE],
+            OpCode::Print => vec![OP_PRINT],
+            OpCode::Jump { jump } => short(OP_JUMP, *jump),
+            OpCode::JumpIfFalse { jump } => short(OP_JUMP_IF_FALSE, *jump),
+            OpCode::Loop { jump } => short(OP_LOOP, *jump),
+            OpCode::Call { argc } => byte(OP_CALL, *argc as usize),
+            OpCode::Closure { index, upvalues } => {
+                // a function may capture 256 upvalues, one more than a byte can count
+                let mut bytes = indexed(OP_CLOSURE, OP_CLOSURE_LONG, *index);
+                bytes.extend_from_slice(&(upvalues.len() as u16).to_be_bytes());
+                for up in upvalues {
+                    match up {
+                        Upvalue::Local(index) => bytes.extend(byte(1, *index)),
+                        Upvalue::Nonlocal(index) => bytes.extend(byte(0, *index)),
+                    }
+                }
+                bytes
+            }
+            OpCode::CloseUpvalue => vec![OP_CLOSE_UPVALUE],
+            OpCode::Return => vec![OP_RETURN],
+            OpCode::Add => vec![OP_ADD],
+            OpCode::Subtract => vec![OP_SUBTRACT],
+            OpCode::Multiply => vec![OP_MULTIPLY],
+            OpCode::Divide => vec![OP_DIVIDE],
+            OpCode::Not => vec![OP_NOT],
+        }
     }
 
-    pub fn fetch(&self, ip: usize) -> OpCode {
-        self.code[ip].clone()
+    // Decodes the instruction at `offset`, returning it with the offset of the next one.
+    pub fn decode(&self, offset: usize) -> (OpCode, usize) {
+        self.decode_with(offset, true)
+    }
+
+    // Like `decode`, but leaves a closure's upvalues out, for the VM to read with
+    // `upvalues_at` instead of collecting them for every closure it makes.
+    pub fn decode_operands(&self, offset: usize) -> (OpCode, usize) {
+        self.decode_with(offset, false)
+    }
+
+    // The upvalues of the CLOSURE instruction at `offset`, read from the code.
+    pub fn upvalues_at(&self, offset: usize) -> impl Iterator<Item = Upvalue> + '_ {
+        let at = offset + if self.code[offset] == OP_CLOSURE { 2 } else { 4 };
+        let count = ((self.code[at] as usize) << 8) | self.code[at + 1] as usize;
+        self.code[at + 2..at + 2 + 2 * count].chunks(2).map(|pair| match pair[0] {
+            1 => Upvalue::Local(pair[1] as usize),
+            _ => Upvalue::Nonlocal(pair[1] as usize),
+        })
+    }
+
+    fn decode_with(&self, offset: usize, upvalues: bool) -> (OpCode, usize) {
+        let code = &self.code;
+        let byte = |at: usize| code[offset + at] as usize;
+        let short = |at: usize| (byte(at) << 8) | byte(at + 1);
+        let long = |at: usize| (byte(at) << 16) | (byte(at + 1) << 8) | byte(at + 2);
+
+        let (op, size) = match code[offset] {
+            OP_CONSTANT => (OpCode::Constant { index: byte(1) }, 2),
+            OP_CONSTANT_LONG => (OpCode::Constant { index: long(1) }, 4),
+            OP_NIL => (OpCode::Nil, 1),
+            OP_TRUE => (OpCode::True, 1),
+            OP_FALSE => (OpCode::False, 1),
+            OP_POP => (OpCode::Pop, 1),
+            OP_GET_LOCAL => (OpCode::GetLocal { index: byte(1) }, 2),
+            OP_SET_LOCAL => (OpCode::SetLocal { index: byte(1) }, 2),
+            OP_GET_GLOBAL => (OpCode::GetGlobal { index: byte(1) }, 2),
+            OP_GET_GLOBAL_LONG => (OpCode::GetGlobal { index: long(1) }, 4),
+            OP_DEFINE_GLOBAL => (OpCode::DefineGlobal { index: byte(1) }, 2),
+            OP_DEFINE_GLOBAL_LONG => (OpCode::DefineGlobal { index: long(1) }, 4),
+            OP_SET_GLOBAL => (OpCode::SetGlobal { index: byte(1) }, 2),
+            OP_SET_GLOBAL_LONG => (OpCode::SetGlobal { index: long(1) }, 4),
+            OP_GET_UPVALUE => (OpCode::GetUpvalue { index: byte(1) }, 2),
+            OP_SET_UPVALUE => (OpCode::SetUpvalue { index: byte(1) }, 2),
+            OP_EQUAL => (OpCode::Equal, 1),
+            OP_GREATER => (OpCode::Greater, 1),
+            OP_LESS => (OpCode::Less, 1),
+            OP_NEGATE => (OpCode::Negate, 1),
+            OP_PRINT => (OpCode::Print, 1),
+            OP_JUMP => (OpCode::Jump { jump: short(1) }, 3),
+            OP_JUMP_IF_FALSE => (OpCode::JumpIfFalse { jump: short(1) }, 3),
+            OP_LOOP => (OpCode::Loop { jump: short(1) }, 3),
+            OP_CALL => (OpCode::Call { argc: byte(1) as u32 }, 2),
+            OP_CLOSURE | OP_CLOSURE_LONG => {
+                let (index, at) = if code[offset] == OP_CLOSURE { (byte(1), 2) } else { (long(1), 4) };
+                let count = short(at);
+                let upvalues = if upvalues { self.upvalues_at(offset).collect() } else { Vec::new() };
+                (OpCode::Closure { index, upvalues }, at + 2 + 2 * count)
+            }
+            OP_CLOSE_UPVALUE => (OpCode::CloseUpvalue, 1),
+            OP_RETURN => (OpCode::Return, 1),
+            OP_ADD => (OpCode::Add, 1),
+            OP_SUBTRACT => (OpCode::Subtract, 1),
+            OP_MULTIPLY => (OpCode::Multiply, 1),
+            OP_DIVIDE => (OpCode::Divide, 1),
+            OP_NOT => (OpCode::Not, 1),
+            tag => panic!("Unknown opcode {} at offset {}", tag, offset),
+        };
+        (op, offset + size)
+    }
+
+    // Like `decode`, but fails instead of panicking on an unknown opcode or an
+    // instruction cut short by the end of the code.
+    pub fn try_decode(&self, offset: usize) -> Result<(OpCode, usize), &'static str> {
+        let code = &self.code;
+        let tag = match code.get(offset) {
+            Some(tag) => *tag,
+            None => return Err("Offset is past the end of the code."),
+        };
+        let size = match tag {
+            OP_NIL | OP_TRUE | OP_FALSE | OP_POP | OP_EQUAL | OP_GREATER | OP_LESS
+            | OP_NEGATE | OP_PRINT | OP_CLOSE_UPVALUE | OP_RETURN | OP_ADD | OP_SUBTRACT
+            | OP_MULTIPLY | OP_DIVIDE | OP_NOT => 1,
+            OP_CONSTANT | OP_GET_LOCAL | OP_SET_LOCAL | OP_GET_GLOBAL | OP_DEFINE_GLOBAL
+            | OP_SET_GLOBAL | OP_GET_UPVALUE | OP_SET_UPVALUE | OP_CALL => 2,
+            OP_JUMP | OP_JUMP_IF_FALSE | OP_LOOP => 3,
+            OP_CONSTANT_LONG | OP_GET_GLOBAL_LONG | OP_DEFINE_GLOBAL_LONG
+            | OP_SET_GLOBAL_LONG => 4,
+            OP_CLOSURE | OP_CLOSURE_LONG => {
+                let at = offset + if tag == OP_CLOSURE { 2 } else { 4 };
+                match (code.get(at), code.get(at + 1)) {
+                    (Some(&hi), Some(&lo)) => at + 2 + 2 * ((hi as usize) << 8 | lo as usize) - offset,
+                    _ => return Err("Instruction is cut short by the end of the code."),
+                }
+            }
+            _ => return Err("Unknown opcode."),
+        };
+        if offset + size > code.len() {
+            return Err("Instruction is cut short by the end of the code.");
+        }
+        Ok(self.decode(offset))
     }
 
     pub fn line_at(&self, ip: usize) -> usize {
         self.lines[ip]
     }
 
-    pub fn disassemble(&self, name: &str) {
+    // `globals` names the global slots, for display only.
+    pub fn disassemble(&self, name: &str, globals: &[String]) {
         println!("== {} ==", name);
+        print!("{}", self.listing(globals));
+    }
 
-        for i in 0..self.code.len() {
-            self.disassemble_instruction(i);
+    // The disassembly of every instruction, one per line.
+    pub fn listing(&self, globals: &[String]) -> String {
+        let mut out = String::new();
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let (text, next) = self.instruction_listing(offset, globals);
+            out.push_str(&text);
+            offset = next;
         }
+        out
+    }
+
+    // Prints the instruction at byte `offset` and returns the offset of the next one.
+    pub fn disassemble_instruction(&self, offset: usize, globals: &[String]) -> usize {
+        let (text, next) = self.instruction_listing(offset, globals);
+        print!("{}", text);
+        next
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) {
-        print!("{:04} ", offset);
-        let op = &self.code[offset];
+    // Formats the instruction at byte `offset`, followed by the upvalue descriptors of a
+    // closure on lines of their own, and returns it with the offset of the next one.
+    pub fn instruction_listing(&self, offset: usize, globals: &[String]) -> (String, usize) {
+        let mut out = format!("{:04} ", offset);
         if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ")
+            out.push_str("   | ");
         } else {
-            print!("{:4} ", self.lines[offset]);
+            out.push_str(&format!("{:4} ", self.lines[offset]));
         }
+        let name = opcode_name(self.code[offset]);
+        let (op, next) = self.decode(offset);
         match op {
-            OpCode::Constant { index } => self.constant_instruction("CONSTANT", index),
-            OpCode::Nil => self.simple_instruction("NIL"),
-            OpCode::True => self.simple_instruction("TRUE"),
-            OpCode::False => self.simple_instruction("FALSE"),
-            OpCode::Pop => self.simple_instruction("POP"),
-            OpCode::GetLocal { index } => self.byte_instruction("GET_LOCAL", index),
-            OpCode::SetLocal { index } => self.byte_instruction("SET_LOCAL", index),
-            OpCode::GetGlobal { index } => self.constant_instruction("GET_GLOBAL", index),
-            OpCode::DefineGlobal { index } => self.constant_instruction("DEFINE_GLOBAL", index),
-            OpCode::SetGlobal { index } => self.constant_instruction("SET_GLOBAL", index),
-            OpCode::GetUpvalue { index } => self.byte_instruction("GET_UPVALUE", index),
-            OpCode::SetUpvalue { index } => self.byte_instruction("SET_UPVALUE", index),
-            OpCode::Equal => self.simple_instruction("EQUAL"),
-            OpCode::Greater => self.simple_instruction("GREATER"),
-            OpCode::Less => self.simple_instruction("LESS"),
-            OpCode::Add => self.simple_instruction("ADD"),
-            OpCode::Subtract => self.simple_instruction("SUBTRACT"),
-            OpCode::Multiply => self.simple_instruction("MULTIPLY"),
-            OpCode::Divide => self.simple_instruction("DIVIDE"),
-            OpCode::Not => self.simple_instruction("NOT"),
-            OpCode::Negate => self.simple_instruction("NEGATE"),
-            OpCode::Print => self.simple_instruction("PRINT"),
-            OpCode::JumpIfFalse { jump } => {
-                self.jump_instruction("JUMP_IF_FALSE", 1, offset + 1, jump)
+            OpCode::Constant { index } => out.push_str(&self.constant_instruction(name, index)),
+            OpCode::GetGlobal { index }
+            | OpCode::DefineGlobal { index }
+            | OpCode::SetGlobal { index } => out.push_str(&Chunk::global_instruction(name, index, globals)),
+            OpCode::GetLocal { index }
+            | OpCode::SetLocal { index }
+            | OpCode::GetUpvalue { index }
+            | OpCode::SetUpvalue { index } => out.push_str(&Chunk::byte_instruction(name, index)),
+            OpCode::Call { argc } => out.push_str(&Chunk::byte_instruction(name, argc as usize)),
+            OpCode::Jump { jump } | OpCode::JumpIfFalse { jump } => {
+                out.push_str(&Chunk::jump_instruction(name, offset, next + jump))
             }
-            OpCode::Jump { jump } => self.jump_instruction("JUMP", 1, offset + 1, jump),
-            OpCode::Loop { jump } => self.jump_instruction("LOOP", -1, offset + 1, jump),
-            OpCode::Call { .. } => self.simple_instruction("CALL"),
+            OpCode::Loop { jump } => out.push_str(&Chunk::jump_instruction(name, offset, next - jump)),
             OpCode::Closure { index, upvalues } => {
-                self.constant_instruction("CLOSURE", index);
-                for up in upvalues {
-                    println!("{:04}      |                     {:?}", offset, up);
+                out.push_str(&self.constant_instruction(name, index));
+                let descriptors = next - 2 * upvalues.len();
+                for (i, up) in upvalues.iter().enumerate() {
+                    let (kind, index) = match up {
+                        Upvalue::Local(index) => ("local", index),
+                        Upvalue::Nonlocal(index) => ("upvalue", index),
+                    };
+                    out.push_str(&format!("{:04}      |                     {} {}\n", descriptors + 2 * i, kind, index));
                 }
             }
-            OpCode::CloseUpvalue => self.simple_instruction("CLOSE_UPVALUE"),
-            OpCode::Return => self.simple_instruction("RETURN"),
+            _ => out.push_str(&Chunk::simple_instruction(name)),
         }
+        (out, next)
     }
 
-    fn constant_instruction(&self, op: &str, offset: &usize) {
-        print!("{:16} {:4} '", op, offset);
-        self.values[*offset].print();
-        println!("'");
+    fn constant_instruction(&self, op: &str, index: usize) -> String {
+        // escaped so that the listing keeps one instruction per line
+        format!("{:16} {:4} '{}'\n", op, index, self.values[index].fmt().escape_debug())
     }
 
-    fn simple_instruction(&self, op: &str) {
-        println!("{}", op)
+    fn global_instruction(op: &str, slot: usize, globals: &[String]) -> String {
+        match globals.get(slot) {
+            Some(global) => format!("{:16} {:4} '{}'\n", op, slot, global.escape_debug()),
+            None => Chunk::byte_instruction(op, slot),
+        }
     }
 
-    fn byte_instruction(&self, op: &str, offset: &usize) {
-        println!("{:16} {:4}", op, offset)
+    fn simple_instruction(op: &str) -> String {
+        format!("{}\n", op)
     }
 
-    fn jump_instruction(&self, op: &str, sign: isize, offset: usize, jump: &usize) {
-        if sign < 0 {
-            println!("{:16} {:4}", op, offset - jump);
-        } else {
-            println!("{:16} {:4}", op, offset + jump);
+    fn byte_instruction(op: &str, operand: usize) -> String {
+        format!("{:16} {:4}\n", op, operand)
+    }
+
+    fn jump_instruction(op: &str, offset: usize, target: usize) -> String {
+        format!("{:16} {:4} -> {}\n", op, offset, target)
+    }
+}
+
+pub fn opcode_name(tag: u8) -> &'static str {
+    match tag {
+        OP_CONSTANT => "CONSTANT",
+        OP_CONSTANT_LONG => "CONSTANT_LONG",
+        OP_NIL => "NIL",
+        OP_TRUE => "TRUE",
+        OP_FALSE => "FALSE",
+        OP_POP => "POP",
+        OP_GET_LOCAL => "GET_LOCAL",
+        OP_SET_LOCAL => "SET_LOCAL",
+        OP_GET_GLOBAL => "GET_GLOBAL",
+        OP_GET_GLOBAL_LONG => "GET_GLOBAL_LONG",
+        OP_DEFINE_GLOBAL => "DEFINE_GLOBAL",
+        OP_DEFINE_GLOBAL_LONG => "DEFINE_GLOBAL_LONG",
+        OP_SET_GLOBAL => "SET_GLOBAL",
+        OP_SET_GLOBAL_LONG => "SET_GLOBAL_LONG",
+        OP_GET_UPVALUE => "GET_UPVALUE",
+        OP_SET_UPVALUE => "SET_UPVALUE",
+        OP_EQUAL => "EQUAL",
+        OP_GREATER => "GREATER",
+        OP_LESS => "LESS",
+        OP_NEGATE => "NEGATE",
+        OP_PRINT => "PRINT",
+        OP_JUMP => "JUMP",
+        OP_JUMP_IF_FALSE => "JUMP_IF_FALSE",
+        OP_LOOP => "LOOP",
+        OP_CALL => "CALL",
+        OP_CLOSURE => "CLOSURE",
+        OP_CLOSURE_LONG => "CLOSURE_LONG",
+        OP_CLOSE_UPVALUE => "CLOSE_UPVALUE",
+        OP_RETURN => "RETURN",
+        OP_ADD => "ADD",
+        OP_SUBTRACT => "SUBTRACT",
+        OP_MULTIPLY => "MULTIPLY",
+        OP_DIVIDE => "DIVIDE",
+        OP_NOT => "NOT",
+        _ => "UNKNOWN",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(ops: &[OpCode]) -> Chunk {
+        let mut chunk = Chunk::new();
+        for op in ops {
+            chunk.write(op.clone(), 1);
         }
+        chunk
+    }
+
+    fn decode_all(chunk: &Chunk) -> Vec<OpCode> {
+        let mut ops = Vec::new();
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let (op, next) = chunk.try_decode(offset).unwrap();
+            ops.push(op);
+            offset = next;
+        }
+        ops
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let ops = vec![
+            OpCode::Constant { index: 3 },
+            OpCode::Nil,
+            OpCode::GetLocal { index: 255 },
+            OpCode::SetLocal { index: 1 },
+            OpCode::GetGlobal { index: 7 },
+            OpCode::DefineGlobal { index: 8 },
+            OpCode::SetGlobal { index: 9 },
+            OpCode::GetUpvalue { index: 2 },
+            OpCode::SetUpvalue { index: 4 },
+            OpCode::Jump { jump: 0x1234 },
+            OpCode::JumpIfFalse { jump: 1 },
+            OpCode::Loop { jump: 0xffff },
+            OpCode::Call { argc: 255 },
+            OpCode::Closure { index: 0, upvalues: vec![Upvalue::Local(1), Upvalue::Nonlocal(0)] },
+            OpCode::CloseUpvalue,
+            OpCode::Not,
+            OpCode::Return,
+        ];
+        let chunk = chunk(&ops);
+        assert_eq!(chunk.code.len(), ops.iter().map(OpCode::size).sum::<usize>());
+        assert_eq!(decode_all(&chunk), ops);
+    }
+
+    #[test]
+    fn switches_to_long_operands_past_a_byte() {
+        let chunk = chunk(&[
+            OpCode::Constant { index: 255 },
+            OpCode::Constant { index: 256 },
+            OpCode::GetGlobal { index: 0x010203 },
+            OpCode::DefineGlobal { index: 300 },
+            OpCode::SetGlobal { index: MAX_LONG_INDEX },
+        ]);
+        assert_eq!(
+            chunk.code,
+            vec![
+                OP_CONSTANT, 255,
+                OP_CONSTANT_LONG, 0, 1, 0,
+                OP_GET_GLOBAL_LONG, 1, 2, 3,
+                OP_DEFINE_GLOBAL_LONG, 0, 1, 44,
+                OP_SET_GLOBAL_LONG, 255, 255, 255,
+            ]
+        );
+        assert_eq!(decode_all(&chunk)[4], OpCode::SetGlobal { index: MAX_LONG_INDEX });
+    }
+
+    #[test]
+    fn counts_closure_upvalues_in_two_bytes() {
+        // 256 upvalues, one more than a byte can count
+        let upvalues = (0..256).map(|i| Upvalue::Nonlocal(i % 256)).collect::<Vec<_>>();
+        let op = OpCode::Closure { index: 1000, upvalues };
+        let chunk = chunk(std::slice::from_ref(&op));
+        assert_eq!(&chunk.code[..6], &[OP_CLOSURE_LONG, 0, 3, 232, 1, 0]);
+        assert_eq!(chunk.code.len(), op.size());
+        assert_eq!(decode_all(&chunk), vec![op]);
+    }
+
+    // the VM reads a closure's upvalues from the code, short or long
+    #[test]
+    fn reads_closure_upvalues_in_place() {
+        let upvalues = vec![Upvalue::Local(1), Upvalue::Nonlocal(0), Upvalue::Local(255)];
+        let ops = [
+            OpCode::Closure { index: 2, upvalues: upvalues.clone() },
+            OpCode::Closure { index: 300, upvalues: upvalues.clone() },
+        ];
+        let chunk = chunk(&ops);
+        let second = ops[0].size();
+        assert_eq!(chunk.decode_operands(0), (OpCode::Closure { index: 2, upvalues: Vec::new() }, second));
+        assert_eq!(chunk.upvalues_at(0).collect::<Vec<_>>(), upvalues);
+        assert_eq!(chunk.upvalues_at(second).collect::<Vec<_>>(), upvalues);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit in three bytes")]
+    fn refuses_an_index_past_three_bytes() {
+        chunk(&[OpCode::Constant { index: MAX_LONG_INDEX + 1 }]);
+    }
+
+    #[test]
+    fn rejects_cut_short_and_unknown_instructions() {
+        let cut = Chunk::from_parts(vec![OP_CONSTANT_LONG, 0, 1], Vec::new(), vec![1; 3]);
+        assert_eq!(cut.try_decode(0), Err("Instruction is cut short by the end of the code."));
+        let closure = Chunk::from_parts(vec![OP_CLOSURE, 0, 0, 1, 1], Vec::new(), vec![1; 5]);
+        assert_eq!(closure.try_decode(0), Err("Instruction is cut short by the end of the code."));
+        let unknown = Chunk::from_parts(vec![OP_NOT + 1], Vec::new(), vec![1]);
+        assert_eq!(unknown.try_decode(0), Err("Unknown opcode."));
+        assert_eq!(unknown.try_decode(1), Err("Offset is past the end of the code."));
+    }
+
+    #[test]
+    fn rewrite_retargets_jumps_around_grown_operands() {
+        // a jump over a constant that moves to the long form
+        let chunk = chunk(&[
+            OpCode::Jump { jump: 2 },
+            OpCode::Constant { index: 0 },
+            OpCode::Loop { jump: 8 },
+            OpCode::Return,
+        ]);
+        let rewritten = chunk.rewrite(|op| match op {
+            OpCode::Constant { .. } => OpCode::Constant { index: 256 },
+            op => op,
+        });
+        assert_eq!(
+            decode_all(&rewritten),
+            vec![
+                OpCode::Jump { jump: 4 },
+                OpCode::Constant { index: 256 },
+                OpCode::Loop { jump: 10 },
+                OpCode::Return,
+            ]
+        );
     }
 }
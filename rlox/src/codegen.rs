@@ -0,0 +1,888 @@
+use ast::Decl;
+use ast::DeclKind;
+use ast::Expr;
+use ast::ExprKind;
+use ast::Function as FunctionDecl;
+use ast::Program;
+use ast::Stmt;
+use ast::StmtKind;
+
+use chunk::LocalName;
+use chunk::OpCode;
+use chunk::MAX_LONG_INDEX;
+
+use compiler::BytecodeEmitter;
+use compiler::Upvalue;
+
+use diagnostic::Diagnostic;
+
+use limits::Limits;
+
+use object::Function;
+use object::FunctionType;
+use object::ObjType;
+
+use parser::Parser;
+use parser::Position;
+
+use scanner::Token;
+use scanner::TokenType;
+
+use symbols::SymbolKind;
+
+use value::Value;
+
+#[derive(Debug, Clone)]
+struct Local {
+    name: Token,
+    depth: i32,
+    is_captured: bool,
+    // offset of the first instruction that runs with the local initialized
+    start: usize,
+    // where the parser's symbols record its declaration
+    definition: Option<usize>,
+    // read, or captured by a closure
+    used: bool,
+}
+
+#[derive(Clone)]
+struct ScopeCell {
+    locals: Vec<Local>,
+    pub upvalues: Vec<Upvalue>,
+    depth: i32,
+    pub emitter: BytecodeEmitter,
+}
+
+impl ScopeCell {
+
+    fn new(limits: Limits) -> ScopeCell {
+        ScopeCell {
+            locals: vec![
+                Local {
+                    name: Token {
+                        tpe: TokenType::Undefined,
+                        line: 0,
+                        text: String::from(""),
+                        start: 0,
+                        length: 0,
+                    },
+                    depth: 0,
+                    is_captured: false,
+                    start: 0,
+                    definition: None,
+                    used: false,
+                }
+            ],
+            upvalues: Vec::new(),
+            depth: 0,
+            emitter: BytecodeEmitter::new(limits),
+        }
+    }
+
+    fn add_upvalue(&mut self, up: Upvalue, name: &str) -> Result<usize, &'static str> {
+        let upvalue = self.upvalues
+            .iter().enumerate()
+            .find(|(_i,u)| **u == up);
+            match upvalue {
+                Some((i,_)) => Ok(i),
+                None => {
+                    if self.upvalues.len() >= self.emitter.limits.upvalues {
+                        return Err("Too many closure variables in function.");
+                    }
+                    self.upvalues.push(up);
+                    self.emitter.chunk().add_upvalue(name);
+                    Ok(self.upvalues.len() - 1)
+                }
+            }
+    }
+
+    fn add_local(&mut self, name: Token, definition: Option<usize>) -> Result<(), &'static str> {
+        if self.locals.len() >= self.emitter.limits.locals {
+            return Err("Too many local variables in function.");
+        }
+        let local = Local { name, depth: -1, is_captured: false, start: 0, definition, used: false };
+        self.locals.push(local);
+        Ok(())
+    }
+
+    fn mark_initialized(&mut self) {
+        if self.depth == 0 {
+            return;
+        }
+        let lastidx = self.locals.len() - 1;
+        self.locals[lastidx].depth = self.depth;
+        self.locals[lastidx].start = self.emitter.function.chunk.code.len();
+    }
+
+    // Records where a local is live, for the debugger, before it goes out of scope.
+    fn record_local(&mut self, slot: usize) {
+        let local = &self.locals[slot];
+        let name = LocalName {
+            name: local.name.text.clone(),
+            slot,
+            start: local.start,
+            end: self.emitter.function.chunk.code.len(),
+        };
+        self.emitter.chunk().add_local(name);
+    }
+
+    // The locals of the function body are never popped: they go with the frame.
+    fn finish(&mut self) {
+        for slot in 1..self.locals.len() {
+            if self.locals[slot].depth > 0 {
+                self.record_local(slot);
+            }
+        }
+    }
+
+    fn resolve_local(&self, name: &Token) -> Result<Option<usize>, &'static str> {
+        for (i, local) in self.locals.iter().enumerate().rev() {
+            if name.text == local.name.text {
+                if local.depth == -1 {
+                    return Err("Can't read local variable in its own initializer.");
+                } else {
+                    return Ok(Some(i));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn depth(&mut self) -> i32 {
+        self.depth
+    }
+
+    fn locals(&mut self) -> &mut Vec<Local> {
+        &mut self.locals
+    }
+
+    fn begin(&mut self) {
+        self.depth += 1;
+    }
+
+    fn end(&mut self, line: usize) -> i32 {
+        self.depth -= 1;
+
+        let mut count: i32 = 0;
+        while !self.locals().is_empty() && self.locals().last().unwrap().depth > self.depth() {
+            self.record_local(self.locals.len() - 1);
+            if self.locals().last().unwrap().is_captured {
+                self.emitter.emit_byte(OpCode::CloseUpvalue, line);
+            } else {
+                self.emitter.emit_byte(OpCode::Pop, line);
+            }
+            self.locals().pop();
+            count += 1;
+        }
+        count
+    }
+}
+
+struct Scope {
+    pub stack: Vec<ScopeCell>,
+}
+
+impl Scope {
+    fn new(limits: Limits) -> Scope {
+        Scope {
+            stack: vec! [ ScopeCell::new(limits) ]
+        }
+    }
+
+    // The index of the upvalue that captures the variable, and where the variable
+    // was declared.
+    fn resolve_upvalue(&mut self, name: &Token) -> Result<Option<(usize, Option<usize>)>, &'static str> {
+        // there is no enclosing function: assume global
+        if self.stack.len() == 1 {
+            return Ok(None);
+        }
+
+        let mut enclosing_stack = Vec::new();
+        let s = &mut self.stack;
+
+        let mut loc = None;
+        let mut definition = None;
+
+        for enclosing in (0..s.len()-1).rev() {
+            let current = enclosing+1;
+            if let Some(local) = s[enclosing].resolve_local(name)? {
+                s[enclosing].locals()[local].is_captured = true;
+                s[enclosing].locals()[local].used = true;
+                definition = s[enclosing].locals()[local].definition;
+                loc = Some(s[current].add_upvalue(Upvalue::Local(local), &name.text)?);
+                break;
+            } else {
+                enclosing_stack.push(current);
+            }
+        }
+
+        if let Some(mut l) = loc {
+            while let Some(enclosing) = enclosing_stack.pop() {
+                l = s[enclosing].add_upvalue(Upvalue::Nonlocal(l), &name.text)?;
+            }
+            loc = Some(l);
+        }
+
+
+        Ok(loc.map(|l| (l, definition)))
+
+    }
+
+}
+
+// Lowers the parser's program to bytecode, resolving names to locals, upvalues and
+// global slots as it goes. Every instruction keeps the line it had when the
+// compiler emitted code while parsing: that of the last token read before it.
+pub struct CodeGenerator<'a> {
+    parser: &'a mut Parser,
+    scope: Scope,
+    limits: Limits,
+    // with where they belong in the source, and where the code after them makes
+    // sense again: the end of the next declaration to be finished
+    errors: Vec<(Diagnostic, Position, Position)>,
+    // the errors from this one on are waiting for that declaration
+    unsettled: usize,
+    // the last token of each enclosing declaration, and where its end belongs
+    ends: Vec<(usize, Position)>,
+    // globals the VM already had before this source, such as natives
+    known_globals: usize,
+    // assignments to globals, checked once every declaration has been seen
+    assigned_globals: Vec<Token>,
+}
+
+impl<'a> CodeGenerator<'a> {
+    pub fn new(parser: &'a mut Parser) -> CodeGenerator<'a> {
+        let limits = parser.limits();
+        let known_globals = parser.memory().globals.names().len();
+        CodeGenerator {
+            parser,
+            scope: Scope::new(limits),
+            limits,
+            errors: Vec::new(),
+            unsettled: 0,
+            ends: Vec::new(),
+            known_globals,
+            assigned_globals: Vec::new(),
+        }
+    }
+
+    // The script, or `None` if the source has errors, which go to the parser.
+    pub fn program(mut self, program: &Program) -> Option<Function> {
+        for decl in &program.declarations {
+            self.declaration(decl);
+        }
+        self.warn_undeclared_globals();
+        self.end(program.eof.line);
+        self.finish()
+    }
+
+    // Lowers an expression to the body of a function that takes `names` as its
+    // parameters, for the debugger to evaluate in a paused frame.
+    pub fn expression_function(mut self, names: &[String], expr: &Expr) -> Option<Function> {
+        self.scope().begin();
+        for name in names {
+            let token = Token {
+                tpe: TokenType::Identifier,
+                text: name.clone(),
+                line: 1,
+                start: 0,
+                length: 0,
+            };
+            if let Err(msg) = self.scope().add_local(token, None) {
+                self.error(&expr.span.first, msg);
+                return self.finish();
+            }
+            self.scope().mark_initialized();
+        }
+        self.emitter().function.arity = names.len() as u32;
+
+        self.expression(expr);
+        let l = expr.span.last.line;
+        self.emitter().emit_byte(OpCode::Return, l);
+        self.finish()
+    }
+
+    fn finish(mut self) -> Option<Function> {
+        let errors = std::mem::take(&mut self.errors);
+        self.parser.add_errors(errors);
+        if self.parser.had_error() {
+            None
+        } else {
+            Some(self.emitter().function.clone())
+        }
+    }
+
+    fn scope(&mut self) -> &mut ScopeCell {
+        self.scope.stack.last_mut().unwrap()
+    }
+
+    fn emitter(&mut self) -> &mut BytecodeEmitter {
+        &mut self.scope.stack.last_mut().unwrap().emitter
+    }
+
+    fn declaration(&mut self, decl: &Decl) {
+        let depth = self.ends.len() + 1;
+        self.ends.push((decl.span.last.start, Position::within(&decl.span, depth)));
+        match &decl.kind {
+            DeclKind::Var { name, initializer } => {
+                self.var_declaration(decl, name, initializer.as_deref())
+            }
+            DeclKind::Fun(function) => self.fun_declaration(decl, function),
+            DeclKind::Statement(stmt) => self.statement(stmt),
+        }
+        self.settle(Position::after(&decl.span, depth));
+        self.ends.pop();
+    }
+
+    fn var_declaration(&mut self, decl: &Decl, name: &Token, initializer: Option<&Expr>) {
+        let global = self.parse_variable(name);
+
+        match initializer {
+            Some(initializer) => self.expression(initializer),
+            None => self.emitter().emit_byte(OpCode::Nil, name.line),
+        }
+
+        self.define_variable(global, decl.span.last.line);
+    }
+
+    fn fun_declaration(&mut self, decl: &Decl, function: &FunctionDecl) {
+        let global = self.parse_variable(&function.name);
+        let definition = self.parser.symbols().last();
+        self.scope().mark_initialized();
+        let arity = self.function(decl, function);
+        self.parser.symbols().set_kind(definition, SymbolKind::Function { arity });
+        self.define_variable(global, decl.span.last.line);
+    }
+
+    // Returns the arity of the function.
+    fn function(&mut self, decl: &Decl, function: &FunctionDecl) -> u32 {
+        let close = &decl.span.last;
+        let mut scope = ScopeCell::new(self.limits);
+        scope.emitter.function = Function::named(function.name.text.clone(), 0);
+        scope.emitter.function.tpe = FunctionType::Function;
+        self.scope.stack.push(scope);
+
+        self.scope().begin();
+        for param in &function.params {
+            self.emitter().function.arity += 1;
+            let constant = self.parse_variable(param);
+            let definition = self.parser.symbols().last();
+            self.parser.symbols().set_kind(definition, SymbolKind::Parameter);
+            self.define_variable(constant, param.line);
+        }
+        // the parser skips what is left of a broken parameter list up to the body
+        if function.open.tpe == TokenType::LeftBrace {
+            self.settle(Position::past(&function.open));
+        }
+
+        self.block(&function.body);
+
+        self.end(close.line);
+        self.warn_unused();
+        self.scope().finish();
+
+        let scope = self.scope.stack.pop().unwrap();
+
+        let function = scope.emitter.function;
+        let arity = function.arity;
+        let ftype = ObjType::Function(function);
+        let value = Value::object(ftype);
+        let index = self.make_constant(value, close);
+
+        self.emitter().emit_byte(OpCode::Closure{ index, upvalues: scope.upvalues }, close.line);
+        arity
+    }
+
+    fn block(&mut self, declarations: &[Decl]) {
+        // the `return` the rest of the block comes after
+        let mut returned = None;
+        for decl in declarations {
+            if let Some(keyword) = returned.take() {
+                let diagnostic = Diagnostic::warning(decl.span.first.clone(), "unreachable-code", "Unreachable code.")
+                    .with_note(keyword, "after this return");
+                self.parser.warn(diagnostic);
+            }
+            if decl.span.first.tpe == TokenType::Return {
+                returned = Some(decl.span.first.clone());
+            }
+            self.declaration(decl);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        let last = &stmt.span.last;
+        match &stmt.kind {
+            StmtKind::Expression(expr) => {
+                self.expression(expr);
+                self.emitter().emit_byte(OpCode::Pop, last.line);
+            }
+            StmtKind::Print(expr) => {
+                self.expression(expr);
+                self.emitter().emit_byte(OpCode::Print, last.line);
+            }
+            StmtKind::Block(declarations) => {
+                self.scope().begin();
+                self.block(declarations);
+                self.warn_unused();
+                self.scope().end(last.line);
+            }
+            StmtKind::If { condition, paren, then, otherwise } => {
+                self.expression(condition);
+                self.warn_constant_condition(&stmt.span.first, condition);
+
+                let then_jump = self.emitter().emit_jump(OpCode::JumpIfFalse { jump: 0xFFFF }, paren.line);
+                self.emitter().emit_byte(OpCode::Pop, paren.line);
+                self.statement(then);
+
+                let then_last = &then.span.last;
+                let else_jump = self.emitter().emit_jump(OpCode::Jump { jump: 0xFFFF }, then_last.line);
+
+                self.patch_jump(then_jump, then_last);
+                self.emitter().emit_byte(OpCode::Pop, then_last.line);
+
+                if let Some(otherwise) = otherwise {
+                    self.statement(otherwise);
+                }
+
+                self.patch_jump(else_jump, last)
+            }
+            StmtKind::While { condition, paren, body } => {
+                let keyword = &stmt.span.first;
+                let loop_start = self.emitter().chunk().code.len();
+                self.expression(condition);
+
+                let exit_jump = self.emitter().emit_jump(OpCode::JumpIfFalse { jump: 0xFFFF }, paren.line);
+                self.emitter().emit_byte(OpCode::Pop, paren.line);
+
+                self.statement(body);
+                self.emit_loop(loop_start, keyword, last);
+
+                self.patch_jump(exit_jump, last);
+                self.emitter().emit_byte(OpCode::Pop, last.line);
+            }
+            StmtKind::For { initializer, condition, semicolon, increment, paren, body } => {
+                let keyword = &stmt.span.first;
+                self.scope().begin();
+
+                match initializer.as_ref().map(|decl| (decl, &decl.kind)) {
+                    Some((decl, DeclKind::Var { name, initializer })) => {
+                        self.var_declaration(decl, name, initializer.as_deref())
+                    }
+                    Some((_, DeclKind::Statement(stmt))) => self.statement(stmt),
+                    _ => {}
+                }
+
+                let mut loop_start = self.emitter().chunk().code.len();
+
+                let mut exit_jump = None;
+
+                if let Some(condition) = condition {
+                    self.expression(condition);
+
+                    // Jump out of the loop if the condition is false.
+                    exit_jump = Some(self.emitter().emit_jump(OpCode::JumpIfFalse { jump: 0xFFFF }, semicolon.line));
+                    self.emitter().emit_byte(OpCode::Pop, semicolon.line); // Condition.
+                }
+
+                if let Some(increment) = increment {
+                    let body_jump = self.emitter().emit_jump(OpCode::Jump { jump: 0xFFFF }, semicolon.line);
+
+                    let increment_start = self.emitter().chunk().code.len();
+                    self.expression(increment);
+
+                    self.emitter().emit_byte(OpCode::Pop, paren.line);
+                    self.emit_loop(loop_start, keyword, paren);
+
+                    loop_start = increment_start;
+
+                    self.patch_jump(body_jump, paren);
+                }
+
+                self.statement(body);
+                self.emit_loop(loop_start, keyword, last);
+
+                if let Some(jump) = exit_jump {
+                    self.patch_jump(jump, last);
+                    self.emitter().emit_byte(OpCode::Pop, last.line); // Condition.
+                }
+
+                self.warn_unused();
+                self.scope().end(last.line);
+            }
+            StmtKind::Return(value) => {
+                if self.emitter().function.tpe == FunctionType::Script {
+                    self.error(&stmt.span.first, "Can't return from top-level code.");
+                }
+                match value {
+                    None => self.emitter().emit_return(last.line),
+                    Some(value) => {
+                        self.expression(value);
+                        self.emitter().emit_byte(OpCode::Return, last.line);
+                    }
+                }
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        let first = &expr.span.first;
+        let last = &expr.span.last;
+        match &expr.kind {
+            ExprKind::Number(n) => self.emit_constant(Value::number(*n), first),
+            ExprKind::String(chars) => {
+                let value = Value::object(ObjType::String(self.parser.memory().strings.intern(chars.clone())));
+                self.emit_constant(value, first);
+            }
+            ExprKind::True => self.emitter().emit_byte(OpCode::True, first.line),
+            ExprKind::False => self.emitter().emit_byte(OpCode::False, first.line),
+            ExprKind::Nil => self.emitter().emit_byte(OpCode::Nil, first.line),
+            ExprKind::Variable(name) => self.named_variable(name, None, last),
+            ExprKind::Assign { name, value } => self.named_variable(name, Some(value), last),
+            ExprKind::Unary { operator, operand } => {
+                self.expression(operand);
+                match operator.tpe {
+                    TokenType::Bang => self.emitter().emit_byte(OpCode::Not, last.line),
+                    TokenType::Minus => self.emitter().emit_byte(OpCode::Negate, last.line),
+                    _ => {} // Unreachable.
+                }
+            }
+            ExprKind::Binary { operator, left, right } => {
+                self.expression(left);
+                self.expression(right);
+                self.warn_self_comparison(operator, left, right);
+
+                let line = operator.line;
+                match operator.tpe {
+                    TokenType::BangEqual => self.emitter().emit_bytes(OpCode::Equal, OpCode::Not, line),
+                    TokenType::EqualEqual => self.emitter().emit_byte(OpCode::Equal, line),
+                    TokenType::Greater => self.emitter().emit_byte(OpCode::Greater, line),
+                    TokenType::GreaterEqual => self.emitter().emit_bytes(OpCode::Less, OpCode::Not, line),
+                    TokenType::Less => self.emitter().emit_byte(OpCode::Less, line),
+                    TokenType::LessEqual => self.emitter().emit_bytes(OpCode::Greater, OpCode::Not, line),
+                    TokenType::Plus => self.emitter().emit_byte(OpCode::Add, line),
+                    TokenType::Minus => self.emitter().emit_byte(OpCode::Subtract, line),
+                    TokenType::Star => self.emitter().emit_byte(OpCode::Multiply, line),
+                    TokenType::Slash => self.emitter().emit_byte(OpCode::Divide, line),
+                    _ => {} // Unreachable.
+                }
+            }
+            ExprKind::Logical { operator, left, right } if operator.tpe == TokenType::And => {
+                self.expression(left);
+                let l = operator.line;
+                let end_jump = self.emitter().emit_jump(OpCode::JumpIfFalse { jump: 0xFFFF }, l);
+                self.emitter().emit_byte(OpCode::Pop, l);
+                self.expression(right);
+                self.patch_jump(end_jump, last);
+            }
+            ExprKind::Logical { operator, left, right } => {
+                self.expression(left);
+                let l = operator.line;
+                let else_jump = self.emitter().emit_jump(OpCode::JumpIfFalse { jump: 0xFFFF }, l);
+                let end_jump = self.emitter().emit_jump(OpCode::Jump { jump: 0xFFFF }, l);
+                self.patch_jump(else_jump, operator);
+                self.emitter().emit_byte(OpCode::Pop, l);
+                self.expression(right);
+                self.patch_jump(end_jump, last);
+            }
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Call { callee, arguments } => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                // the parser has reported a call with too many arguments to encode
+                if arguments.len() <= self.limits.arguments {
+                    let argc = arguments.len() as u32;
+                    self.emitter().emit_byte(OpCode::Call{ argc }, last.line);
+                }
+            }
+            ExprKind::Invalid => {}
+        }
+    }
+
+    // Reads a variable, or with a value assigns to it.
+    fn named_variable(&mut self, name: &Token, value: Option<&Expr>, last: &Token) {
+        let cons_get: fn (usize) -> OpCode;
+        let cons_set: fn (usize) -> OpCode;
+
+        let arg;
+        // a local read here counts as used; a global assigned here must be declared somewhere
+        let mut local = None;
+        let mut global = false;
+        match self.scope().resolve_local(name) {
+            Err(msg) => {
+                self.error(name, msg);
+                return;
+            }
+            Ok(Some(arg_)) => {
+                arg = arg_;
+                local = Some(arg);
+                let definition = self.scope().locals[arg].definition;
+                self.parser.symbols().refer(name.clone(), definition);
+                cons_get = |index| OpCode::GetLocal { index };
+                cons_set = |index| OpCode::SetLocal { index };
+            }
+            Ok(None) => {
+                match self.scope.resolve_upvalue(name) {
+                    Err(msg) => {
+                        self.error(name, msg);
+                        return;
+                    }
+                    Ok(Some((arg_, definition))) => {
+                        arg = arg_;
+                        self.parser.symbols().refer(name.clone(), definition);
+                        cons_get = |index| OpCode::GetUpvalue { index };
+                        cons_set = |index| OpCode::SetUpvalue { index };
+                    }
+                    Ok(None) => {
+                        arg = self.global_slot(name);
+                        global = true;
+                        self.parser.symbols().refer(name.clone(), None);
+                        cons_get = |index| OpCode::GetGlobal { index };
+                        cons_set = |index| OpCode::SetGlobal { index };
+                    }
+                }
+            }
+        }
+
+        match value {
+            Some(value) => {
+                if global {
+                    self.assigned_globals.push(name.clone());
+                }
+                self.expression(value);
+                self.emitter().emit_byte(cons_set(arg), last.line)
+            }
+            None => {
+                if let Some(local) = local {
+                    self.scope().locals[local].used = true;
+                }
+                self.emitter().emit_byte(cons_get(arg), last.line)
+            }
+        }
+    }
+
+    fn global_slot(&mut self, name: &Token) -> usize {
+        let slot = self.parser.memory().globals.resolve(&name.text);
+        if slot > MAX_LONG_INDEX {
+            self.report_name(Diagnostic::new(name.clone(), "Too many global variables."));
+            return 0;
+        }
+        slot
+    }
+
+    fn make_constant(&mut self, value: Value, at: &Token) -> usize {
+        match self.emitter().write_constant(value) {
+            Ok(index) => index,
+            Err(msg) => {
+                self.report_after(Diagnostic::new(at.clone(), msg));
+                0
+            }
+        }
+    }
+
+    fn emit_constant(&mut self, value: Value, at: &Token) {
+        if let Err(msg) = self.emitter().emit_constant(value, at.line) {
+            self.error(at, msg);
+        }
+    }
+
+    fn patch_jump(&mut self, offset: usize, at: &Token) {
+        if let Err(msg) = self.emitter().patch_jump(offset) {
+            self.report_after(Diagnostic::new(at.clone(), msg));
+        }
+    }
+
+    fn emit_loop(&mut self, loop_start: usize, keyword: &Token, at: &Token) {
+        // the VM has already moved past the Loop instruction when it jumps back
+        let jump = self.emitter().chunk().code.len() + OpCode::Loop { jump: 0 }.size() - loop_start;
+        if jump > self.limits.jump {
+            let diagnostic = Diagnostic::new(at.clone(), "Loop body too large.")
+                .with_note(keyword.clone(), "loop starts here");
+            self.report_after(diagnostic);
+            return;
+        }
+
+        self.emitter().emit_byte(OpCode::Loop { jump }, at.line);
+    }
+
+    fn declare_variable(&mut self, name: &Token) {
+        if self.scope().depth() == 0 {
+            return;
+        }
+        let iter = &self.scope().locals().clone();
+        for local in iter.iter().rev() {
+            if local.depth != -1 && local.depth < self.scope().depth() {
+                break;
+            }
+            if name.text == local.name.text {
+                let diagnostic = Diagnostic::new(name.clone(), "Already variable with this name in this scope.")
+                    .with_note(local.name.clone(), "variable declared here");
+                self.report_name(diagnostic);
+            }
+        }
+        self.warn_shadowing(name);
+
+        let definition = self.parser.symbols().define(name.clone(), false);
+        if let Err(msg) = self.scope().add_local(name.clone(), Some(definition)) {
+            self.report_name(Diagnostic::new(name.clone(), msg));
+        }
+    }
+
+    fn parse_variable(&mut self, name: &Token) -> usize {
+        self.declare_variable(name);
+        if self.scope().depth() > 0 {
+            return 0;
+        }
+
+        self.parser.symbols().define(name.clone(), true);
+        self.global_slot(name)
+    }
+
+    fn define_variable(&mut self, index: usize, line: usize) {
+        if self.scope().depth() > 0 {
+            self.scope().mark_initialized();
+            return;
+        }
+
+        self.emitter()
+            .emit_byte(OpCode::DefineGlobal { index }, line);
+    }
+
+    fn end(&mut self, line: usize) {
+        self.emitter().emit_return(line);
+        if cfg!(feature = "debug-print-code") && !self.parser.had_error() && self.errors.is_empty() {
+            let s = self.emitter().function.name.clone().or(Some("<script>".to_string()));
+            let globals = self.parser.memory().globals.names().to_vec();
+            self.emitter().chunk().disassemble(&s.unwrap(), &globals);
+        }
+    }
+
+    fn error(&mut self, token: &Token, message: &str) {
+        self.report(Diagnostic::new(token.clone(), message));
+    }
+
+    fn report(&mut self, diagnostic: Diagnostic) {
+        let found = Position::at(&diagnostic.token);
+        self.errors.push((diagnostic, found, Position::END));
+    }
+
+    // Reports an error in code emitted once what it covers was parsed: one at the
+    // last token of a declaration was found after the errors that ended it.
+    fn report_after(&mut self, diagnostic: Diagnostic) {
+        let found = match self.ends.last() {
+            Some((last, end)) if *last == diagnostic.token.start => *end,
+            _ => Position::at(&diagnostic.token),
+        };
+        self.errors.push((diagnostic, found, Position::END));
+    }
+
+    // Reports an error about a name, unless the parser did not find one: it has
+    // reported that, and the token it kept in the name's place is not worth another.
+    fn report_name(&mut self, diagnostic: Diagnostic) {
+        if diagnostic.token.tpe == TokenType::Identifier {
+            self.report(diagnostic);
+        }
+    }
+
+    // Records where the code after the errors found since the last call makes sense
+    // again.
+    fn settle(&mut self, position: Position) {
+        for (_, _, recovered) in &mut self.errors[self.unsettled..] {
+            *recovered = position;
+        }
+        self.unsettled = self.errors.len();
+    }
+
+    // Warns about the locals of the scope being closed that are never read.
+    fn warn_unused(&mut self) {
+        let depth = self.scope().depth();
+        let unused = self.scope().locals.iter()
+            .filter(|local| local.depth >= depth && !local.used && !local.name.text.starts_with('_'))
+            .filter_map(|local| local.definition.map(|definition| (local.name.clone(), definition)))
+            .collect::<Vec<_>>();
+        for (name, definition) in unused {
+            let diagnostic = match self.parser.symbols().definitions()[definition].kind {
+                SymbolKind::Parameter => {
+                    let message = format!("Parameter '{}' is never used.", name.text);
+                    Diagnostic::warning(name, "unused-parameter", &message)
+                }
+                SymbolKind::Function { .. } => {
+                    let message = format!("Function '{}' is never used.", name.text);
+                    Diagnostic::warning(name, "unused-variable", &message)
+                }
+                SymbolKind::Variable => {
+                    let message = format!("Variable '{}' is never used.", name.text);
+                    Diagnostic::warning(name, "unused-variable", &message)
+                }
+            };
+            self.parser.warn(diagnostic);
+        }
+    }
+
+    // Warns if a new local hides one of an enclosing scope, or of an enclosing function.
+    fn warn_shadowing(&mut self, name: &Token) {
+        let depth = self.scope().depth();
+        let (current, enclosing) = self.scope.stack.split_last().unwrap();
+        let outer = current.locals.iter().rev()
+            .filter(|local| local.depth != -1 && local.depth < depth)
+            .chain(enclosing.iter().rev().flat_map(|cell| cell.locals.iter().rev()))
+            .find(|local| local.name.text == name.text)
+            .map(|local| local.name.clone());
+        if let Some(outer) = outer {
+            let message = format!("'{}' shadows a variable of an enclosing scope.", name.text);
+            let diagnostic = Diagnostic::warning(name.clone(), "shadowing", &message)
+                .with_note(outer, "shadowed variable declared here");
+            self.parser.warn(diagnostic);
+        }
+    }
+
+    // Warns if the condition is a single literal.
+    fn warn_constant_condition(&mut self, keyword: &Token, condition: &Expr) {
+        let value = match &ungrouped(condition).kind {
+            ExprKind::True | ExprKind::Number(_) | ExprKind::String(_) => "true",
+            ExprKind::False | ExprKind::Nil => "false",
+            _ => return,
+        };
+        let message = format!("Condition is always {}.", value);
+        self.parser.warn(Diagnostic::warning(keyword.clone(), "constant-condition", &message));
+    }
+
+    // Warns if both operands of a comparison read the same variable.
+    fn warn_self_comparison(&mut self, operator: &Token, left: &Expr, right: &Expr) {
+        match operator.tpe {
+            TokenType::EqualEqual | TokenType::BangEqual | TokenType::Greater
+            | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {}
+            _ => return,
+        }
+        match (&ungrouped(left).kind, &ungrouped(right).kind) {
+            (ExprKind::Variable(a), ExprKind::Variable(b)) if a.text == b.text => {}
+            _ => return,
+        }
+        let message = format!("Both sides of '{}' are the same variable.", operator.text);
+        self.parser.warn(Diagnostic::warning(operator.clone(), "self-comparison", &message));
+    }
+
+    // Warns about assignments to globals that neither the source nor the VM declares.
+    fn warn_undeclared_globals(&mut self) {
+        let known = self.parser.memory().globals.names()[..self.known_globals].to_vec();
+        let symbols = self.parser.symbols();
+        let undeclared = self.assigned_globals.iter()
+            .filter(|name| !known.contains(&name.text))
+            .filter(|name| !symbols.definitions().iter().any(|d| d.global && d.token.text == name.text))
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in undeclared {
+            let message = format!("Assignment to undeclared global '{}'.", name.text);
+            self.parser.warn(Diagnostic::warning(name, "undeclared-global", &message));
+        }
+    }
+}
+
+// What an expression in parentheses compiles to: the same code as without them.
+fn ungrouped(expr: &Expr) -> &Expr {
+    match &expr.kind {
+        ExprKind::Grouping(inner) => ungrouped(inner),
+        _ => expr,
+    }
+}
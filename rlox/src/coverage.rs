@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use chunk::Chunk;
+use chunk::OpCode;
+use object::Function;
+use object::ObjType;
+use profile::function_name;
+use value::Unpacked;
+
+// What `--coverage` collects for a script: how often each line with code was
+// reached and which way each `JumpIfFalse` went.
+pub struct Coverage {
+    // every line that has code, with how many times running reached it
+    lines: BTreeMap<usize, u64>,
+    // (line, function, offset) of a `JumpIfFalse`, with how often it fell
+    // through and how often it jumped
+    branches: BTreeMap<(usize, String, usize), [u64; 2]>,
+    // the line each frame is on, by depth
+    current: Vec<usize>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            lines: BTreeMap::new(),
+            branches: BTreeMap::new(),
+            current: Vec::new(),
+        }
+    }
+
+    // Records the lines and branches of a script and every function in it, so
+    // that the ones never run are reported too.
+    pub fn add(&mut self, function: &Function) {
+        let chunk = &function.chunk;
+        let end = match function.name {
+            None => implicit_return(chunk),
+            Some(_) => None,
+        };
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let line = chunk.line_at(offset);
+            if Some(line) != end {
+                self.lines.entry(line).or_insert(0);
+            }
+            let (instruction, next) = chunk.decode(offset);
+            if let OpCode::JumpIfFalse { .. } = instruction {
+                self.branches.entry((line, function_name(function).to_string(), offset)).or_insert([0, 0]);
+            }
+            offset = next;
+        }
+        for value in chunk.values.iter() {
+            if let Unpacked::Object(ObjType::Function(nested)) = value.unpack() {
+                self.add(nested);
+            }
+        }
+    }
+
+    // Counts a line each time a frame at `depth` moves onto it, and each time a
+    // backward jump goes to it, so a loop on a single line counts every pass.
+    pub fn instruction(&mut self, function: &Function, ip: usize, depth: usize) {
+        let line = function.chunk.line_at(ip);
+        self.current.resize(depth + 1, 0);
+        if self.current[depth] != line {
+            self.current[depth] = line;
+            if let Some(count) = self.lines.get_mut(&line) {
+                *count += 1;
+            }
+        }
+        if let (OpCode::Loop { .. }, _) = function.chunk.decode_operands(ip) {
+            // no line is numbered zero
+            self.current[depth] = 0;
+        }
+    }
+
+    pub fn branch(&mut self, function: &Function, ip: usize, jumped: bool) {
+        let key = (function.chunk.line_at(ip), function_name(function).to_string(), ip);
+        self.branches.entry(key).or_insert([0, 0])[jumped as usize] += 1;
+    }
+
+    // The tracefile format read by genhtml and most coverage services.
+    pub fn lcov(&self, path: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "TN:").unwrap();
+        writeln!(out, "SF:{}", path).unwrap();
+        let mut taken = 0;
+        for (block, ((line, _, _), counts)) in self.branches.iter().enumerate() {
+            let reached = counts[0] + counts[1] > 0;
+            for (branch, count) in counts.iter().enumerate() {
+                if reached {
+                    writeln!(out, "BRDA:{},{},{},{}", line, block, branch, count).unwrap();
+                } else {
+                    writeln!(out, "BRDA:{},{},{},-", line, block, branch).unwrap();
+                }
+                if *count > 0 {
+                    taken += 1;
+                }
+            }
+        }
+        writeln!(out, "BRF:{}", self.branches.len() * 2).unwrap();
+        writeln!(out, "BRH:{}", taken).unwrap();
+        for (line, count) in self.lines.iter() {
+            writeln!(out, "DA:{},{}", line, count).unwrap();
+        }
+        writeln!(out, "LF:{}", self.lines.len()).unwrap();
+        writeln!(out, "LH:{}", self.lines.values().filter(|count| **count > 0).count()).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+        out
+    }
+
+    // The source with counts in the margin, in the style of gcov: `-` for lines
+    // without code and `#####` for lines never reached.
+    pub fn annotate(&self, source: &str) -> String {
+        let mut out = String::new();
+        for (i, text) in source.lines().enumerate() {
+            let count = match self.lines.get(&(i + 1)) {
+                None => String::from("-"),
+                Some(0) => String::from("#####"),
+                Some(count) => count.to_string(),
+            };
+            writeln!(out, "{:>9}:{:>5}:{}", count, i + 1, text).unwrap();
+        }
+        out
+    }
+}
+
+// The line of the `NIL` and `RETURN` a script ends with, when they are past the last
+// line with code: the compiler puts them on the line of the end of the file, which
+// has nothing on it to run.
+fn implicit_return(chunk: &Chunk) -> Option<usize> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (instruction, next) = chunk.decode_operands(offset);
+        instructions.push((instruction, chunk.line_at(offset)));
+        offset = next;
+    }
+    match instructions.split_last_chunk::<2>() {
+        Some((code, [(OpCode::Nil, nil), (OpCode::Return, line)]))
+            if nil == line && code.iter().all(|(_, other)| other < line) => Some(*line),
+        _ => None,
+    }
+}
@@ -0,0 +1,393 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::path::Path;
+use std::rc::Rc;
+
+use debugger::Breakpoints;
+use debugger::Debugger;
+use debugger::Frontend;
+use debugger::Reason;
+use debugger::Resume;
+use json::Json;
+use object::Function;
+use object::ObjType;
+use protocol;
+use protocol::object;
+use value::Unpacked;
+use vm::InterpretResult;
+use vm::VM;
+
+// `rlox dap`: a Debug Adapter Protocol server on stdin and stdout. It debugs one
+// Lox script, named by the `launch` request, on its only thread.
+pub fn serve(vm: &mut VM) {
+    let connection = Rc::new(RefCell::new(Connection::new()));
+    let mut breakpoints = Breakpoints::new();
+    let mut program: Option<(String, Function, bool)> = None;
+
+    // initialize, launch and the breakpoints, until configurationDone
+    loop {
+        let request = match connection.borrow_mut().read() {
+            Some(request) => request,
+            None => return,
+        };
+        let mut connection = connection.borrow_mut();
+        match command(&request) {
+            "initialize" => {
+                connection.respond(&request, object(vec![
+                    ("supportsConfigurationDoneRequest", Json::Bool(true)),
+                    ("supportsFunctionBreakpoints", Json::Bool(true)),
+                    ("supportsEvaluateForHovers", Json::Bool(true)),
+                ]));
+            }
+            "launch" => {
+                let arguments = request.get("arguments");
+                let path = arguments.and_then(|a| a.get("program")).and_then(Json::as_str);
+                let stop_on_entry = arguments.and_then(|a| a.get("stopOnEntry")).and_then(Json::as_bool);
+                let path = match path {
+                    Some(path) => path.to_string(),
+                    None => {
+                        connection.fail(&request, "Missing \"program\".");
+                        continue;
+                    }
+                };
+                let source = match fs::read_to_string(&path) {
+                    Ok(source) => source,
+                    Err(e) => {
+                        connection.fail(&request, &format!("Could not read \"{}\": {}", path, e));
+                        continue;
+                    }
+                };
+                match vm.compile(&source) {
+                    Some(function) => {
+                        connection.respond(&request, Json::Null);
+                        connection.event("initialized", Json::Null);
+                        program = Some((path, function, stop_on_entry.unwrap_or(false)));
+                    }
+                    None => connection.fail(&request, &format!("Could not compile \"{}\".", path)),
+                }
+            }
+            "configurationDone" => match program {
+                Some(_) => {
+                    connection.respond(&request, Json::Null);
+                    break;
+                }
+                None => connection.fail(&request, "Nothing has been launched."),
+            },
+            "disconnect" => {
+                connection.respond(&request, Json::Null);
+                return;
+            }
+            _ => {
+                let lines = program.as_ref().map(|(_, function, _)| code_lines(function)).unwrap_or_default();
+                connection.common(&request, &mut breakpoints, &lines);
+            }
+        }
+    }
+
+    let (path, function, stop_on_entry) = program.unwrap();
+    let output = Rc::new(RefCell::new(Vec::new()));
+    vm.set_output(Box::new(Captured(output.clone())));
+    let adapter = Adapter {
+        connection: connection.clone(),
+        output: output.clone(),
+        lines: code_lines(&function),
+        path,
+    };
+    vm.set_debugger(Debugger::new(Box::new(adapter), breakpoints, stop_on_entry));
+    let exit_code = match vm.execute(function) {
+        InterpretResult::Ok => 0,
+        InterpretResult::CompileError => 65,
+        InterpretResult::RuntimeError => 70,
+    };
+
+    let mut connection = connection.borrow_mut();
+    connection.output(&output);
+    connection.event("exited", object(vec![("exitCode", Json::Number(exit_code as f64))]));
+    connection.event("terminated", Json::Null);
+    while let Some(request) = connection.read() {
+        if command(&request) == "disconnect" {
+            connection.respond(&request, Json::Null);
+            break;
+        }
+        connection.fail(&request, "The program has finished.");
+    }
+}
+
+// Answers the requests of a paused program.
+struct Adapter {
+    connection: Rc<RefCell<Connection>>,
+    // what the program printed since it last paused
+    output: Rc<RefCell<Vec<u8>>>,
+    lines: BTreeSet<usize>,
+    path: String,
+}
+
+impl Frontend for Adapter {
+    fn pause(&mut self, vm: &mut VM, breakpoints: &mut Breakpoints, reason: Reason) -> Resume {
+        let mut connection = self.connection.borrow_mut();
+        connection.output(&self.output);
+        let reason = match reason {
+            Reason::Entry => "entry",
+            Reason::Step => "step",
+            Reason::Breakpoint => "breakpoint",
+            Reason::FunctionBreakpoint => "function breakpoint",
+        };
+        connection.event("stopped", object(vec![
+            ("reason", Json::String(reason.to_string())),
+            ("threadId", Json::Number(THREAD as f64)),
+            ("allThreadsStopped", Json::Bool(true)),
+        ]));
+
+        loop {
+            let request = match connection.read() {
+                Some(request) => request,
+                None => return Resume::Stop,
+            };
+            let arguments = request.get("arguments");
+            let argument = |name: &str| arguments.and_then(|a| a.get(name)).and_then(Json::as_f64);
+            let resume = match command(&request) {
+                "continue" => Some(Resume::Continue),
+                "next" => Some(Resume::Next),
+                "stepIn" => Some(Resume::Step),
+                "stepOut" => Some(Resume::Finish),
+                "disconnect" => Some(Resume::Stop),
+                _ => None,
+            };
+            if let Some(resume) = resume {
+                let body = match resume {
+                    Resume::Continue => object(vec![("allThreadsContinued", Json::Bool(true))]),
+                    _ => Json::Null,
+                };
+                connection.respond(&request, body);
+                return resume;
+            }
+
+            match command(&request) {
+                "stackTrace" => {
+                    let backtrace = vm.backtrace();
+                    let start = argument("startFrame").unwrap_or(0.0) as usize;
+                    let levels = match argument("levels") {
+                        Some(levels) if levels > 0.0 => levels as usize,
+                        _ => backtrace.len(),
+                    };
+                    let name = Path::new(&self.path).file_name().unwrap().to_string_lossy().to_string();
+                    let frames = backtrace
+                        .iter()
+                        .enumerate()
+                        .skip(start)
+                        .take(levels)
+                        .map(|(level, (function, line))| object(vec![
+                            ("id", Json::Number(level as f64)),
+                            ("name", Json::String(function.clone())),
+                            ("line", Json::Number(*line as f64)),
+                            ("column", Json::Number(1.0)),
+                            ("source", object(vec![
+                                ("name", Json::String(name.clone())),
+                                ("path", Json::String(self.path.clone())),
+                            ])),
+                        ]))
+                        .collect();
+                    connection.respond(&request, object(vec![
+                        ("stackFrames", Json::Array(frames)),
+                        ("totalFrames", Json::Number(backtrace.len() as f64)),
+                    ]));
+                }
+                "scopes" => {
+                    let level = argument("frameId").unwrap_or(0.0) as usize;
+                    let scope = |name: &str, reference: usize| object(vec![
+                        ("name", Json::String(name.to_string())),
+                        ("variablesReference", Json::Number(reference as f64)),
+                        ("expensive", Json::Bool(false)),
+                    ]);
+                    connection.respond(&request, object(vec![(
+                        "scopes",
+                        Json::Array(vec![scope("Locals", level * 2 + 1), scope("Upvalues", level * 2 + 2)]),
+                    )]));
+                }
+                "variables" => {
+                    // odd references are the locals of a frame, even ones its upvalues
+                    let reference = argument("variablesReference").unwrap_or(0.0) as usize;
+                    let variables = match reference {
+                        0 => Vec::new(),
+                        r if r % 2 == 1 => vm.locals((r - 1) / 2),
+                        r => vm.upvalues((r - 2) / 2),
+                    };
+                    let variables = variables
+                        .into_iter()
+                        .map(|(name, value)| object(vec![
+                            ("name", Json::String(name)),
+                            ("value", Json::String(value.fmt())),
+                            ("variablesReference", Json::Number(0.0)),
+                        ]))
+                        .collect();
+                    connection.respond(&request, object(vec![("variables", Json::Array(variables))]));
+                }
+                "evaluate" => {
+                    let expression = arguments.and_then(|a| a.get("expression")).and_then(Json::as_str).unwrap_or("");
+                    let level = argument("frameId").unwrap_or(0.0) as usize;
+                    match vm.evaluate(expression, level) {
+                        Some(value) => connection.respond(&request, object(vec![
+                            ("result", Json::String(value.fmt())),
+                            ("variablesReference", Json::Number(0.0)),
+                        ])),
+                        None => connection.fail(&request, &format!("Could not evaluate \"{}\".", expression)),
+                    }
+                }
+                _ => connection.common(&request, breakpoints, &self.lines),
+            }
+        }
+    }
+}
+
+const THREAD: usize = 1;
+
+// Requests in, and responses and events out, numbered in the order they are sent.
+struct Connection {
+    input: Box<dyn BufRead>,
+    out: Box<dyn Write>,
+    seq: usize,
+}
+
+impl Connection {
+    fn new() -> Connection {
+        Connection {
+            input: Box::new(io::BufReader::new(io::stdin())),
+            out: Box::new(io::stdout()),
+            seq: 0,
+        }
+    }
+
+    // The next request, or None once the client has gone.
+    fn read(&mut self) -> Option<Json> {
+        protocol::read_message(&mut *self.input)
+    }
+
+    fn send(&mut self, kind: &str, mut members: Vec<(String, Json)>) {
+        self.seq += 1;
+        members.insert(0, (String::from("seq"), Json::Number(self.seq as f64)));
+        members.insert(1, (String::from("type"), Json::String(kind.to_string())));
+        protocol::write_message(&mut *self.out, &Json::Object(members));
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) {
+        let mut members = self.reply(request, true);
+        if body != Json::Null {
+            members.push((String::from("body"), body));
+        }
+        self.send("response", members);
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) {
+        let mut members = self.reply(request, false);
+        members.push((String::from("message"), Json::String(message.to_string())));
+        self.send("response", members);
+    }
+
+    fn reply(&self, request: &Json, success: bool) -> Vec<(String, Json)> {
+        vec![
+            (String::from("request_seq"), request.get("seq").cloned().unwrap_or(Json::Null)),
+            (String::from("success"), Json::Bool(success)),
+            (String::from("command"), Json::String(command(request).to_string())),
+        ]
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        let mut members = vec![(String::from("event"), Json::String(event.to_string()))];
+        if body != Json::Null {
+            members.push((String::from("body"), body));
+        }
+        self.send("event", members);
+    }
+
+    // Sends what the program printed, if anything.
+    fn output(&mut self, output: &RefCell<Vec<u8>>) {
+        let printed = std::mem::take(&mut *output.borrow_mut());
+        if !printed.is_empty() {
+            self.event("output", object(vec![
+                ("category", Json::String(String::from("stdout"))),
+                ("output", Json::String(String::from_utf8_lossy(&printed).to_string())),
+            ]));
+        }
+    }
+
+    // The requests answered the same way whether or not the program is paused.
+    fn common(&mut self, request: &Json, breakpoints: &mut Breakpoints, lines: &BTreeSet<usize>) {
+        let arguments = request.get("arguments");
+        match command(request) {
+            "threads" => {
+                let thread = object(vec![
+                    ("id", Json::Number(THREAD as f64)),
+                    ("name", Json::String(String::from("main"))),
+                ]);
+                self.respond(request, object(vec![("threads", Json::Array(vec![thread]))]));
+            }
+            "setBreakpoints" => {
+                // a breakpoint on a line without code moves down to the next line with some
+                breakpoints.lines.clear();
+                let requested = arguments.and_then(|a| a.get("breakpoints")).and_then(Json::as_array).unwrap_or(&[]);
+                let mut set = Vec::new();
+                for breakpoint in requested {
+                    let line = breakpoint.get("line").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+                    let verified = match lines.range(line..).next() {
+                        Some(&line) => {
+                            breakpoints.lines.insert(line);
+                            object(vec![("verified", Json::Bool(true)), ("line", Json::Number(line as f64))])
+                        }
+                        None => object(vec![("verified", Json::Bool(false)), ("line", Json::Number(line as f64))]),
+                    };
+                    set.push(verified);
+                }
+                self.respond(request, object(vec![("breakpoints", Json::Array(set))]));
+            }
+            "setFunctionBreakpoints" => {
+                breakpoints.functions.clear();
+                let requested = arguments.and_then(|a| a.get("breakpoints")).and_then(Json::as_array).unwrap_or(&[]);
+                let mut set = Vec::new();
+                for breakpoint in requested {
+                    match breakpoint.get("name").and_then(Json::as_str) {
+                        Some(name) => {
+                            breakpoints.functions.insert(name.to_string());
+                            set.push(object(vec![("verified", Json::Bool(true))]));
+                        }
+                        None => set.push(object(vec![("verified", Json::Bool(false))])),
+                    }
+                }
+                self.respond(request, object(vec![("breakpoints", Json::Array(set))]));
+            }
+            command => self.fail(request, &format!("Unsupported request \"{}\".", command)),
+        }
+    }
+}
+
+fn command(request: &Json) -> &str {
+    request.get("command").and_then(Json::as_str).unwrap_or("")
+}
+
+// The lines of the script, and of every function in it, that have code.
+fn code_lines(function: &Function) -> BTreeSet<usize> {
+    let mut lines = function.chunk.lines().iter().cloned().collect::<BTreeSet<_>>();
+    for value in function.chunk.values.iter() {
+        if let Unpacked::Object(ObjType::Function(nested)) = value.unpack() {
+            lines.extend(code_lines(nested));
+        }
+    }
+    lines
+}
+
+// Keeps what the program prints, to send it as `output` events.
+pub struct Captured(pub Rc<RefCell<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
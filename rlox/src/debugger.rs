@@ -0,0 +1,301 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+
+use value::Value;
+use vm::VM;
+
+// How far the program runs before the debugger pauses it again.
+enum Mode {
+    // pause on the first instruction
+    Entry,
+    Continue,
+    // pause on the next line, in whatever frame it is
+    Step,
+    // pause on the next line of this frame or of one of its callers
+    Next(usize),
+    // pause as soon as the frame at this depth returns
+    Finish(usize),
+}
+
+// Why the program paused.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Reason {
+    Entry,
+    Step,
+    Breakpoint,
+    FunctionBreakpoint,
+}
+
+// How a paused program goes on.
+pub enum Resume {
+    Continue,
+    // to the next line, stepping into calls
+    Step,
+    // to the next line, stepping over calls
+    Next,
+    // until the innermost function returns
+    Finish,
+    Stop,
+}
+
+pub struct Breakpoints {
+    pub lines: BTreeSet<usize>,
+    pub functions: BTreeSet<String>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        Breakpoints { lines: BTreeSet::new(), functions: BTreeSet::new() }
+    }
+}
+
+// What drives a paused program: the command line of `rlox debug`, or an editor
+// speaking DAP. It may change the breakpoints before the program goes on.
+pub trait Frontend {
+    fn pause(&mut self, vm: &mut VM, breakpoints: &mut Breakpoints, reason: Reason) -> Resume;
+}
+
+// The VM asks the debugger before every instruction whether to pause, and the
+// frontend takes over until it tells the program to go on.
+pub struct Debugger {
+    mode: Mode,
+    breakpoints: Breakpoints,
+    // the line each frame is on, by depth
+    current: Vec<usize>,
+    frontend: Box<dyn Frontend>,
+}
+
+impl Debugger {
+    pub fn new(frontend: Box<dyn Frontend>, breakpoints: Breakpoints, stop_on_entry: bool) -> Debugger {
+        Debugger {
+            mode: if stop_on_entry { Mode::Entry } else { Mode::Continue },
+            breakpoints,
+            current: Vec::new(),
+            frontend,
+        }
+    }
+
+    // Called before the instruction at `ip` of the innermost frame, which is at
+    // `depth`, runs. Only moving onto another line, or into a function, can pause.
+    pub fn should_pause(&mut self, function: &str, ip: usize, line: usize, depth: usize) -> Option<Reason> {
+        let entering = ip == 0;
+        self.current.resize(depth + 1, 0);
+        let moved = entering || self.current[depth] != line;
+        self.current[depth] = line;
+
+        let stepped = match self.mode {
+            Mode::Entry => return Some(Reason::Entry),
+            Mode::Finish(d) => depth < d,
+            _ if !moved => false,
+            Mode::Continue => false,
+            Mode::Step => true,
+            Mode::Next(d) => depth <= d,
+        };
+        if stepped {
+            Some(Reason::Step)
+        } else if moved && self.breakpoints.lines.contains(&line) {
+            Some(Reason::Breakpoint)
+        } else if entering && self.breakpoints.functions.contains(function) {
+            Some(Reason::FunctionBreakpoint)
+        } else {
+            None
+        }
+    }
+
+    // Hands the paused program to the frontend. Returns false if it should stop.
+    pub fn pause(&mut self, vm: &mut VM, reason: Reason) -> bool {
+        let depth = vm.backtrace().len() - 1;
+        self.mode = match self.frontend.pause(vm, &mut self.breakpoints, reason) {
+            Resume::Continue => Mode::Continue,
+            Resume::Step => Mode::Step,
+            Resume::Next => Mode::Next(depth),
+            Resume::Finish => Mode::Finish(depth),
+            Resume::Stop => return false,
+        };
+        true
+    }
+}
+
+// The command loop of `rlox debug`, reading from stdin.
+pub struct Console {
+    // the frame `print`, `locals` and `upvalues` look at, counted from the innermost
+    selected: usize,
+    source: Vec<String>,
+    // an empty command repeats it
+    last_command: String,
+    input: Box<dyn BufRead>,
+}
+
+const HELP: &str = "\
+Commands:
+  break, b [line|function]   pause at a line, or when a function is called
+  delete, d [line|function]  remove a breakpoint, or all of them
+  breakpoints                list the breakpoints
+  step, s                    run to the next line, stepping into calls
+  next, n                    run to the next line, stepping over calls
+  finish, out                run until the current function returns
+  continue, c                run until a breakpoint
+  backtrace, bt              show the call stack
+  up, down, frame <n>        select a frame of the call stack
+  locals                     show the locals of the selected frame
+  upvalues                   show the variables the selected frame captured
+  print, p <expression>      evaluate an expression in the selected frame
+  list, l                    show the source around the selected frame
+  quit, q                    stop the program
+An empty line repeats the last command.";
+
+impl Console {
+    pub fn new(source: &str) -> Console {
+        Console {
+            selected: 0,
+            source: source.lines().map(String::from).collect(),
+            last_command: String::new(),
+            input: Box::new(io::BufReader::new(io::stdin())),
+        }
+    }
+
+    fn set_breakpoint(&self, vm: &VM, breakpoints: &mut Breakpoints, argument: &str) {
+        if argument.is_empty() {
+            let (_, line) = &vm.backtrace()[self.selected];
+            breakpoints.lines.insert(*line);
+            println!("Breakpoint at line {}.", line);
+        } else if let Ok(line) = argument.parse() {
+            breakpoints.lines.insert(line);
+            println!("Breakpoint at line {}.", line);
+        } else {
+            breakpoints.functions.insert(argument.to_string());
+            println!("Breakpoint at {}().", argument);
+        }
+    }
+
+    fn delete_breakpoint(&self, breakpoints: &mut Breakpoints, argument: &str) {
+        let removed = if argument.is_empty() {
+            breakpoints.lines.clear();
+            breakpoints.functions.clear();
+            true
+        } else if let Ok(line) = argument.parse() {
+            breakpoints.lines.remove(&line)
+        } else {
+            breakpoints.functions.remove(argument)
+        };
+        if !removed {
+            println!("No breakpoint at {}.", argument);
+        }
+    }
+
+    fn select(&mut self, vm: &VM, level: usize) {
+        if level < vm.backtrace().len() {
+            self.selected = level;
+            self.show_frame(vm);
+        } else {
+            println!("No such frame.");
+        }
+    }
+
+    fn show_frame(&self, vm: &VM) {
+        let (function, line) = &vm.backtrace()[self.selected];
+        println!("{} at line {}", describe(function), line);
+        if let Some(text) = self.source.get(line.wrapping_sub(1)) {
+            println!("{:>4}  {}", line, text);
+        }
+    }
+
+    // A few lines either side of the selected frame's line.
+    fn list(&self, vm: &VM) {
+        let (_, line) = vm.backtrace()[self.selected];
+        let first = line.saturating_sub(5).max(1);
+        for number in first..line + 5 {
+            if let Some(text) = self.source.get(number - 1) {
+                let marker = if number == line { '>' } else { ' ' };
+                println!("{}{:>4}  {}", marker, number, text);
+            }
+        }
+    }
+}
+
+impl Frontend for Console {
+    // Reads commands until one of them resumes the program.
+    fn pause(&mut self, vm: &mut VM, breakpoints: &mut Breakpoints, _reason: Reason) -> Resume {
+        self.selected = 0;
+        self.show_frame(vm);
+        loop {
+            print!("(rlox) ");
+            io::stdout().flush().unwrap();
+            let mut line = String::new();
+            if self.input.read_line(&mut line).unwrap_or(0) == 0 {
+                println!();
+                return Resume::Stop;
+            }
+            let line = line.trim();
+            let command = if line.is_empty() { self.last_command.clone() } else { line.to_string() };
+            self.last_command = command.clone();
+
+            let (word, argument) = match command.split_once(' ') {
+                Some((word, argument)) => (word, argument.trim()),
+                None => (command.as_str(), ""),
+            };
+            match word {
+                "" => {}
+                "step" | "s" => return Resume::Step,
+                "next" | "n" => return Resume::Next,
+                "finish" | "out" if vm.backtrace().len() == 1 => println!("The script has no caller to return to."),
+                "finish" | "out" => return Resume::Finish,
+                "continue" | "c" => return Resume::Continue,
+                "quit" | "q" => return Resume::Stop,
+                "break" | "b" => self.set_breakpoint(vm, breakpoints, argument),
+                "delete" | "d" => self.delete_breakpoint(breakpoints, argument),
+                "breakpoints" => {
+                    for line in breakpoints.lines.iter() {
+                        println!("line {}", line);
+                    }
+                    for function in breakpoints.functions.iter() {
+                        println!("{}()", function);
+                    }
+                }
+                "backtrace" | "bt" => {
+                    for (level, (function, line)) in vm.backtrace().iter().enumerate() {
+                        let marker = if level == self.selected { '*' } else { ' ' };
+                        println!("{}#{:<3} {} at line {}", marker, level, describe(function), line);
+                    }
+                }
+                "up" => self.select(vm, self.selected + 1),
+                "down" => self.select(vm, self.selected.wrapping_sub(1)),
+                "frame" => match argument.parse() {
+                    Ok(level) => self.select(vm, level),
+                    Err(_) => println!("Expect a frame number."),
+                },
+                "locals" => show_variables(vm.locals(self.selected)),
+                "upvalues" => show_variables(vm.upvalues(self.selected)),
+                "print" | "p" => {
+                    if argument.is_empty() {
+                        println!("Expect an expression.");
+                    } else if let Some(value) = vm.evaluate(argument, self.selected) {
+                        println!("{}", value.fmt());
+                    }
+                }
+                "list" | "l" => self.list(vm),
+                "help" | "h" => println!("{}", HELP),
+                _ => println!("Unknown command '{}'. Try 'help'.", word),
+            }
+        }
+    }
+}
+
+fn describe(function: &str) -> String {
+    if function == "script" {
+        String::from("script")
+    } else {
+        format!("{}()", function)
+    }
+}
+
+fn show_variables(variables: Vec<(String, Value)>) {
+    if variables.is_empty() {
+        println!("None.");
+    }
+    for (name, value) in variables {
+        println!("{} = {}", name, value.fmt());
+    }
+}
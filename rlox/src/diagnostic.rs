@@ -0,0 +1,158 @@
+use json;
+use scanner::Token;
+use scanner::TokenType;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DiagnosticFormat {
+    // `[line N] Error at 'x': message`, as clox prints it
+    Plain,
+    // source snippet with carets and notes
+    Pretty,
+    // one JSON object per line, for editor integrations
+    Json,
+}
+
+impl DiagnosticFormat {
+    pub fn from_name(name: &str) -> Option<DiagnosticFormat> {
+        match name {
+            "plain" => Some(DiagnosticFormat::Plain),
+            "pretty" => Some(DiagnosticFormat::Pretty),
+            "json" => Some(DiagnosticFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub token: Token,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub token: Token,
+    pub message: String,
+    pub notes: Vec<Note>,
+    // the lint rule that raised a warning; errors have none
+    pub rule: Option<&'static str>,
+}
+
+impl Diagnostic {
+    pub fn new(token: Token, message: &str) -> Diagnostic {
+        Diagnostic {
+            token,
+            message: message.to_string(),
+            notes: Vec::new(),
+            rule: None,
+        }
+    }
+
+    pub fn warning(token: Token, rule: &'static str, message: &str) -> Diagnostic {
+        Diagnostic { rule: Some(rule), ..Diagnostic::new(token, message) }
+    }
+
+    fn severity(&self) -> &'static str {
+        if self.rule.is_some() { "warning" } else { "error" }
+    }
+
+    pub fn with_note(mut self, token: Token, message: &str) -> Diagnostic {
+        self.notes.push(Note { token, message: message.to_string() });
+        self
+    }
+
+    pub fn render(&self, format: DiagnosticFormat, source: &[char]) -> String {
+        match format {
+            DiagnosticFormat::Plain => self.render_plain(),
+            DiagnosticFormat::Pretty => self.render_pretty(source),
+            DiagnosticFormat::Json => self.render_json(source),
+        }
+    }
+
+    fn render_plain(&self) -> String {
+        let location = match self.token.tpe {
+            TokenType::Eof => String::from(" at end"),
+            TokenType::Error => String::new(),
+            _ => format!(" at '{}'", self.token.text),
+        };
+        match self.rule {
+            Some(rule) => format!("[line {}] Warning{}: {} [{}]", self.token.line, location, self.message, rule),
+            None => format!("[line {}] Error{}: {}", self.token.line, location, self.message),
+        }
+    }
+
+    fn render_pretty(&self, source: &[char]) -> String {
+        let width = self.notes.iter()
+            .map(|n| n.token.line)
+            .fold(self.token.line, usize::max)
+            .to_string()
+            .len();
+
+        let mut out = match self.rule {
+            Some(rule) => format!("warning[{}]: {}\n", rule, self.message),
+            None => format!("error: {}\n", self.message),
+        };
+        out.push_str(&snippet(&self.token, source, width));
+        for note in self.notes.iter() {
+            out.push_str(&format!("note: {}\n", note.message));
+            out.push_str(&snippet(&note.token, source, width));
+        }
+        out
+    }
+
+    fn render_json(&self, source: &[char]) -> String {
+        let notes = self.notes.iter()
+            .map(|n| format!("{{\"message\":{},{}}}", json::quote(&n.message), json_span(&n.token, source)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let token = match self.token.tpe {
+            TokenType::Eof | TokenType::Error => String::from("null"),
+            _ => json::quote(&self.token.text),
+        };
+        let rule = match self.rule {
+            Some(rule) => format!(",\"rule\":{}", json::quote(rule)),
+            None => String::new(),
+        };
+        format!(
+            "{{\"severity\":\"{}\"{},\"message\":{},{},\"token\":{},\"notes\":[{}]}}",
+            self.severity(),
+            rule,
+            json::quote(&self.message),
+            json_span(&self.token, source),
+            token,
+            notes
+        )
+    }
+}
+
+// Returns the line and column (1-based) where the token starts, and the text of that line.
+fn locate(token: &Token, source: &[char]) -> (usize, usize, String) {
+    let start = token.start.min(source.len());
+    let line = source[..start].iter().filter(|&&c| c == '\n').count() + 1;
+    let line_start = source[..start].iter()
+        .rposition(|&c| c == '\n')
+        .map_or(0, |i| i + 1);
+    let line_end = source[start..].iter()
+        .position(|&c| c == '\n')
+        .map_or(source.len(), |i| start + i);
+    let text = source[line_start..line_end].iter().collect::<String>();
+    (line, start - line_start + 1, text)
+}
+
+fn snippet(token: &Token, source: &[char], width: usize) -> String {
+    let (line, column, text) = locate(token, source);
+    // a multi-line token is only underlined up to the end of its first line
+    let length = token.length.min(text.chars().count() + 1 - column).max(1);
+
+    let pad = " ".repeat(width);
+    let mut out = format!("{} --> {}:{}\n", pad, line, column);
+    out.push_str(&format!("{} |\n", pad));
+    out.push_str(&format!("{:>w$} | {}\n", line, text.trim_end(), w = width));
+    out.push_str(&format!("{} | {}{}\n", pad, " ".repeat(column - 1), "^".repeat(length)));
+    out
+}
+
+fn json_span(token: &Token, source: &[char]) -> String {
+    let (line, column, _) = locate(token, source);
+    format!("\"line\":{},\"column\":{},\"length\":{}", line, column, token.length)
+}
@@ -0,0 +1,131 @@
+use std::cell::RefCell;
+use std::panic;
+use std::rc::Rc;
+
+use dap::Captured;
+
+use interpreter::Interpreter;
+
+use vm::InterpretResult;
+use vm::VM;
+
+// What a run printed, and how it ended.
+struct Outcome {
+    output: String,
+    errors: String,
+    // the exit code rlox gives it, or 101 if the engine panicked
+    status: i32,
+}
+
+// Runs a script on the VM and on the tree-walking interpreter. If they agree, passes
+// on what they printed and returns the exit code; otherwise returns a report of the
+// first place they differ.
+pub fn run(vm: &mut VM, interpreter: &mut Interpreter, source: &str) -> Result<i32, String> {
+    // the engines share the front end, so only the first one reports compile errors
+    let function = match vm.compile(source) {
+        Some(function) => function,
+        None => return Ok(65),
+    };
+    let program = match interpreter.compile(source) {
+        Some(program) => program,
+        None => return Ok(65),
+    };
+
+    let (output, errors) = (Rc::new(RefCell::new(Vec::new())), Rc::new(RefCell::new(Vec::new())));
+    vm.set_output(Box::new(Captured(output.clone())));
+    vm.set_error_output(Box::new(Captured(errors.clone())));
+    let status = caught(|| vm.execute(function), &errors);
+    let ran_on_vm = outcome(&output, &errors, status);
+
+    let (output, errors) = (Rc::new(RefCell::new(Vec::new())), Rc::new(RefCell::new(Vec::new())));
+    interpreter.set_output(Box::new(Captured(output.clone())));
+    interpreter.set_error_output(Box::new(Captured(errors.clone())));
+    let status = caught(|| interpreter.execute(&program), &errors);
+    let ran_on_tree = outcome(&output, &errors, status);
+
+    match divergence(&ran_on_vm, &ran_on_tree) {
+        Some(report) => Err(report),
+        None => {
+            print!("{}", ran_on_vm.output);
+            eprint!("{}", ran_on_vm.errors);
+            Ok(ran_on_vm.status)
+        }
+    }
+}
+
+// Runs an engine for its exit code, turning a panic into an error it printed.
+fn caught<F: FnOnce() -> InterpretResult>(run: F, errors: &Rc<RefCell<Vec<u8>>>) -> i32 {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(run));
+    panic::set_hook(hook);
+    match result {
+        Ok(InterpretResult::Ok) => 0,
+        Ok(InterpretResult::CompileError) => 65,
+        Ok(InterpretResult::RuntimeError) => 70,
+        Err(payload) => {
+            let message = match payload.downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => payload.downcast_ref::<String>().cloned().unwrap_or_default(),
+            };
+            errors.borrow_mut().extend_from_slice(format!("panicked: {}\n", message).as_bytes());
+            101
+        }
+    }
+}
+
+fn outcome(output: &Rc<RefCell<Vec<u8>>>, errors: &Rc<RefCell<Vec<u8>>>, status: i32) -> Outcome {
+    Outcome {
+        output: String::from_utf8_lossy(&output.borrow()).into_owned(),
+        errors: String::from_utf8_lossy(&errors.borrow()).into_owned(),
+        status,
+    }
+}
+
+// The first line of the output that differs, else the first line of the errors, else
+// the exit codes.
+fn divergence(vm: &Outcome, tree: &Outcome) -> Option<String> {
+    let mut vm_lines = vm.output.lines();
+    let mut tree_lines = tree.output.lines();
+    for line in 1.. {
+        match (vm_lines.next(), tree_lines.next()) {
+            (None, None) => break,
+            (a, b) if a == b => {}
+            (a, b) => {
+                return Some(report(&format!("line {} of the output", line), &ended(a, vm), &ended(b, tree)));
+            }
+        }
+    }
+
+    let mut vm_lines = vm.errors.lines();
+    let mut tree_lines = tree.errors.lines();
+    for line in 1.. {
+        match (vm_lines.next(), tree_lines.next()) {
+            (None, None) => break,
+            (a, b) if a == b => {}
+            (a, b) => {
+                let a = a.unwrap_or("(no more errors)");
+                let b = b.unwrap_or("(no more errors)");
+                return Some(report(&format!("line {} of the errors", line), a, b));
+            }
+        }
+    }
+
+    if vm.status != tree.status {
+        return Some(report("exit code", &vm.status.to_string(), &tree.status.to_string()));
+    }
+    None
+}
+
+// A line of output, or why there is none: the error the run stopped with.
+fn ended(line: Option<&str>, outcome: &Outcome) -> String {
+    match (line, outcome.errors.lines().next()) {
+        (Some(line), _) => line.to_string(),
+        (None, Some(error)) => format!("(stopped: {})", error),
+        (None, None) => "(no more output)".to_string(),
+    }
+}
+
+fn report(place: &str, vm: &str, tree: &str) -> String {
+    format!("The engines differ at {}:\n  vm:   {}\n  tree: {}", place, vm, tree)
+}
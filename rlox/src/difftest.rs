@@ -0,0 +1,233 @@
+use std::env;
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use format;
+
+// How `rlox difftest` runs the two implementations.
+pub struct Options {
+    // the clox binary, which `make` builds in `clox/`
+    pub clox: String,
+    // what rlox is run with, such as `-O1` or `--engine=tree`
+    pub rlox: Vec<String>,
+    // how long either may take on a file, in seconds
+    pub timeout: u64,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            clox: String::from("clox/a.out"),
+            rlox: Vec::new(),
+            timeout: 10,
+        }
+    }
+}
+
+// What a run printed and how it ended, normalized for comparing: line endings and
+// trailing spaces are dropped, and the exit code becomes what it stands for.
+struct Run {
+    status: String,
+    output: Vec<String>,
+    errors: Vec<String>,
+}
+
+impl Run {
+    // Whether it ended on its own terms, so that what it printed to stderr means
+    // something: a panic's message names source lines and threads.
+    fn finished(&self) -> bool {
+        self.status == "ok" || self.status.ends_with("error")
+    }
+}
+
+// Runs every `.lox` file under the paths on clox and on rlox, prints a table of the
+// ones where they differ and returns whether there were any.
+pub fn run(paths: &[&str], options: &Options) -> Result<bool, String> {
+    let mut files = Vec::new();
+    for path in paths {
+        format::lox_files(Path::new(path), &mut files)?;
+    }
+    if files.is_empty() {
+        return Err(String::from("No Lox files found."));
+    }
+    let rlox = env::current_exe().map_err(|e| format!("Could not find rlox: {}", e))?;
+    let rlox = rlox.to_string_lossy().to_string();
+
+    let mut mismatches = Vec::new();
+    for file in &files {
+        let expected = execute(&options.clox, &[], file, options.timeout)
+            .map_err(|e| format!("{} Build clox with make in clox/, or name it with --clox.", e))?;
+        let actual = execute(&rlox, &options.rlox, file, options.timeout)?;
+        if let Some(difference) = difference(&expected, &actual) {
+            mismatches.push((file, expected.status, actual.status, difference));
+        }
+    }
+
+    if !mismatches.is_empty() {
+        let width = mismatches.iter().map(|(file, ..)| file.len()).max().unwrap().max(4);
+        println!("{:<width$}  {:<13}  {:<13}  first difference", "file", "clox", "rlox", width = width);
+        for (file, expected, actual, difference) in &mismatches {
+            println!("{:<width$}  {:<13}  {:<13}  {}", file, expected, actual, difference, width = width);
+        }
+    }
+    println!("{} files, {} differ", files.len(), mismatches.len());
+    Ok(!mismatches.is_empty())
+}
+
+// Runs a program on a file, killing it if it takes longer than the timeout.
+fn execute(program: &str, options: &[String], file: &str, timeout: u64) -> Result<Run, String> {
+    let mut child = Command::new(program)
+        .args(options)
+        .arg(file)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Could not run \"{}\": {}.", program, e))?;
+    // read both as they come, so that neither pipe fills up and blocks the program
+    let output = read_all(child.stdout.take().unwrap());
+    let errors = read_all(child.stderr.take().unwrap());
+
+    let start = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break describe(status),
+            Ok(None) if start.elapsed() < Duration::from_secs(timeout) => thread::sleep(Duration::from_millis(5)),
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break String::from("timeout");
+            }
+        }
+    };
+    Ok(Run {
+        status,
+        output: normalize(&output.join().unwrap()),
+        errors: normalize(&errors.join().unwrap()),
+    })
+}
+
+fn read_all<R: Read + Send + 'static>(mut reader: R) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let _ = reader.read_to_end(&mut bytes);
+        bytes
+    })
+}
+
+// The exit codes both implementations share, by what they mean.
+fn describe(status: ExitStatus) -> String {
+    match status.code() {
+        Some(0) => String::from("ok"),
+        Some(64) => String::from("usage error"),
+        Some(65) => String::from("compile error"),
+        Some(70) => String::from("runtime error"),
+        Some(74) => String::from("io error"),
+        Some(101) => String::from("panic"),
+        Some(code) => format!("exit {}", code),
+        None => String::from("killed"),
+    }
+}
+
+fn normalize(bytes: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(bytes).lines().map(|line| line.trim_end().to_string()).collect()
+}
+
+// The first line of output that differs, else the first line of errors; `None` if
+// the runs agree. Runs that ended differently may have nothing else to tell apart.
+fn difference(expected: &Run, actual: &Run) -> Option<String> {
+    if let Some(line) = first_difference(&expected.output, &actual.output) {
+        return Some(line_difference("output", line, &expected.output, &actual.output));
+    }
+    if expected.finished() && actual.finished() {
+        if let Some(line) = first_difference(&expected.errors, &actual.errors) {
+            return Some(line_difference("errors", line, &expected.errors, &actual.errors));
+        }
+    }
+    if expected.status != actual.status {
+        return Some(String::from("exit code"));
+    }
+    None
+}
+
+fn first_difference(expected: &[String], actual: &[String]) -> Option<usize> {
+    (0..expected.len().max(actual.len())).find(|&i| expected.get(i) != actual.get(i))
+}
+
+fn line_difference(stream: &str, index: usize, expected: &[String], actual: &[String]) -> String {
+    let line = |lines: &[String]| match lines.get(index) {
+        Some(line) => format!("{:?}", line),
+        None => String::from("nothing"),
+    };
+    format!("{} line {}: {} vs {}", stream, index + 1, line(expected), line(actual))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(status: &str, output: &[&str], errors: &[&str]) -> Run {
+        Run {
+            status: status.to_string(),
+            output: output.iter().map(|line| line.to_string()).collect(),
+            errors: errors.iter().map(|line| line.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn normalizes_line_endings_and_trailing_spaces() {
+        assert_eq!(normalize(b"a  \r\nb\t\n\nc"), vec!["a", "b", "", "c"]);
+        assert_eq!(normalize(b""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn describes_exit_codes_by_what_they_mean() {
+        use std::os::unix::process::ExitStatusExt;
+        let exit = |code: i32| describe(ExitStatus::from_raw(code << 8));
+        assert_eq!(exit(0), "ok");
+        assert_eq!(exit(65), "compile error");
+        assert_eq!(exit(70), "runtime error");
+        assert_eq!(exit(101), "panic");
+        assert_eq!(exit(3), "exit 3");
+        // killed by SIGKILL
+        assert_eq!(describe(ExitStatus::from_raw(9)), "killed");
+    }
+
+    #[test]
+    fn finds_the_first_line_that_differs() {
+        let lines = |lines: &[&str]| lines.iter().map(|line| line.to_string()).collect::<Vec<_>>();
+        assert_eq!(first_difference(&lines(&["1", "2"]), &lines(&["1", "2"])), None);
+        assert_eq!(first_difference(&lines(&["1", "2"]), &lines(&["1", "3"])), Some(1));
+        assert_eq!(first_difference(&lines(&["1"]), &lines(&["1", "2"])), Some(1));
+    }
+
+    #[test]
+    fn reports_output_then_errors_then_status() {
+        let clox = run("runtime error", &["1"], &["Oops.", "[line 2] in script"]);
+        assert_eq!(difference(&clox, &run("runtime error", &["1"], &["Oops.", "[line 2] in script"])), None);
+        assert_eq!(
+            difference(&clox, &run("ok", &["1", "2"], &[])),
+            Some(String::from("output line 2: nothing vs \"2\""))
+        );
+        assert_eq!(
+            difference(&clox, &run("runtime error", &["1"], &["Oops.", "[line 3] in script"])),
+            Some(String::from("errors line 2: \"[line 2] in script\" vs \"[line 3] in script\""))
+        );
+        let rlox = run("compile error", &["1"], &["Oops.", "[line 2] in script"]);
+        assert_eq!(difference(&clox, &rlox), Some(String::from("exit code")));
+    }
+
+    // a panic's message says nothing about the program, so only its status is compared
+    #[test]
+    fn ignores_the_errors_of_a_run_that_did_not_finish() {
+        let clox = run("runtime error", &[], &["Oops.", "[line 2] in script"]);
+        let rlox = run("panic", &[], &["thread 'main' panicked at src/vm.rs:10:5:"]);
+        assert_eq!(difference(&clox, &rlox), Some(String::from("exit code")));
+    }
+}
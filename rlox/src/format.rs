@@ -0,0 +1,391 @@
+use std::fs;
+use std::path::Path;
+
+use compiler::Compiler;
+use diagnostic::Diagnostic;
+use diagnostic::DiagnosticFormat;
+use limits::Limits;
+use memory::Memory;
+use object::Function;
+use object::ObjType;
+use parser::Parser;
+use scanner::Scanner;
+use scanner::TokenType;
+use scanner::Trivia;
+use value::Unpacked;
+
+const INDENT: &str = "  ";
+
+// Lays out the source the one way `rlox fmt` accepts, keeping its comments. Fails
+// only if the source has a token the scanner rejects.
+pub fn format(source: &str) -> Result<String, Diagnostic> {
+    let mut scanner = Scanner::with_trivia(source.to_string());
+    let mut printer = Printer::new();
+    loop {
+        let token = scanner.scan();
+        if token.tpe == TokenType::Error {
+            return Err(Diagnostic::new(token.clone(), &token.text));
+        }
+        printer.trivia(&scanner.take_trivia());
+        if token.tpe == TokenType::Eof {
+            return Ok(printer.finish());
+        }
+        printer.token(&token.tpe, &token.text);
+    }
+}
+
+// Writes tokens out one at a time, deciding the spaces and line breaks before each.
+struct Printer {
+    out: String,
+    indent: usize,
+    // something is written on the current line
+    open: bool,
+    // the next token starts a line of its own
+    break_line: bool,
+    // the source had a blank line before the next token
+    blank: bool,
+    previous: TokenType,
+    // the previous token was a prefix `-` or `!`
+    unary: bool,
+    // for each open paren, whether it holds the clauses of a `for`
+    parens: Vec<bool>,
+}
+
+impl Printer {
+    fn new() -> Printer {
+        Printer {
+            out: String::new(),
+            indent: 0,
+            open: false,
+            break_line: false,
+            blank: false,
+            previous: TokenType::Start,
+            unary: false,
+            parens: Vec::new(),
+        }
+    }
+
+    fn trivia(&mut self, trivia: &[Trivia]) {
+        let mut newlines = 0;
+        for item in trivia {
+            match item {
+                Trivia::Newline => newlines += 1,
+                Trivia::Comment { text, .. } => {
+                    if self.open && newlines == 0 {
+                        // a comment at the end of a line stays there
+                        self.out.push(' ');
+                    } else {
+                        let continuation = self.continues();
+                        self.end_line();
+                        if newlines > 1 {
+                            self.blank_line();
+                        }
+                        self.start_line(continuation);
+                    }
+                    self.out.push_str(text);
+                    self.open = true;
+                    self.end_line();
+                    newlines = 0;
+                }
+            }
+        }
+        self.blank = newlines > 1;
+    }
+
+    fn token(&mut self, tpe: &TokenType, text: &str) {
+        match tpe {
+            // `{}` stays on one line
+            TokenType::RightBrace if self.previous == TokenType::LeftBrace && self.open => {
+                self.indent = self.indent.saturating_sub(1);
+            }
+            TokenType::RightBrace => {
+                self.indent = self.indent.saturating_sub(1);
+                self.end_line();
+                self.start_line(false);
+            }
+            TokenType::Else if self.previous == TokenType::RightBrace && self.open => {
+                self.out.push(' ')
+            }
+            _ if self.break_line || !self.open => {
+                let continuation = self.continues();
+                self.end_line();
+                if self.blank {
+                    self.blank_line();
+                }
+                self.start_line(continuation);
+            }
+            _ if self.space_before(tpe) => self.out.push(' '),
+            _ => {}
+        }
+        self.out.push_str(text);
+        self.open = true;
+        self.blank = false;
+
+        self.unary =
+            *tpe == TokenType::Bang || (*tpe == TokenType::Minus && !ends_operand(&self.previous));
+        self.break_line = match tpe {
+            TokenType::LeftParen => {
+                self.parens.push(self.previous == TokenType::For);
+                false
+            }
+            TokenType::RightParen => {
+                self.parens.pop();
+                false
+            }
+            TokenType::LeftBrace => {
+                self.indent += 1;
+                true
+            }
+            TokenType::RightBrace => true,
+            // the clauses of a `for` share its line
+            TokenType::Semicolon => !self.parens.last().cloned().unwrap_or(false),
+            _ => false,
+        };
+        self.previous = tpe.clone();
+    }
+
+    fn finish(mut self) -> String {
+        self.end_line();
+        self.out
+    }
+
+    // Whether a new line would be in the middle of a statement.
+    fn continues(&self) -> bool {
+        self.previous != TokenType::Start && !self.break_line
+    }
+
+    fn space_before(&self, tpe: &TokenType) -> bool {
+        if self.unary {
+            return false;
+        }
+        match tpe {
+            TokenType::Semicolon | TokenType::Comma | TokenType::RightParen | TokenType::Dot => {
+                false
+            }
+            // a call, or the parameters of a function
+            TokenType::LeftParen => {
+                self.previous != TokenType::Identifier && self.previous != TokenType::RightParen
+            }
+            _ => self.previous != TokenType::LeftParen && self.previous != TokenType::Dot,
+        }
+    }
+
+    fn end_line(&mut self) {
+        if self.open {
+            self.out.push('\n');
+            self.open = false;
+        }
+    }
+
+    // Keeps one blank line from the source, except at the start of the file or of a block.
+    fn blank_line(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with("{\n") {
+            self.out.push('\n');
+        }
+    }
+
+    fn start_line(&mut self, continuation: bool) {
+        for _ in 0..self.indent + continuation as usize {
+            self.out.push_str(INDENT);
+        }
+    }
+}
+
+// Whether an operator after this token is binary rather than prefix.
+fn ends_operand(tpe: &TokenType) -> bool {
+    matches!(
+        tpe,
+        TokenType::Identifier
+            | TokenType::String
+            | TokenType::Number
+            | TokenType::RightParen
+            | TokenType::True
+            | TokenType::False
+            | TokenType::Nil
+            | TokenType::This
+    )
+}
+
+// Checks that the formatted source means what the original did: it has the same
+// tokens, compiles to the same code, and is left alone by formatting it again.
+pub fn verify(source: &str, formatted: &str) -> Result<(), String> {
+    if tokens(source) != tokens(formatted) {
+        return Err(String::from("the tokens changed"));
+    }
+    match (compile(source), compile(formatted)) {
+        (Ok((a, a_globals)), Ok((b, b_globals))) if a_globals == b_globals && same_code(&a, &b) => {
+        }
+        (Err(a), Err(b)) if a == b => {}
+        _ => return Err(String::from("the compiled code changed")),
+    }
+    match format(formatted) {
+        Ok(again) if again == formatted => Ok(()),
+        _ => Err(String::from("formatting it again changes it")),
+    }
+}
+
+fn tokens(source: &str) -> Vec<(TokenType, String)> {
+    let mut scanner = Scanner::new(source.to_string());
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.scan();
+        if token.tpe == TokenType::Eof {
+            return tokens;
+        }
+        tokens.push((token.tpe, token.text));
+    }
+}
+
+// The script with its global names, or the messages of its compile errors.
+fn compile(source: &str) -> Result<(Function, Vec<String>), Vec<String>> {
+    let mut parser = Parser::new(source.to_string(), Limits::new(), Memory::new());
+    let function = Compiler::new(&mut parser).compile();
+    match function {
+        Some(function) => Ok((function, parser.take_memory().globals.names().to_vec())),
+        None => Err(parser
+            .diagnostics()
+            .iter()
+            .map(|d| d.message.clone())
+            .collect()),
+    }
+}
+
+// Whether two functions have the same code, whatever lines it came from.
+fn same_code(a: &Function, b: &Function) -> bool {
+    a.arity == b.arity
+        && a.name == b.name
+        && a.chunk.code == b.chunk.code
+        && a.chunk.values.len() == b.chunk.values.len()
+        && a.chunk
+            .values
+            .iter()
+            .zip(b.chunk.values.iter())
+            .all(|(x, y)| match (x.unpack(), y.unpack()) {
+                (
+                    Unpacked::Object(ObjType::Function(f)),
+                    Unpacked::Object(ObjType::Function(g)),
+                ) => same_code(f, g),
+                _ => x.fmt() == y.fmt(),
+            })
+}
+
+// `rlox fmt`: formats every file in place, or with `check` only lists the ones
+// that are not formatted. Returns whether any file was unformatted or could not
+// be formatted.
+pub fn run(paths: &[&str], check: bool, diagnostics: DiagnosticFormat) -> Result<bool, String> {
+    let mut files = Vec::new();
+    for path in paths {
+        lox_files(Path::new(path), &mut files)?;
+    }
+    if files.is_empty() {
+        return Err(String::from("No Lox files found."));
+    }
+
+    let mut failed = false;
+    for file in files {
+        let source =
+            fs::read_to_string(&file).map_err(|e| format!("Could not read \"{}\": {}", file, e))?;
+        let formatted = match format(&source) {
+            Ok(formatted) => formatted,
+            Err(diagnostic) => {
+                let chars = source.chars().collect::<Vec<_>>();
+                eprintln!(
+                    "Could not format \"{}\":\n{}",
+                    file,
+                    diagnostic.render(diagnostics, &chars)
+                );
+                failed = true;
+                continue;
+            }
+        };
+        if let Err(e) = verify(&source, &formatted) {
+            eprintln!("Could not format \"{}\": {}.", file, e);
+            failed = true;
+        } else if formatted != source {
+            if check {
+                println!("{}", file);
+                failed = true;
+            } else {
+                fs::write(&file, formatted)
+                    .map_err(|e| format!("Could not write \"{}\": {}", file, e))?;
+            }
+        }
+    }
+    Ok(failed)
+}
+
+// The `.lox` files at a path, looking through directories and their subdirectories.
+pub fn lox_files(path: &Path, files: &mut Vec<String>) -> Result<(), String> {
+    if !path.is_dir() {
+        files.push(path.to_string_lossy().to_string());
+        return Ok(());
+    }
+    let entries =
+        fs::read_dir(path).map_err(|e| format!("Could not read \"{}\": {}", path.display(), e))?;
+    let mut found = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect::<Vec<_>>();
+    found.sort();
+    for entry in found {
+        if entry.is_dir() || entry.extension().is_some_and(|ext| ext == "lox") {
+            lox_files(&entry, files)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn formatted(source: &str) -> String {
+        let formatted = format(source).unwrap();
+        assert_eq!(verify(source, &formatted), Ok(()));
+        formatted
+    }
+
+    #[test]
+    fn lays_out_statements_and_blocks() {
+        assert_eq!(
+            formatted("var a=1;fun f(x){if(x>1){return x;}else print -x;}\nprint f(a)+2;"),
+            "var a = 1;\nfun f(x) {\n  if (x > 1) {\n    return x;\n  } else print -x;\n}\nprint f(a) + 2;\n"
+        );
+    }
+
+    #[test]
+    fn keeps_comments_where_they_were() {
+        let source = "// leading\nvar a=1;   // trailing\n{\n// wrong depth\n  print a;\n  // last in block\n}\n\
+                      // at the end\n";
+        assert_eq!(
+            formatted(source),
+            "// leading\nvar a = 1; // trailing\n{\n  // wrong depth\n  print a;\n  // last in block\n}\n\
+             // at the end\n"
+        );
+    }
+
+    #[test]
+    fn keeps_a_comment_after_an_opening_brace_on_its_line() {
+        assert_eq!(
+            formatted("if (true) {print 1;} else {   // after else\nprint 2;}\n"),
+            "if (true) {\n  print 1;\n} else { // after else\n  print 2;\n}\n"
+        );
+    }
+
+    #[test]
+    fn collapses_blank_lines() {
+        assert_eq!(formatted("print 1;\n\n\n\nprint 2;\n"), "print 1;\n\nprint 2;\n");
+        assert_eq!(formatted("\n\nprint 1;\n\n"), "print 1;\n");
+    }
+
+    #[test]
+    fn fails_on_a_token_the_scanner_rejects() {
+        let diagnostic = format("print \"open;").unwrap_err();
+        assert_eq!(diagnostic.message, "Unterminated string.");
+    }
+
+    #[test]
+    fn notices_a_change_in_meaning() {
+        assert_eq!(verify("print 1;", "print 2;"), Err(String::from("the tokens changed")));
+        assert_eq!(verify("print 1;", "print 1;"), Err(String::from("formatting it again changes it")));
+    }
+}
@@ -0,0 +1,473 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::io;
+use std::io::Write;
+use std::rc::Rc;
+
+use ast::Decl;
+use ast::DeclKind;
+use ast::Expr;
+use ast::ExprKind;
+use ast::Function;
+use ast::Program;
+use ast::Stmt;
+use ast::StmtKind;
+
+use compiler::Compiler;
+
+use diagnostic::DiagnosticFormat;
+
+use limits::Limits;
+
+use memory::Memory;
+
+use object::ObjType;
+use object::TreeFunction;
+
+use parser::Parser;
+
+use scanner::Token;
+use scanner::TokenType;
+
+use value::Unpacked;
+use value::Value;
+
+use vm;
+use vm::InterpretResult;
+
+// The local variables in scope, innermost first. A declaration puts its variable in
+// front of the ones it can see, so a function keeps exactly those that were in scope
+// where it was declared, and shares them with the code around it.
+#[derive(Clone)]
+pub struct Environment(Option<Rc<Binding>>);
+
+struct Binding {
+    name: String,
+    value: RefCell<Value>,
+    next: Environment,
+}
+
+impl Environment {
+    fn new() -> Environment {
+        Environment(None)
+    }
+
+    fn define(&self, name: &str, value: Value) -> Environment {
+        Environment(Some(Rc::new(Binding {
+            name: name.to_string(),
+            value: RefCell::new(value),
+            next: self.clone(),
+        })))
+    }
+
+    fn lookup(&self, name: &str) -> Option<&RefCell<Value>> {
+        let mut environment = self;
+        while let Some(binding) = &environment.0 {
+            if binding.name == name {
+                return Some(&binding.value);
+            }
+            environment = &binding.next;
+        }
+        None
+    }
+}
+
+// A call in progress, for stack traces.
+struct Frame {
+    // `None` for the script
+    name: Option<String>,
+    // where the frame is, or the call it is waiting in
+    line: usize,
+}
+
+// Why a statement stopped before its end.
+enum Unwind {
+    Return(Value),
+    // the error has been reported
+    Error,
+}
+
+type Flow<T> = Result<T, Unwind>;
+
+// Runs programs straight from their syntax tree, as a second opinion on the VM. It
+// shares the front end, the values and the natives with it, and reports the same
+// runtime errors, but keeps variables where a closure can share them instead of on a
+// stack. The limit on the size of the stack has no counterpart here; calls nest on the
+// native stack instead, so they also stop with a stack overflow before it runs out.
+pub struct Interpreter {
+    memory: Memory,
+    diagnostics: DiagnosticFormat,
+    limits: Limits,
+    // where `print` writes
+    out: Box<dyn Write>,
+    // where runtime errors are reported
+    errors: Box<dyn Write>,
+    frames: Vec<Frame>,
+    environment: Environment,
+    // blocks and calls the running code is nested in; declarations at zero are global
+    depth: usize,
+    // the address of the native stack where the program started
+    stack_base: usize,
+}
+
+// The native stack a program may need, which the thread running the interpreter has to
+// have. A quarter of it is left for what the deepest call does before calling again.
+pub const STACK_SIZE: usize = 256 << 20;
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        let mut interpreter = Interpreter {
+            memory: Memory::new(),
+            diagnostics: DiagnosticFormat::Plain,
+            limits: Limits::new(),
+            out: Box::new(io::stdout()),
+            errors: Box::new(io::stderr()),
+            frames: Vec::new(),
+            environment: Environment::new(),
+            depth: 0,
+            stack_base: 0,
+        };
+        for native in vm::natives() {
+            let slot = interpreter.memory.globals.resolve(&native.name);
+            interpreter.memory.globals.define(slot, Value::object(ObjType::NativeFn(native)));
+        }
+        interpreter
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn set_diagnostics(&mut self, format: DiagnosticFormat) {
+        self.diagnostics = format;
+    }
+
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    pub fn set_error_output(&mut self, errors: Box<dyn Write>) {
+        self.errors = errors;
+    }
+
+    // Parses and checks the source, printing diagnostics if it fails.
+    pub fn compile(&mut self, source: &str) -> Option<Program> {
+        let memory = std::mem::replace(&mut self.memory, Memory::new());
+        let parser = &mut Parser::new(source.to_string(), self.limits, memory);
+        let mut compiler = Compiler::new(parser);
+        let result = compiler.compile_tree();
+        if result.is_none() {
+            let source = source.chars().collect::<Vec<_>>();
+            for diagnostic in compiler.diagnostics() {
+                eprintln!("{}", diagnostic.render(self.diagnostics, &source));
+            }
+        }
+        self.memory = parser.take_memory();
+        result
+    }
+
+    pub fn execute(&mut self, program: &Program) -> InterpretResult {
+        let base = 0u8;
+        self.stack_base = &base as *const u8 as usize;
+        self.frames.push(Frame { name: None, line: 0 });
+        let result = self.declarations(&program.declarations);
+        self.frames.clear();
+        self.environment = Environment::new();
+        self.depth = 0;
+        match result {
+            Ok(()) => InterpretResult::Ok,
+            Err(_) => InterpretResult::RuntimeError,
+        }
+    }
+
+    fn declarations(&mut self, declarations: &[Decl]) -> Flow<()> {
+        for decl in declarations {
+            self.declaration(decl)?;
+        }
+        Ok(())
+    }
+
+    fn declaration(&mut self, decl: &Decl) -> Flow<()> {
+        match &decl.kind {
+            DeclKind::Var { name, initializer } => {
+                let value = match initializer {
+                    Some(initializer) => self.expression(initializer)?,
+                    None => Value::nil(),
+                };
+                self.define(name, value);
+            }
+            DeclKind::Fun(function) if self.depth == 0 => {
+                let value = self.closure(function);
+                self.define(&function.name, value);
+            }
+            DeclKind::Fun(function) => {
+                // a local function is in scope in its own body, so that it can call itself
+                self.define(&function.name, Value::nil());
+                let value = self.closure(function);
+                *self.environment.lookup(&function.name.text).unwrap().borrow_mut() = value;
+            }
+            DeclKind::Statement(stmt) => self.statement(stmt)?,
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> Flow<()> {
+        match &stmt.kind {
+            StmtKind::Expression(expr) => {
+                self.expression(expr)?;
+            }
+            StmtKind::Print(expr) => {
+                let value = self.expression(expr)?;
+                writeln!(self.out, "{}", value.fmt()).unwrap();
+            }
+            StmtKind::Block(declarations) => {
+                self.depth += 1;
+                let enclosing = self.environment.clone();
+                let result = self.declarations(declarations);
+                self.environment = enclosing;
+                self.depth -= 1;
+                result?;
+            }
+            StmtKind::If { condition, then, otherwise, .. } => {
+                if !is_falsey(&self.expression(condition)?) {
+                    self.statement(then)?;
+                } else if let Some(otherwise) = otherwise {
+                    self.statement(otherwise)?;
+                }
+            }
+            StmtKind::While { condition, body, .. } => {
+                while !is_falsey(&self.expression(condition)?) {
+                    self.statement(body)?;
+                }
+            }
+            StmtKind::For { initializer, condition, increment, body, .. } => {
+                // the initializer's variable is in a scope of its own, and the
+                // iterations share it
+                self.depth += 1;
+                let enclosing = self.environment.clone();
+                let result = self.for_loop(initializer.as_deref(), condition.as_deref(), increment.as_deref(), body);
+                self.environment = enclosing;
+                self.depth -= 1;
+                result?;
+            }
+            StmtKind::Return(value) => {
+                let value = match value {
+                    Some(value) => self.expression(value)?,
+                    None => Value::nil(),
+                };
+                return Err(Unwind::Return(value));
+            }
+        }
+        Ok(())
+    }
+
+    fn for_loop(&mut self, initializer: Option<&Decl>, condition: Option<&Expr>, increment: Option<&Expr>, body: &Stmt) -> Flow<()> {
+        if let Some(initializer) = initializer {
+            self.declaration(initializer)?;
+        }
+        loop {
+            if let Some(condition) = condition {
+                if is_falsey(&self.expression(condition)?) {
+                    return Ok(());
+                }
+            }
+            self.statement(body)?;
+            if let Some(increment) = increment {
+                self.expression(increment)?;
+            }
+        }
+    }
+
+    // Errors are reported on the lines the VM has them on.
+    fn expression(&mut self, expr: &Expr) -> Flow<Value> {
+        let line = expr.span.last.line;
+        let value = match &expr.kind {
+            ExprKind::Number(n) => Value::number(*n),
+            ExprKind::String(chars) => Value::object(ObjType::String(self.memory.strings.intern(chars.clone()))),
+            ExprKind::True => Value::boolean(true),
+            ExprKind::False => Value::boolean(false),
+            ExprKind::Nil => Value::nil(),
+            ExprKind::Variable(name) => self.variable(name, line)?,
+            ExprKind::Assign { name, value } => {
+                let value = self.expression(value)?;
+                self.assign(name, value.clone(), line)?;
+                value
+            }
+            ExprKind::Unary { operator, operand } => {
+                let value = self.expression(operand)?;
+                match operator.tpe {
+                    TokenType::Bang => Value::boolean(is_falsey(&value)),
+                    _ => match value.as_number() {
+                        Some(n) => Value::number(-n),
+                        None => return Err(self.error("Operand must be a number.", line)),
+                    },
+                }
+            }
+            ExprKind::Binary { operator, left, right } => {
+                let a = self.expression(left)?;
+                let b = self.expression(right)?;
+                self.binary(operator, a, b)?
+            }
+            ExprKind::Logical { operator, left, right } => {
+                let left = self.expression(left)?;
+                // `and` stops at a falsey operand, `or` at a truthy one
+                if is_falsey(&left) == (operator.tpe == TokenType::And) {
+                    left
+                } else {
+                    self.expression(right)?
+                }
+            }
+            ExprKind::Grouping(inner) => self.expression(inner)?,
+            ExprKind::Call { callee, arguments } => {
+                let callee = self.expression(callee)?;
+                let mut values = Vec::new();
+                for argument in arguments {
+                    values.push(self.expression(argument)?);
+                }
+                self.call(callee, values, line)?
+            }
+            // programs with syntax errors are not run
+            ExprKind::Invalid => unreachable!(),
+        };
+        Ok(value)
+    }
+
+    fn binary(&mut self, operator: &Token, a: Value, b: Value) -> Flow<Value> {
+        let line = operator.line;
+        let value = match operator.tpe {
+            TokenType::EqualEqual => Value::boolean(a == b),
+            TokenType::BangEqual => Value::boolean(a != b),
+            TokenType::Plus => match (a.unpack(), b.unpack()) {
+                (Unpacked::Number(a), Unpacked::Number(b)) => Value::number(a + b),
+                (Unpacked::Object(ObjType::String(a)), Unpacked::Object(ObjType::String(b))) => {
+                    Value::object(ObjType::String(self.memory.strings.intern(format!("{}{}", a, b))))
+                }
+                _ => return Err(self.error("Operands must be two numbers or two strings.", line)),
+            },
+            _ => {
+                let (a, b) = match (a.as_number(), b.as_number()) {
+                    (Some(a), Some(b)) => (a, b),
+                    _ => return Err(self.error("Operands must be numbers.", line)),
+                };
+                match operator.tpe {
+                    TokenType::Greater => Value::boolean(a > b),
+                    TokenType::Less => Value::boolean(a < b),
+                    // as in the VM, `>=` is not `<` and `<=` is not `>`, which holds for NaN
+                    TokenType::GreaterEqual => Value::boolean(a.partial_cmp(&b) != Some(Ordering::Less)),
+                    TokenType::LessEqual => Value::boolean(a.partial_cmp(&b) != Some(Ordering::Greater)),
+                    TokenType::Minus => Value::number(a - b),
+                    TokenType::Star => Value::number(a * b),
+                    _ => Value::number(a / b),
+                }
+            }
+        };
+        Ok(value)
+    }
+
+    fn call(&mut self, callee: Value, arguments: Vec<Value>, line: usize) -> Flow<Value> {
+        match callee.as_object() {
+            Some(ObjType::TreeFunction(function)) => {
+                let declaration = &function.declaration;
+                if arguments.len() != declaration.params.len() {
+                    let message = format!("Expected {} arguments but got {}.", declaration.params.len(), arguments.len());
+                    return Err(self.error(&message, line));
+                }
+                if self.frames.len() >= self.limits.frames || self.stack_used() > STACK_SIZE / 4 * 3 {
+                    return Err(self.error("Stack overflow.", line));
+                }
+                self.frames.last_mut().unwrap().line = line;
+                self.frames.push(Frame { name: Some(declaration.name.text.clone()), line });
+
+                let enclosing = std::mem::replace(&mut self.environment, function.environment.clone());
+                let depth = std::mem::replace(&mut self.depth, 1);
+                for (param, argument) in declaration.params.iter().zip(arguments) {
+                    self.define(param, argument);
+                }
+                let result = self.declarations(&declaration.body);
+                self.environment = enclosing;
+                self.depth = depth;
+                self.frames.pop();
+
+                match result {
+                    Ok(()) => Ok(Value::nil()),
+                    Err(Unwind::Return(value)) => Ok(value),
+                    Err(Unwind::Error) => Err(Unwind::Error),
+                }
+            }
+            Some(ObjType::NativeFn(native)) => {
+                if arguments.len() != native.arity as usize {
+                    let message = format!("Expected {} arguments but got {}.", native.arity, arguments.len());
+                    return Err(self.error(&message, line));
+                }
+                Ok((native.fun)(&arguments))
+            }
+            _ => Err(self.error("Can only call functions and classes.", line)),
+        }
+    }
+
+    // How far the native stack has grown since the program started.
+    fn stack_used(&self) -> usize {
+        let here = 0u8;
+        self.stack_base.abs_diff(&here as *const u8 as usize)
+    }
+
+    fn closure(&self, declaration: &Rc<Function>) -> Value {
+        Value::object(ObjType::TreeFunction(TreeFunction {
+            declaration: declaration.clone(),
+            environment: self.environment.clone(),
+        }))
+    }
+
+    fn define(&mut self, name: &Token, value: Value) {
+        if self.depth == 0 {
+            let slot = self.memory.globals.resolve(&name.text);
+            self.memory.globals.define(slot, value);
+        } else {
+            self.environment = self.environment.define(&name.text, value);
+        }
+    }
+
+    fn variable(&mut self, name: &Token, line: usize) -> Flow<Value> {
+        if let Some(value) = self.environment.lookup(&name.text) {
+            return Ok(value.borrow().clone());
+        }
+        let slot = self.memory.globals.resolve(&name.text);
+        match self.memory.globals.get(slot) {
+            Some(value) => Ok(value.clone()),
+            None => Err(self.error(&format!("Undefined variable '{}'.", name.text), line)),
+        }
+    }
+
+    fn assign(&mut self, name: &Token, value: Value, line: usize) -> Flow<()> {
+        if let Some(variable) = self.environment.lookup(&name.text) {
+            *variable.borrow_mut() = value;
+            return Ok(());
+        }
+        let slot = self.memory.globals.resolve(&name.text);
+        if !self.memory.globals.set(slot, value) {
+            return Err(self.error(&format!("Undefined variable '{}'.", name.text), line));
+        }
+        Ok(())
+    }
+
+    // Reports an error on a line of the innermost frame, with the calls it is in.
+    fn error(&mut self, message: &str, line: usize) -> Unwind {
+        self.frames.last_mut().unwrap().line = line;
+        writeln!(self.errors, "{}", message).unwrap();
+        for frame in self.frames.iter().rev() {
+            match &frame.name {
+                Some(name) => writeln!(self.errors, "[line {}] in {}()", frame.line, name).unwrap(),
+                None => writeln!(self.errors, "[line {}] in script", frame.line).unwrap(),
+            }
+        }
+        Unwind::Error
+    }
+}
+
+fn is_falsey(value: &Value) -> bool {
+    match value.unpack() {
+        Unpacked::Nil => true,
+        Unpacked::Bool(b) => !b,
+        _ => false,
+    }
+}
@@ -0,0 +1,65 @@
+use chunk::MAX_LONG_INDEX;
+
+// Sizes that clox gets from its one-byte and two-byte operands and its fixed arrays.
+// Constant indexes past 255 use the `*_LONG` instructions, so only locals, upvalues,
+// arguments and jumps are capped by the width of their operands.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    // constants in a single chunk
+    pub constants: usize,
+    // locals in a function, including the reserved slot zero
+    pub locals: usize,
+    // upvalues captured by a function
+    pub upvalues: usize,
+    // arguments in a call, and parameters in a function declaration
+    pub arguments: usize,
+    // distance of a forward or backward jump, in bytes
+    pub jump: usize,
+    // nested calls, including the top-level script
+    pub frames: usize,
+    // values on the stack
+    pub stack: usize,
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits {
+            constants: 256,
+            locals: 256,
+            upvalues: 256,
+            arguments: u8::MAX as usize,
+            jump: u16::MAX as usize,
+            frames: 64,
+            stack: 64 * 256,
+        }
+    }
+
+    // Sets a limit from its field name, as given on the command line.
+    // Fails for unknown names, for values the bytecode cannot encode and for ones that
+    // leave the VM no room to run the script itself.
+    pub fn set(&mut self, name: &str, value: usize) -> bool {
+        let (min, max) = match name {
+            "constants" => (0, MAX_LONG_INDEX + 1),
+            "locals" | "upvalues" => (0, u8::MAX as usize + 1),
+            "arguments" => (0, u8::MAX as usize),
+            "jump" => (0, u16::MAX as usize),
+            // the script takes a frame and the stack slot of its function
+            "frames" | "stack" => (1, usize::MAX),
+            _ => (0, usize::MAX),
+        };
+        if value < min || value > max {
+            return false;
+        }
+        match name {
+            "constants" => self.constants = value,
+            "locals" => self.locals = value,
+            "upvalues" => self.upvalues = value,
+            "arguments" => self.arguments = value,
+            "jump" => self.jump = value,
+            "frames" => self.frames = value,
+            "stack" => self.stack = value,
+            _ => return false,
+        }
+        true
+    }
+}
@@ -0,0 +1,259 @@
+use std::fs;
+use std::path::Path;
+
+use compiler::Compiler;
+use diagnostic::Diagnostic;
+use diagnostic::DiagnosticFormat;
+use format;
+use limits::Limits;
+use memory::Memory;
+use parser::Parser;
+use scanner::Scanner;
+use scanner::Token;
+use scanner::TokenType;
+use scanner::Trivia;
+
+// The warnings the compiler can raise, by rule ID.
+pub const RULES: &[&str] = &[
+    "unused-variable",
+    "unused-parameter",
+    "shadowing",
+    "undeclared-global",
+    "unreachable-code",
+    "constant-condition",
+    "self-comparison",
+];
+
+// A config comment: `// lint: disable=<rules>` turns rules off from its line on,
+// `// lint: enable=<rules>` turns them back on. `all` names every rule.
+struct Setting {
+    line: usize,
+    rule: String,
+    enabled: bool,
+}
+
+// Compiles the source for its warnings. Fails with the compile errors if it has any.
+pub fn lint(source: &str, memory: Memory) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+    let mut parser = Parser::new(source.to_string(), Limits::new(), memory);
+    Compiler::new(&mut parser).compile();
+    if !parser.diagnostics().is_empty() {
+        return Err(parser.diagnostics().to_vec());
+    }
+    Ok(enabled(source, parser.warnings()))
+}
+
+// The warnings the config comments of the source leave on, in source order, with
+// an error for each config comment naming a rule that does not exist.
+pub fn enabled(source: &str, warnings: &[Diagnostic]) -> Vec<Diagnostic> {
+    let (settings, mut found) = settings(source);
+    found.extend(
+        warnings
+            .iter()
+            .filter(|w| is_enabled(&settings, w))
+            .cloned(),
+    );
+    found.sort_by_key(|d| d.token.start);
+    found
+}
+
+fn is_enabled(settings: &[Setting], warning: &Diagnostic) -> bool {
+    settings
+        .iter()
+        .filter(|s| s.line <= warning.token.line)
+        .rfind(|s| s.rule == "all" || Some(s.rule.as_str()) == warning.rule)
+        .is_none_or(|s| s.enabled)
+}
+
+fn settings(source: &str) -> (Vec<Setting>, Vec<Diagnostic>) {
+    let mut settings = Vec::new();
+    let mut errors = Vec::new();
+    let mut scanner = Scanner::with_trivia(source.to_string());
+    loop {
+        let token = scanner.scan();
+        for trivia in scanner.take_trivia() {
+            let (text, line, start) = match trivia {
+                Trivia::Comment { text, line, start } => (text, line, start),
+                Trivia::Newline => continue,
+            };
+            let config = match text.trim_start_matches('/').trim().strip_prefix("lint:") {
+                Some(config) => config.trim().to_string(),
+                None => continue,
+            };
+            let (enabled, rules) = if let Some(rules) = config.strip_prefix("disable=") {
+                (false, rules)
+            } else if let Some(rules) = config.strip_prefix("enable=") {
+                (true, rules)
+            } else {
+                let message = "Expect 'disable=' or 'enable=' after 'lint:'.";
+                errors.push(Diagnostic::new(comment_token(&text, line, start), message));
+                continue;
+            };
+            for rule in rules.split(',').map(str::trim) {
+                if rule != "all" && !RULES.contains(&rule) {
+                    let message = format!("Unknown lint rule '{}'.", rule);
+                    errors.push(Diagnostic::new(comment_token(&text, line, start), &message));
+                }
+                settings.push(Setting {
+                    line,
+                    rule: rule.to_string(),
+                    enabled,
+                });
+            }
+        }
+        if token.tpe == TokenType::Eof {
+            return (settings, errors);
+        }
+    }
+}
+
+fn comment_token(text: &str, line: usize, start: usize) -> Token {
+    Token {
+        tpe: TokenType::Error,
+        text: text.to_string(),
+        line,
+        start,
+        length: text.chars().count(),
+    }
+}
+
+// `rlox lint`: prints the warnings of every file, and the errors of those that do
+// not compile. Returns whether there were any.
+pub fn run(paths: &[&str], memory: &Memory, diagnostics: DiagnosticFormat) -> Result<bool, String> {
+    let mut files = Vec::new();
+    for path in paths {
+        format::lox_files(Path::new(path), &mut files)?;
+    }
+    if files.is_empty() {
+        return Err(String::from("No Lox files found."));
+    }
+
+    let mut found = false;
+    for file in files {
+        let source =
+            fs::read_to_string(&file).map_err(|e| format!("Could not read \"{}\": {}", file, e))?;
+        let findings = match lint(&source, memory.clone()) {
+            Ok(warnings) => warnings,
+            Err(errors) => errors,
+        };
+        if findings.is_empty() {
+            continue;
+        }
+        found = true;
+        // JSON is one object per line, for tools that lint one file at a time
+        if diagnostics != DiagnosticFormat::Json {
+            println!("{}:", file);
+        }
+        let chars = source.chars().collect::<Vec<_>>();
+        for finding in findings {
+            println!("{}", finding.render(diagnostics, &chars));
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each finding as its line, its rule and its message.
+    fn findings(source: &str) -> Vec<String> {
+        let found = match lint(source, Memory::new()) {
+            Ok(warnings) => warnings,
+            Err(errors) => errors,
+        };
+        found
+            .iter()
+            .map(|d| format!("{} {} {}", d.token.line, d.rule.unwrap_or("error"), d.message))
+            .collect()
+    }
+
+    #[test]
+    fn warns_about_unused_locals() {
+        let source = "{\n  var unused = 1;\n  var _ignored = 2;\n  fun helper() {}\n}\nfun f(a, b) { return a; }\n";
+        assert_eq!(
+            findings(source),
+            vec![
+                "2 unused-variable Variable 'unused' is never used.",
+                "4 unused-variable Function 'helper' is never used.",
+                "6 unused-parameter Parameter 'b' is never used.",
+            ]
+        );
+    }
+
+    #[test]
+    fn warns_about_shadowing() {
+        let source = "{\n  var a = 1;\n  print a;\n  {\n    var a = 2;\n    print a;\n  }\n}\n";
+        assert_eq!(findings(source), vec!["5 shadowing 'a' shadows a variable of an enclosing scope."]);
+        // nor a global, nor a local of its own scope
+        assert_eq!(findings("var a = 1;\n{\n  var a = 2;\n  print a;\n}\n"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn warns_about_undeclared_globals() {
+        let source = "undeclared = 1;\nvar declared;\ndeclared = 2;\nlater = 3;\nvar later;\n";
+        assert_eq!(findings(source), vec!["1 undeclared-global Assignment to undeclared global 'undeclared'."]);
+    }
+
+    #[test]
+    fn warns_once_about_code_after_a_return() {
+        let source = "fun f() {\n  return;\n  print 1;\n  print 2;\n}\nf();\n";
+        assert_eq!(findings(source), vec!["3 unreachable-code Unreachable code."]);
+    }
+
+    #[test]
+    fn warns_about_constant_conditions_of_if() {
+        let source = "if (true) print 1;\nif ((nil)) print 2;\nwhile (false) print 3;\nvar a;\nif (a) print 4;\n";
+        assert_eq!(
+            findings(source),
+            vec!["1 constant-condition Condition is always true.", "2 constant-condition Condition is always false."]
+        );
+    }
+
+    #[test]
+    fn warns_about_comparing_a_variable_with_itself() {
+        let source = "var a = 1;\nprint a == a;\nprint (a) < a;\nprint a + a;\nprint 1 == 1;\n";
+        assert_eq!(
+            findings(source),
+            vec![
+                "2 self-comparison Both sides of '==' are the same variable.",
+                "3 self-comparison Both sides of '<' are the same variable.",
+            ]
+        );
+    }
+
+    #[test]
+    fn config_comments_turn_rules_off_and_on() {
+        let source = "// lint: disable=self-comparison, constant-condition\n\
+                      var a = 1;\n\
+                      print a == a;\n\
+                      if (true) print a;\n\
+                      // lint: enable=self-comparison\n\
+                      print a == a;\n\
+                      if (true) print a;\n\
+                      // lint: disable=all\n\
+                      print a == a;\n\
+                      // lint: enable=all\n\
+                      if (true) print a;\n";
+        assert_eq!(
+            findings(source),
+            vec![
+                "6 self-comparison Both sides of '==' are the same variable.",
+                "11 constant-condition Condition is always true.",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_bad_config_comments() {
+        let source = "// lint: disable=no-such-rule\n// lint: off\nprint 1;\n";
+        assert_eq!(
+            findings(source),
+            vec!["1 error Unknown lint rule 'no-such-rule'.", "2 error Expect 'disable=' or 'enable=' after 'lint:'."]
+        );
+    }
+
+    #[test]
+    fn reports_compile_errors_instead_of_warnings() {
+        assert_eq!(findings("var a = 1;\nprint a == a;\nprint ;\n"), vec!["3 error Expect expression."]);
+    }
+}
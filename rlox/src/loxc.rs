@@ -0,0 +1,277 @@
+use std::rc::Rc;
+
+use chunk::Chunk;
+use object::Function;
+use object::LoxString;
+use object::FunctionType;
+use object::ObjType;
+use value::Unpacked;
+use value::Value;
+
+// Layout of a `.loxc` file, all integers big-endian like the bytecode operands:
+//
+//   magic    "LOXC"
+//   version  u16
+//   checksum u32, FNV-1a of the payload
+//   length   u32, size of the payload
+//   payload  the global names, then the script function
+//
+// A function is its name, arity, kind, code, line table and constants. Nested
+// functions are stored inline as constants; their upvalue descriptors are part
+// of the CLOSURE instructions that create them. Global instructions refer to the
+// names by slot; the VM moves them onto its own slots when it loads the file.
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u16 = 2;
+
+const HEADER_SIZE: usize = 4 + 2 + 4 + 4;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+pub fn is_loxc(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn write(function: &Function, globals: &[String]) -> Result<Vec<u8>, &'static str> {
+    let mut payload = Writer { bytes: Vec::new() };
+    payload.u32(globals.len());
+    for name in globals {
+        payload.string(name);
+    }
+    payload.function(function)?;
+    let payload = payload.bytes;
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + payload.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_be_bytes());
+    out.extend_from_slice(&checksum(&payload).to_be_bytes());
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    out.extend_from_slice(&payload);
+    Ok(out)
+}
+
+// Returns the script together with the names of its global slots.
+pub fn read(bytes: &[u8]) -> Result<(Function, Vec<String>), &'static str> {
+    if !is_loxc(bytes) {
+        return Err("Not a compiled Lox file.");
+    }
+    if bytes.len() < HEADER_SIZE {
+        return Err("Truncated header.");
+    }
+    let mut header = Reader { bytes: &bytes[..HEADER_SIZE], at: MAGIC.len() };
+    if header.u16()? != VERSION {
+        return Err("Unsupported bytecode version.");
+    }
+    let expected = header.u32()?;
+    let length = header.u32()? as usize;
+
+    let payload = &bytes[HEADER_SIZE..];
+    if payload.len() != length {
+        return Err("Payload length does not match the header.");
+    }
+    if checksum(payload) != expected {
+        return Err("Checksum mismatch.");
+    }
+
+    let mut reader = Reader { bytes: payload, at: 0 };
+    let mut globals = Vec::new();
+    for _ in 0..reader.len()? {
+        globals.push(reader.string()?);
+    }
+    let function = reader.function()?;
+    if reader.at != payload.len() {
+        return Err("Trailing bytes after the script.");
+    }
+    if function.tpe != FunctionType::Script {
+        return Err("Top-level function is not a script.");
+    }
+    Ok((function, globals))
+}
+
+// 32-bit FNV-1a.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &b| (hash ^ b as u32).wrapping_mul(0x01000193))
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: usize) {
+        self.bytes.extend_from_slice(&(value as u32).to_be_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.u32(s.len());
+        self.bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn function(&mut self, function: &Function) -> Result<(), &'static str> {
+        match &function.name {
+            Some(name) => {
+                self.u8(1);
+                self.string(name);
+            }
+            None => self.u8(0),
+        }
+        self.u32(function.arity as usize);
+        self.u8(match function.tpe {
+            FunctionType::Script => 0,
+            FunctionType::Function => 1,
+        });
+
+        let chunk = &function.chunk;
+        self.u32(chunk.code.len());
+        self.bytes.extend_from_slice(&chunk.code);
+
+        // lines are stored per byte, so run-length encode them
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for &line in chunk.lines() {
+            match runs.last_mut() {
+                Some((last, count)) if *last == line => *count += 1,
+                _ => runs.push((line, 1)),
+            }
+        }
+        self.u32(runs.len());
+        for (line, count) in runs {
+            self.u32(line);
+            self.u32(count);
+        }
+
+        self.u32(chunk.values.len());
+        for value in chunk.values.iter() {
+            self.value(value)?;
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, value: &Value) -> Result<(), &'static str> {
+        match value.unpack() {
+            Unpacked::Nil => self.u8(TAG_NIL),
+            Unpacked::Bool(false) => self.u8(TAG_FALSE),
+            Unpacked::Bool(true) => self.u8(TAG_TRUE),
+            Unpacked::Number(n) => {
+                self.u8(TAG_NUMBER);
+                self.bytes.extend_from_slice(&n.to_bits().to_be_bytes());
+            }
+            Unpacked::Object(ObjType::String(s)) => {
+                self.u8(TAG_STRING);
+                self.string(s.as_str());
+            }
+            Unpacked::Object(ObjType::Function(f)) => {
+                self.u8(TAG_FUNCTION);
+                self.function(f)?;
+            }
+            Unpacked::Object(_) => return Err("Only strings and functions can be stored as constants."),
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl <'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], &'static str> {
+        if self.bytes.len() - self.at < n {
+            return Err("Unexpected end of file.");
+        }
+        let slice = &self.bytes[self.at..self.at + n];
+        self.at += n;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, &'static str> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, &'static str> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn len(&mut self) -> Result<usize, &'static str> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn string(&mut self) -> Result<String, &'static str> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| "Invalid UTF-8 in string.")
+    }
+
+    fn function(&mut self) -> Result<Function, &'static str> {
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?),
+            _ => return Err("Invalid function name."),
+        };
+        let arity = self.u32()?;
+        let tpe = match self.u8()? {
+            0 => FunctionType::Script,
+            1 => FunctionType::Function,
+            _ => return Err("Invalid function kind."),
+        };
+
+        let code_len = self.len()?;
+        let code = self.take(code_len)?.to_vec();
+
+        let mut lines = Vec::with_capacity(code.len());
+        for _ in 0..self.len()? {
+            let line = self.len()?;
+            let count = self.len()?;
+            if lines.len() + count > code.len() {
+                return Err("Line table is longer than the code.");
+            }
+            lines.extend(std::iter::repeat_n(line, count));
+        }
+        if lines.len() != code.len() {
+            return Err("Line table does not cover the code.");
+        }
+
+        let mut values = Vec::new();
+        for _ in 0..self.len()? {
+            values.push(self.value()?);
+        }
+
+        Ok(Function {
+            arity,
+            chunk: Chunk::from_parts(code, values, lines),
+            name,
+            tpe,
+        })
+    }
+
+    fn value(&mut self) -> Result<Value, &'static str> {
+        match self.u8()? {
+            TAG_NIL => Ok(Value::nil()),
+            TAG_FALSE => Ok(Value::boolean(false)),
+            TAG_TRUE => Ok(Value::boolean(true)),
+            TAG_NUMBER => {
+                let b = self.take(8)?;
+                let mut bits = [0; 8];
+                bits.copy_from_slice(b);
+                Ok(Value::number(f64::from_bits(u64::from_be_bytes(bits))))
+            }
+            TAG_STRING => Ok(Value::object(ObjType::String(Rc::new(LoxString::new(self.string()?))))),
+            TAG_FUNCTION => Ok(Value::object(ObjType::Function(self.function()?))),
+            _ => Err("Invalid constant tag."),
+        }
+    }
+}
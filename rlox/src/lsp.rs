@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+use compiler::Compiler;
+use diagnostic::Diagnostic;
+use json::Json;
+use limits::Limits;
+use lint;
+use memory::Memory;
+use parser::Parser;
+use protocol;
+use protocol::object;
+use scanner::Token;
+use symbols::SymbolKind;
+use symbols::Symbols;
+
+const KEYWORDS: &[&str] = &[
+    "and", "class", "else", "false", "for", "fun", "if", "nil", "or", "print", "return", "super", "this", "true",
+    "var", "while",
+];
+
+// Names the VM defines before a script runs.
+const NATIVES: &[&str] = &["clock"];
+
+// LSP's numbers for the kinds of symbols and completions, and for severities.
+const SYMBOL_FUNCTION: f64 = 12.0;
+const SYMBOL_VARIABLE: f64 = 13.0;
+const COMPLETION_FUNCTION: f64 = 3.0;
+const COMPLETION_VARIABLE: f64 = 6.0;
+const COMPLETION_KEYWORD: f64 = 14.0;
+const SEVERITY_ERROR: f64 = 1.0;
+const SEVERITY_WARNING: f64 = 2.0;
+
+// `rlox lsp`: a Language Server Protocol server on stdin and stdout. Every change
+// to a document compiles it again, for its diagnostics and its symbols.
+pub fn serve() {
+    let mut input = io::BufReader::new(io::stdin());
+    let mut server = Server { out: Box::new(io::stdout()), documents: HashMap::new() };
+    while let Some(message) = protocol::read_message(&mut input) {
+        if message.get("method").and_then(Json::as_str) == Some("exit") {
+            break;
+        }
+        server.handle(&message);
+    }
+}
+
+// An open document, as of its last change.
+struct Document {
+    // character offset of the start of each line
+    lines: Vec<usize>,
+    length: usize,
+    // the compile errors, or if there are none the lint warnings
+    diagnostics: Vec<Diagnostic>,
+    symbols: Symbols,
+}
+
+impl Document {
+    fn new(text: &str) -> Document {
+        let mut memory = Memory::new();
+        for native in NATIVES {
+            memory.globals.resolve(native);
+        }
+        let mut parser = Parser::new(text.to_string(), Limits::new(), memory);
+        Compiler::new(&mut parser).compile();
+        let diagnostics = if parser.diagnostics().is_empty() {
+            lint::enabled(text, parser.warnings())
+        } else {
+            parser.diagnostics().to_vec()
+        };
+        let mut lines = vec![0];
+        for (i, c) in text.chars().enumerate() {
+            if c == '\n' {
+                lines.push(i + 1);
+            }
+        }
+        Document { lines, length: text.chars().count(), diagnostics, symbols: parser.take_symbols() }
+    }
+
+    fn position(&self, offset: usize) -> Json {
+        let offset = offset.min(self.length);
+        let line = match self.lines.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        object(vec![
+            ("line", Json::Number(line as f64)),
+            ("character", Json::Number((offset - self.lines[line]) as f64)),
+        ])
+    }
+
+    fn range(&self, token: &Token) -> Json {
+        object(vec![("start", self.position(token.start)), ("end", self.position(token.start + token.length))])
+    }
+
+    // The character offset of an LSP position.
+    fn offset(&self, position: &Json) -> Option<usize> {
+        let line = position.get("line").and_then(Json::as_f64)? as usize;
+        let character = position.get("character").and_then(Json::as_f64)? as usize;
+        Some(self.lines.get(line)? + character)
+    }
+}
+
+struct Server {
+    out: Box<dyn Write>,
+    documents: HashMap<String, Document>,
+}
+
+impl Server {
+    fn handle(&mut self, message: &Json) {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        let uri = params.get("textDocument").and_then(|d| d.get("uri")).and_then(Json::as_str);
+        let uri = uri.unwrap_or("").to_string();
+
+        // notifications
+        match method {
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").and_then(|d| d.get("text")).and_then(Json::as_str);
+                self.update(&uri, text.unwrap_or(""));
+                return;
+            }
+            "textDocument/didChange" => {
+                // the server asks for whole documents, so the last change has all of it
+                let changes = params.get("contentChanges").and_then(Json::as_array).unwrap_or(&[]);
+                if let Some(text) = changes.last().and_then(|c| c.get("text")).and_then(Json::as_str) {
+                    self.update(&uri, text);
+                }
+                return;
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.publish(&uri, Vec::new());
+                return;
+            }
+            _ => {}
+        }
+
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return,
+        };
+        let document = self.documents.get(&uri);
+        let at = document.and_then(|d| Some((d, d.symbols.definition_at(d.offset(params.get("position")?)?)?)));
+        let result = match method {
+            "initialize" => object(vec![
+                ("capabilities", object(vec![
+                    ("textDocumentSync", Json::Number(1.0)),
+                    ("documentSymbolProvider", Json::Bool(true)),
+                    ("definitionProvider", Json::Bool(true)),
+                    ("referencesProvider", Json::Bool(true)),
+                    ("hoverProvider", Json::Bool(true)),
+                    ("completionProvider", object(vec![])),
+                ])),
+                ("serverInfo", object(vec![("name", Json::String(String::from("rlox")))])),
+            ]),
+            "shutdown" => Json::Null,
+            "textDocument/documentSymbol" => match document {
+                Some(document) => Json::Array(document_symbols(&uri, document)),
+                None => Json::Null,
+            },
+            "textDocument/definition" => match at {
+                Some((document, definition)) => {
+                    location(&uri, document, &document.symbols.definitions()[definition].token)
+                }
+                None => Json::Null,
+            },
+            "textDocument/references" => match at {
+                Some((document, definition)) => {
+                    let declaration = params
+                        .get("context")
+                        .and_then(|c| c.get("includeDeclaration"))
+                        .and_then(Json::as_bool)
+                        .unwrap_or(true);
+                    let mut tokens = Vec::new();
+                    if declaration {
+                        tokens.push(&document.symbols.definitions()[definition].token);
+                    }
+                    tokens.extend(document.symbols.uses(definition));
+                    Json::Array(tokens.into_iter().map(|token| location(&uri, document, token)).collect())
+                }
+                None => Json::Null,
+            },
+            "textDocument/hover" => match at {
+                Some((document, definition)) => object(vec![
+                    ("contents", object(vec![
+                        ("kind", Json::String(String::from("plaintext"))),
+                        ("value", Json::String(describe(&document.symbols, definition))),
+                    ])),
+                    ("range", document.range(&document.symbols.definitions()[definition].token)),
+                ]),
+                None => Json::Null,
+            },
+            "textDocument/completion" => Json::Array(completions(document)),
+            _ => {
+                self.send(object(vec![
+                    ("jsonrpc", Json::String(String::from("2.0"))),
+                    ("id", id),
+                    ("error", object(vec![
+                        ("code", Json::Number(-32601.0)),
+                        ("message", Json::String(format!("Unsupported method \"{}\".", method))),
+                    ])),
+                ]));
+                return;
+            }
+        };
+        self.send(object(vec![("jsonrpc", Json::String(String::from("2.0"))), ("id", id), ("result", result)]));
+    }
+
+    fn update(&mut self, uri: &str, text: &str) {
+        let document = Document::new(text);
+        let diagnostics = document
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let related = diagnostic
+                    .notes
+                    .iter()
+                    .map(|note| object(vec![
+                        ("location", location(uri, &document, &note.token)),
+                        ("message", Json::String(note.message.clone())),
+                    ]))
+                    .collect();
+                let severity = if diagnostic.rule.is_some() { SEVERITY_WARNING } else { SEVERITY_ERROR };
+                let mut members = vec![
+                    ("range", document.range(&diagnostic.token)),
+                    ("severity", Json::Number(severity)),
+                    ("source", Json::String(String::from("rlox"))),
+                    ("message", Json::String(diagnostic.message.clone())),
+                    ("relatedInformation", Json::Array(related)),
+                ];
+                if let Some(rule) = diagnostic.rule {
+                    members.push(("code", Json::String(rule.to_string())));
+                }
+                object(members)
+            })
+            .collect();
+        self.documents.insert(uri.to_string(), document);
+        self.publish(uri, diagnostics);
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Json>) {
+        self.send(object(vec![
+            ("jsonrpc", Json::String(String::from("2.0"))),
+            ("method", Json::String(String::from("textDocument/publishDiagnostics"))),
+            ("params", object(vec![("uri", Json::String(uri.to_string())), ("diagnostics", Json::Array(diagnostics))])),
+        ]));
+    }
+
+    fn send(&mut self, message: Json) {
+        protocol::write_message(&mut *self.out, &message);
+    }
+}
+
+// The functions, wherever they are declared, and the global variables. The
+// compiler has no classes yet, so there are none to list.
+fn document_symbols(uri: &str, document: &Document) -> Vec<Json> {
+    document
+        .symbols
+        .definitions()
+        .iter()
+        .filter_map(|definition| {
+            let kind = match definition.kind {
+                SymbolKind::Function { .. } => SYMBOL_FUNCTION,
+                SymbolKind::Variable if definition.global => SYMBOL_VARIABLE,
+                _ => return None,
+            };
+            Some(object(vec![
+                ("name", Json::String(definition.token.text.clone())),
+                ("kind", Json::Number(kind)),
+                ("location", location(uri, document, &definition.token)),
+            ]))
+        })
+        .collect()
+}
+
+fn describe(symbols: &Symbols, definition: usize) -> String {
+    let declared = &symbols.definitions()[definition];
+    let name = &declared.token.text;
+    match declared.kind {
+        SymbolKind::Function { arity } => {
+            // the parameters are declared right after the function's name
+            let parameters = symbols.definitions()[definition + 1..]
+                .iter()
+                .take(arity as usize)
+                .map(|p| p.token.text.clone())
+                .collect::<Vec<_>>();
+            let plural = if arity == 1 { "" } else { "s" };
+            format!("fun {}({})\n\nTakes {} argument{}.", name, parameters.join(", "), arity, plural)
+        }
+        SymbolKind::Parameter => format!("parameter {}", name),
+        SymbolKind::Variable if declared.global => format!("var {} (global)", name),
+        SymbolKind::Variable => format!("var {} (local)", name),
+    }
+}
+
+// The keywords and every name declared in the document.
+fn completions(document: Option<&Document>) -> Vec<Json> {
+    let mut items = KEYWORDS.iter().map(|k| (k.to_string(), COMPLETION_KEYWORD)).collect::<Vec<_>>();
+    items.extend(NATIVES.iter().map(|n| (n.to_string(), COMPLETION_FUNCTION)));
+    if let Some(document) = document {
+        for definition in document.symbols.definitions() {
+            let kind = match definition.kind {
+                SymbolKind::Function { .. } => COMPLETION_FUNCTION,
+                _ => COMPLETION_VARIABLE,
+            };
+            if !items.iter().any(|(label, _)| *label == definition.token.text) {
+                items.push((definition.token.text.clone(), kind));
+            }
+        }
+    }
+    items
+        .into_iter()
+        .map(|(label, kind)| object(vec![("label", Json::String(label)), ("kind", Json::Number(kind))]))
+        .collect()
+}
+
+fn location(uri: &str, document: &Document, token: &Token) -> Json {
+    object(vec![("uri", Json::String(uri.to_string())), ("range", document.range(token))])
+}
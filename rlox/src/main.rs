@@ -1,11 +1,34 @@
 extern crate core;
 
+mod assembler;
+mod ast;
+mod bench;
 mod chunk;
+mod codegen;
 mod compiler;
+mod coverage;
+mod dap;
+mod debugger;
+mod diagnostic;
+mod differential;
+mod difftest;
+mod format;
+mod interpreter;
+mod json;
+mod limits;
+mod lint;
+mod loxc;
+mod lsp;
 mod memory;
 mod object;
+mod optimizer;
+mod parser;
+mod profile;
+mod protocol;
 mod scanner;
+mod symbols;
 mod value;
+mod verifier;
 mod vm;
 
 use std::env;
@@ -13,23 +36,168 @@ use std::fs;
 use std::io;
 use std::io::Write;
 use std::process;
+use std::thread;
 
+use diagnostic::DiagnosticFormat;
+use interpreter::Interpreter;
+use limits::Limits;
+use object::Function;
 use vm::InterpretResult;
 use vm::VM;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    // the tree-walking interpreter nests calls on the native stack, so give it the
+    // stack it counts on
+    let main = thread::Builder::new().stack_size(interpreter::STACK_SIZE).spawn(run).unwrap();
+    if main.join().is_err() {
+        process::exit(101);
+    }
+}
 
+fn run() {
     let mut vm = VM::new();
+    let mut limits = Limits::new();
+    let mut paths = Vec::new();
+    let mut output = None;
+    let mut bench = bench::Options::new();
+    let mut difftest = difftest::Options::new();
+    let mut reports = Reports { stacks: None, coverage: None, annotate: None };
+    let mut check = false;
+    let mut engine = Engine::Vm;
 
-    if args.len() == 1 {
-        repl(&mut vm);
-    } else if args.len() == 2 {
-        run_file(&mut vm, &args[1]);
-    } else {
-        eprint!("Usage: clox [path]\n");
-        process::exit(64);
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            match args.next() {
+                Some(path) => output = Some(path),
+                None => usage(),
+            }
+        } else if arg == "--coverage" || arg == "--annotate" {
+            match args.next() {
+                Some(path) if arg == "--coverage" => reports.coverage = Some(path),
+                Some(path) => reports.annotate = Some(path),
+                None => usage(),
+            }
+            vm.enable_coverage();
+        } else if let Some(name) = arg.strip_prefix("--diagnostics=") {
+            match DiagnosticFormat::from_name(name) {
+                Some(format) => vm.set_diagnostics(format),
+                None => usage(),
+            }
+            difftest.rlox.push(arg.clone());
+        } else if let Some(name) = arg.strip_prefix("--engine=") {
+            match name {
+                "vm" => engine = Engine::Vm,
+                "tree" => engine = Engine::Tree,
+                "diff" => engine = Engine::Diff,
+                _ => usage(),
+            }
+            difftest.rlox.push(arg.clone());
+        } else if let Some(limit) = arg.strip_prefix("--limit-") {
+            let parsed = limit.split_once('=')
+                .and_then(|(name, value)| value.parse().ok().map(|value| (name, value)));
+            match parsed {
+                Some((name, value)) if limits.set(name, value) => {}
+                _ => usage(),
+            }
+            difftest.rlox.push(arg.clone());
+        } else if arg == "-O0" || arg == "-O1" {
+            vm.set_optimize(arg == "-O1");
+            bench.optimize = arg == "-O1";
+            difftest.rlox.push(arg.clone());
+        } else if let Some(runs) = arg.strip_prefix("--runs=") {
+            match runs.parse() {
+                Ok(runs) if runs > 0 => bench.runs = runs,
+                _ => usage(),
+            }
+        } else if let Some(threshold) = arg.strip_prefix("--threshold=") {
+            match threshold.parse() {
+                Ok(threshold) => bench.threshold = threshold,
+                _ => usage(),
+            }
+        } else if let Some(path) = arg.strip_prefix("--baseline=") {
+            bench.baseline = Some(path.to_string());
+        } else if let Some(path) = arg.strip_prefix("--save=") {
+            bench.save = Some(path.to_string());
+        } else if let Some(path) = arg.strip_prefix("--clox=") {
+            difftest.clox = path.to_string();
+        } else if let Some(timeout) = arg.strip_prefix("--timeout=") {
+            match timeout.parse() {
+                Ok(timeout) if timeout > 0 => difftest.timeout = timeout,
+                _ => usage(),
+            }
+        } else if arg == "--check" {
+            check = true;
+        } else if arg == "--profile" {
+            vm.enable_profiling();
+        } else if let Some(path) = arg.strip_prefix("--profile=") {
+            vm.enable_profiling();
+            reports.stacks = Some(path.to_string());
+        } else if arg.starts_with("--") {
+            usage();
+        } else {
+            paths.push(arg);
+        }
     }
+
+    vm.set_limits(limits);
+    bench.limits = limits;
+    let mut interpreter = Interpreter::new();
+    interpreter.set_limits(limits);
+    interpreter.set_diagnostics(vm.diagnostics());
+
+    match paths.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] if output.is_none() => repl(&mut vm),
+        ["compile", path] => match output {
+            Some(output) => compile_file(&mut vm, path, &output),
+            None => usage(),
+        },
+        ["disasm", path] if output.is_none() => disasm_file(&mut vm, path),
+        ["debug", path] if output.is_none() => debug_file(&mut vm, path),
+        ["dap"] if output.is_none() => dap::serve(&mut vm),
+        ["lsp"] if output.is_none() => lsp::serve(),
+        ["fmt", ref paths @ ..] if output.is_none() && !paths.is_empty() => {
+            run_format(paths, check, vm.diagnostics())
+        }
+        ["lint", ref paths @ ..] if output.is_none() && !paths.is_empty() => run_lint(paths, &vm),
+        ["bench"] if output.is_none() => run_bench(&["test/benchmark"], &bench),
+        ["bench", ref paths @ ..] if output.is_none() => run_bench(paths, &bench),
+        ["difftest", ref paths @ ..] if output.is_none() && !paths.is_empty() => run_difftest(paths, &difftest),
+        [path] if output.is_none() => match engine {
+            Engine::Vm => run_file(&mut vm, path, &reports),
+            Engine::Tree => run_tree(&mut interpreter, path),
+            Engine::Diff => run_differential(&mut vm, &mut interpreter, path),
+        },
+        _ => usage(),
+    }
+}
+
+fn usage() {
+    eprintln!("Usage: rlox [options] [path]");
+    eprintln!("       rlox compile [options] <path> -o <out.loxc|out.loxasm>");
+    eprintln!("       rlox disasm [options] <path>");
+    eprintln!("       rlox debug [options] <path>");
+    eprintln!("       rlox dap [options]");
+    eprintln!("       rlox lsp");
+    eprintln!("       rlox fmt [--check] <paths>");
+    eprintln!("       rlox lint [options] <paths>");
+    eprintln!("       rlox bench [options] [--runs=<n>] [--save=<file>] [--baseline=<file>] [--threshold=<percent>] [paths]");
+    eprintln!("       rlox difftest [options] [--clox=<binary>] [--timeout=<seconds>] <paths>");
+    eprintln!("Paths may name Lox source, an assembly listing (.loxasm) or compiled bytecode (.loxc).");
+    eprintln!("Benchmarks default to test/benchmark; --save writes a JSON baseline for --baseline to compare with.");
+    eprintln!("Options: -O0|-O1, --diagnostics=plain|pretty|json, --limit-<name>=<n>, --profile[=<stacks file>]");
+    eprintln!("         --coverage <out.info>, --annotate <out.txt>, --engine=vm|tree|diff");
+    eprintln!("--profile prints where a run spent its time; the file gets collapsed stacks for flame graphs.");
+    eprintln!("--engine=tree runs a script on a tree-walking interpreter instead of the VM; diff runs it on both");
+    eprintln!("         and reports the first line of output or error where they differ.");
+    eprintln!("--coverage writes lcov line and branch coverage; --annotate writes the source with line counts.");
+    eprintln!("fmt rewrites Lox files in place; --check lists the ones that are not formatted instead.");
+    eprintln!("difftest runs every Lox file in the paths on clox (clox/a.out, which make builds in clox/) and on");
+    eprintln!("         rlox with its -O, --limit, --engine and --diagnostics options, and lists where they differ.");
+    eprintln!("lint rules, which a `// lint: disable=<rules>` or `// lint: enable=<rules>` comment turns off or on:");
+    eprintln!("         {}", lint::RULES.join(", "));
+    eprintln!("Limits: constants, locals, upvalues, arguments, jump, frames, stack");
+    process::exit(64);
 }
 
 fn repl(vm: &mut VM) {
@@ -48,9 +216,124 @@ fn repl(vm: &mut VM) {
     }
 }
 
-fn run_file(vm: &mut VM, f: &str) {
-    let source = fs::read_to_string(f).expect("Could not open file");
-    match vm.interpret(&source) {
+fn read_file(f: &str) -> Vec<u8> {
+    fs::read(f).unwrap_or_else(|e| {
+        eprintln!("Could not open file \"{}\": {}", f, e);
+        process::exit(74);
+    })
+}
+
+fn to_source(f: &str, bytes: Vec<u8>) -> String {
+    String::from_utf8(bytes).unwrap_or_else(|_| {
+        eprintln!("Could not read file \"{}\": not valid UTF-8", f);
+        process::exit(74);
+    })
+}
+
+// Compiles a source file, or loads and verifies an assembly listing or a `.loxc` file.
+fn load_file(vm: &mut VM, f: &str) -> Function {
+    let bytes = read_file(f);
+    let (function, globals) = if loxc::is_loxc(&bytes) {
+        loxc::read(&bytes).unwrap_or_else(|e| {
+            eprintln!("Could not load \"{}\": {}", f, e);
+            process::exit(65);
+        })
+    } else if f.ends_with(".loxasm") {
+        assembler::assemble(&to_source(f, bytes)).unwrap_or_else(|e| {
+            eprintln!("Could not assemble \"{}\": {}", f, e);
+            process::exit(65);
+        })
+    } else {
+        return match vm.compile(&to_source(f, bytes)) {
+            Some(function) => function,
+            None => process::exit(65),
+        };
+    };
+    if let Err(e) = verifier::verify(&function, globals.len()) {
+        eprintln!("Invalid bytecode in \"{}\": {}", f, e);
+        process::exit(65);
+    }
+    vm.link(function, &globals)
+}
+
+// Reads a file that has to be Lox source, as the tree-walking interpreter needs.
+fn read_source(f: &str) -> String {
+    let bytes = read_file(f);
+    if loxc::is_loxc(&bytes) || f.ends_with(".loxasm") {
+        eprintln!("Could not interpret \"{}\": the tree engine needs Lox source.", f);
+        process::exit(74);
+    }
+    to_source(f, bytes)
+}
+
+// Which interpreter runs a script, from `--engine=<name>`.
+enum Engine {
+    Vm,
+    Tree,
+    // both, comparing what they do
+    Diff,
+}
+
+// Files written after a run, besides its output.
+struct Reports {
+    // collapsed stacks from `--profile=<file>`
+    stacks: Option<String>,
+    // lcov from `--coverage <file>`
+    coverage: Option<String>,
+    // annotated source from `--annotate <file>`
+    annotate: Option<String>,
+}
+
+fn write_file<C: AsRef<[u8]>>(path: &str, contents: C) {
+    if let Err(e) = fs::write(path, contents) {
+        eprintln!("Could not write file \"{}\": {}", path, e);
+        process::exit(74);
+    }
+}
+
+fn compile_file(vm: &mut VM, f: &str, output: &str) {
+    let function = load_file(vm, f);
+    let bytes = if output.ends_with(".loxasm") {
+        assembler::disassemble(&function, vm.global_names()).into_bytes()
+    } else {
+        loxc::write(&function, vm.global_names()).unwrap_or_else(|e| {
+            eprintln!("Could not serialize \"{}\": {}", f, e);
+            process::exit(65);
+        })
+    };
+    write_file(output, bytes);
+}
+
+// Prints the script and every function nested in it, without running anything.
+fn disasm_file(vm: &mut VM, f: &str) {
+    let function = load_file(vm, f);
+    print!("{}", assembler::disassemble(&function, vm.global_names()));
+}
+
+fn run_file(vm: &mut VM, f: &str, reports: &Reports) {
+    let function = load_file(vm, f);
+    let result = vm.execute(function);
+    if let Some(profiler) = vm.profiler() {
+        eprint!("{}", profiler.report());
+        if let Some(path) = &reports.stacks {
+            write_file(path, profiler.collapsed_stacks());
+        }
+    }
+    if let Some(coverage) = vm.coverage() {
+        if let Some(path) = &reports.coverage {
+            let source = fs::canonicalize(f).map(|p| p.display().to_string()).unwrap_or_else(|_| f.to_string());
+            write_file(path, coverage.lcov(&source));
+        }
+        if let Some(path) = &reports.annotate {
+            let bytes = read_file(f);
+            if loxc::is_loxc(&bytes) {
+                eprintln!("Could not annotate \"{}\": no source to show.", f);
+                process::exit(74);
+            }
+            write_file(path, coverage.annotate(&to_source(f, bytes)));
+        }
+    }
+    match result {
         InterpretResult::Ok => {}
         InterpretResult::CompileError => {
             process::exit(65);
@@ -60,3 +343,92 @@ fn run_file(vm: &mut VM, f: &str) {
         }
     }
 }
+
+fn run_tree(interpreter: &mut Interpreter, f: &str) {
+    let program = match interpreter.compile(&read_source(f)) {
+        Some(program) => program,
+        None => process::exit(65),
+    };
+    match interpreter.execute(&program) {
+        InterpretResult::Ok => {}
+        InterpretResult::CompileError => process::exit(65),
+        InterpretResult::RuntimeError => process::exit(70),
+    }
+}
+
+// Exits with 1 if the engines differ, reporting where.
+fn run_differential(vm: &mut VM, interpreter: &mut Interpreter, f: &str) {
+    match differential::run(vm, interpreter, &read_source(f)) {
+        Ok(0) => {}
+        Ok(code) => process::exit(code),
+        Err(report) => {
+            eprintln!("{}", report);
+            process::exit(1);
+        }
+    }
+}
+
+// Runs a script under the debugger, which pauses on its first line.
+fn debug_file(vm: &mut VM, f: &str) {
+    let function = load_file(vm, f);
+    let bytes = read_file(f);
+    // compiled files have no source to show
+    let source = if loxc::is_loxc(&bytes) { String::new() } else { to_source(f, bytes) };
+    let console = debugger::Console::new(&source);
+    // pause on the first line, so that breakpoints can be set
+    vm.set_debugger(debugger::Debugger::new(Box::new(console), debugger::Breakpoints::new(), true));
+    match vm.execute(function) {
+        InterpretResult::Ok => {}
+        InterpretResult::CompileError => process::exit(65),
+        InterpretResult::RuntimeError => process::exit(70),
+    }
+}
+
+// Exits with 1 if a file is not formatted, or could not be.
+fn run_format(paths: &[&str], check: bool, diagnostics: DiagnosticFormat) {
+    match format::run(paths, check, diagnostics) {
+        Ok(false) => {}
+        Ok(true) => process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(74);
+        }
+    }
+}
+
+// Exits with 1 if there are warnings, or errors.
+fn run_lint(paths: &[&str], vm: &VM) {
+    match lint::run(paths, vm.memory(), vm.diagnostics()) {
+        Ok(false) => {}
+        Ok(true) => process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(74);
+        }
+    }
+}
+
+// Exits with 1 if clox and rlox differ on a file.
+fn run_difftest(paths: &[&str], options: &difftest::Options) {
+    match difftest::run(paths, options) {
+        Ok(false) => {}
+        Ok(true) => process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(74);
+        }
+    }
+}
+
+// Exits with 1 if a benchmark fails, or is slower than the baseline by more than the
+// threshold.
+fn run_bench(paths: &[&str], options: &bench::Options) {
+    match bench::run(paths, options) {
+        Ok(false) => {}
+        Ok(true) => process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(74);
+        }
+    }
+}
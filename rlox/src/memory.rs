@@ -1,53 +1,113 @@
 use std::collections::HashMap;
-use std::collections::HashSet;
+use std::rc::Rc;
 
-use object::ObjType;
+use object::LoxString;
 use value::Value;
 
+// Global variables by slot. The compiler resolves every global name to a slot, so the
+// VM indexes `values` instead of hashing the name; a slot stays `None` until its
+// variable is defined.
 #[derive(Clone)]
-pub struct Memory {
-    objects: Vec<ObjType>,
-    pub globals: HashMap<String, Value>,
-    strings: HashSet<String>,
+pub struct Globals {
+    names: Vec<String>,
+    slots: HashMap<String, usize>,
+    values: Vec<Option<Value>>,
 }
 
-impl Memory {
-    pub fn new() -> Memory {
-        Memory {
-            objects: Vec::new(),
-            globals: HashMap::new(),
-            strings: HashSet::new(),
+impl Globals {
+    pub fn new() -> Globals {
+        Globals {
+            names: Vec::new(),
+            slots: HashMap::new(),
+            values: Vec::new(),
         }
     }
-    pub fn set_global(&mut self, s: String, value: Value) -> bool {
-        self.globals.insert(s, value).is_none()
+
+    // Returns the slot of the name, adding an undefined one the first time it is seen.
+    pub fn resolve(&mut self, name: &str) -> usize {
+        if let Some(slot) = self.slots.get(name) {
+            return *slot;
+        }
+        let slot = self.names.len();
+        self.names.push(name.to_string());
+        self.slots.insert(name.to_string(), slot);
+        self.values.push(None);
+        slot
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
     }
 
-    pub fn get_global(&mut self, s: String) -> Option<&Value> {
-        self.globals.get(&s)
+    pub fn get(&self, slot: usize) -> Option<&Value> {
+        self.values[slot].as_ref()
     }
 
-    pub fn delete_global(&mut self, s: String) {
-        self.globals.remove(&s);
+    // Defines or, as the REPL allows, redefines the variable.
+    pub fn define(&mut self, slot: usize, value: Value) {
+        self.values[slot] = Some(value);
     }
 
-    pub fn push(&mut self, obj: ObjType) {
-        self.objects.push(obj.clone());
-        match obj {
-            ObjType::String(r) => {
-                self.intern(r);
+    // Assigns to a variable that is already defined, failing otherwise.
+    pub fn set(&mut self, slot: usize, value: Value) -> bool {
+        match &mut self.values[slot] {
+            Some(v) => {
+                *v = value;
+                true
             }
-            _ => { /* ignore otherwise */ }
+            None => false,
         }
     }
-    pub fn intern(&mut self, obj: String) -> String {
-        match self.strings.get(&obj) {
+}
+
+// Every string the VM sees, so that equal strings are the same object. Buckets are
+// keyed by the cached hash and only compared by content on a collision.
+#[derive(Clone)]
+pub struct Strings {
+    table: HashMap<u32, Vec<Rc<LoxString>>>,
+}
+
+impl Strings {
+    pub fn new() -> Strings {
+        Strings { table: HashMap::new() }
+    }
+
+    pub fn intern(&mut self, chars: String) -> Rc<LoxString> {
+        let bucket = self.table.entry(LoxString::hash_of(&chars)).or_default();
+        match bucket.iter().find(|s| s.as_str() == chars) {
+            Some(s) => s.clone(),
             None => {
-                let item = obj.clone();
-                self.strings.insert(item);
-                obj
+                let s = Rc::new(LoxString::new(chars));
+                bucket.push(s.clone());
+                s
             }
-            Some(e) => e.clone(),
+        }
+    }
+
+    // Interns a string made outside the VM, keeping it if no equal one is known yet.
+    pub fn adopt(&mut self, s: &Rc<LoxString>) -> Rc<LoxString> {
+        let bucket = self.table.entry(s.hash()).or_default();
+        match bucket.iter().find(|known| known.as_str() == s.as_str()) {
+            Some(known) => known.clone(),
+            None => {
+                bucket.push(s.clone());
+                s.clone()
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Memory {
+    pub globals: Globals,
+    pub strings: Strings,
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory {
+            globals: Globals::new(),
+            strings: Strings::new(),
         }
     }
 }
@@ -1,6 +1,8 @@
+use std::cell::RefCell;
 use std::rc::Rc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use ast;
 use chunk::Chunk;
+use interpreter::Environment;
 use value::Value;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -70,54 +72,142 @@ impl std::fmt::Debug for Native {
     }
 }
 
-static COUNTER: AtomicUsize = AtomicUsize::new(0);
+// A function of the tree-walking interpreter, with the variables that were in scope
+// where it was declared.
+#[derive(Clone)]
+pub struct TreeFunction {
+    pub declaration: Rc<ast::Function>,
+    pub environment: Environment,
+}
 
-#[derive(Debug, Clone, PartialEq)]
+impl std::fmt::Debug for TreeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<fn {}/{}>", self.declaration.name.text, self.declaration.params.len())
+    }
+}
+
+// A variable captured by closures, shared by all of them. While the variable is still
+// on the stack the upvalue points at its slot; once the slot goes away the value is
+// moved into the upvalue.
+#[derive(Clone)]
 pub struct Upvalue {
-    pub id: usize,
-    pub value: Box<Value>,
-    pub is_local: bool
+    state: Rc<RefCell<UpvalueState>>,
+}
+
+enum UpvalueState {
+    Open(usize),
+    Closed(Value),
 }
 
 impl Upvalue {
-    pub fn local(value: Value) -> Upvalue {
+    pub fn open(slot: usize) -> Upvalue {
         Upvalue {
-            id: COUNTER.fetch_add(1, Ordering::Relaxed),
-            value: Box::new(value),
-            is_local: true
+            state: Rc::new(RefCell::new(UpvalueState::Open(slot)))
         }
     }
-    pub fn capture(value: Value) -> Upvalue {
-        Upvalue {
-            id: COUNTER.fetch_add(1, Ordering::Relaxed),
-            value: Box::new(value),
-            is_local: true
+
+    // the stack slot it points at, until it is closed
+    pub fn slot(&self) -> Option<usize> {
+        match *self.state.borrow() {
+            UpvalueState::Open(slot) => Some(slot),
+            UpvalueState::Closed(_) => None,
+        }
+    }
+
+    pub fn get(&self, stack: &[Value]) -> Value {
+        match &*self.state.borrow() {
+            UpvalueState::Open(slot) => stack[*slot].clone(),
+            UpvalueState::Closed(value) => value.clone(),
+        }
+    }
+
+    pub fn set(&self, stack: &mut [Value], value: Value) {
+        match &mut *self.state.borrow_mut() {
+            UpvalueState::Open(slot) => stack[*slot] = value,
+            UpvalueState::Closed(closed) => *closed = value,
+        }
+    }
+
+    pub fn close(&self, stack: &[Value]) {
+        let value = self.get(stack);
+        *self.state.borrow_mut() = UpvalueState::Closed(value);
+    }
+}
+
+// a closed upvalue may hold the closure that captured it
+impl std::fmt::Debug for Upvalue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.slot() {
+            Some(slot) => write!(f, "<upvalue slot {}>", slot),
+            None => write!(f, "<closed upvalue>"),
         }
     }
 }
 
+impl PartialEq for Upvalue {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+// An immutable string with its hash computed once. The VM only works with interned
+// strings (see `Strings::intern`), so two of them are equal exactly when they are
+// the same object.
+#[derive(Debug, PartialEq)]
+pub struct LoxString {
+    chars: String,
+    hash: u32,
+}
+
+impl LoxString {
+    pub fn new(chars: String) -> LoxString {
+        let hash = LoxString::hash_of(&chars);
+        LoxString { chars, hash }
+    }
+
+    // 32-bit FNV-1a, as in clox.
+    pub fn hash_of(chars: &str) -> u32 {
+        chars.bytes().fold(0x811c9dc5, |hash, b| (hash ^ b as u32).wrapping_mul(0x01000193))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.chars
+    }
+
+    pub fn hash(&self) -> u32 {
+        self.hash
+    }
+}
+
+impl std::fmt::Display for LoxString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.chars)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum ObjType {
-    String(String),
+    String(Rc<LoxString>),
     Function(Function),
     Closure(Closure),
     NativeFn(Native),
-    Upvalue(Upvalue)
+    TreeFunction(TreeFunction)
 }
 
 impl PartialEq for ObjType {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (ObjType::Upvalue(a), ObjType::Upvalue(b)) => 
-                a == b,
-            (ObjType::String(a), ObjType::String(b)) => 
-                a == b,
+            (ObjType::String(a), ObjType::String(b)) =>
+                Rc::ptr_eq(a, b),
             (ObjType::Function(Function{ arity: arity1, name: name1 , tpe: tpe1, ..}), 
              ObjType::Function(Function{ arity: arity2, name: name2 , tpe: tpe2, ..})) =>
                 arity1 == arity2 && name1 == name2 && tpe1 == tpe2,
-            (ObjType::Closure(Closure{ function: f1, upvalues: upvs1}), 
-             ObjType::Closure(Closure{ function: f2, upvalues: upvs2})) =>
-                f1.arity == f2.arity && f1.name == f2.name && f1.tpe == f2.tpe && upvs1 == upvs2,
+            // as in clox, a closure or a native is only equal to itself: values share
+            // the object they hold, so the same one is at the same address
+            (ObjType::Closure(_), ObjType::Closure(_))
+            | (ObjType::TreeFunction(_), ObjType::TreeFunction(_))
+            | (ObjType::NativeFn(_), ObjType::NativeFn(_)) =>
+                std::ptr::eq(self, other),
             _ => false
         }
     }
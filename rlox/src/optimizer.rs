@@ -0,0 +1,506 @@
+use std::rc::Rc;
+
+use chunk::Chunk;
+use chunk::OpCode;
+use object::Function;
+use object::LoxString;
+use object::ObjType;
+use value::Unpacked;
+use value::Value;
+
+// An instruction of the chunk being optimized. Jumps refer to the index of their
+// target instead of a byte distance, so that instructions can come and go.
+#[derive(Debug, Clone)]
+struct Instruction {
+    op: OpCode,
+    line: usize,
+    target: Option<usize>,
+}
+
+// Rewrites the chunk of the function, and of every function nested in it, until no
+// pass finds anything left to change. Every instruction keeps the line it came from.
+pub fn optimize(function: &mut Function) {
+    for value in function.chunk.values.iter_mut() {
+        let mut nested = match value.as_object() {
+            Some(ObjType::Function(nested)) => nested.clone(),
+            _ => continue,
+        };
+        optimize(&mut nested);
+        *value = Value::object(ObjType::Function(nested));
+    }
+
+    let chunk = &mut function.chunk;
+    let mut code = decode(chunk);
+    loop {
+        let mut changed = peephole(&mut code, &mut chunk.values);
+        changed |= thread_jumps(&mut code);
+        changed |= remove_empty_jumps(&mut code);
+        changed |= remove_unreachable(&mut code);
+        if !changed {
+            break;
+        }
+    }
+    let values = compact_constants(&mut code, std::mem::take(&mut chunk.values));
+    *chunk = encode(&code, values);
+}
+
+// Drops the constants no instruction refers to any more, such as the operands of
+// folded expressions.
+fn compact_constants(code: &mut [Instruction], values: Vec<Value>) -> Vec<Value> {
+    fn index(op: &mut OpCode) -> Option<&mut usize> {
+        match op {
+            OpCode::Constant { index } | OpCode::Closure { index, .. } => Some(index),
+            _ => None,
+        }
+    }
+
+    let mut mapping = vec![None; values.len()];
+    let mut compacted = Vec::new();
+    for instruction in code.iter_mut() {
+        if let Some(index) = index(&mut instruction.op) {
+            *index = *mapping[*index].get_or_insert_with(|| {
+                compacted.push(values[*index].clone());
+                compacted.len() - 1
+            });
+        }
+    }
+    compacted
+}
+
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let mut offsets = Vec::new();
+    let mut decoded = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (op, next) = chunk.decode(offset);
+        let target = match op {
+            OpCode::Jump { jump } | OpCode::JumpIfFalse { jump } => Some(next + jump),
+            OpCode::Loop { jump } => Some(next - jump),
+            _ => None,
+        };
+        offsets.push(offset);
+        decoded.push((op, chunk.line_at(offset), target));
+        offset = next;
+    }
+    offsets.push(offset);
+
+    decoded.into_iter()
+        .map(|(op, line, target)| Instruction {
+            op,
+            line,
+            target: target.map(|t| offsets.binary_search(&t).expect("Jump into the middle of an instruction")),
+        })
+        .collect()
+}
+
+fn encode(code: &[Instruction], values: Vec<Value>) -> Chunk {
+    let offsets = offsets(code);
+    let mut chunk = Chunk::from_parts(Vec::new(), values, Vec::new());
+    for (i, instruction) in code.iter().enumerate() {
+        let op = match (&instruction.op, instruction.target) {
+            (OpCode::Jump { .. }, Some(t)) => OpCode::Jump { jump: offsets[t] - offsets[i + 1] },
+            (OpCode::JumpIfFalse { .. }, Some(t)) => OpCode::JumpIfFalse { jump: offsets[t] - offsets[i + 1] },
+            (OpCode::Loop { .. }, Some(t)) => OpCode::Loop { jump: offsets[i + 1] - offsets[t] },
+            (op, _) => op.clone(),
+        };
+        chunk.write(op, instruction.line);
+    }
+    chunk
+}
+
+// Byte offset of every instruction, and of the end of the code.
+fn offsets(code: &[Instruction]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(code.len() + 1);
+    let mut offset = 0;
+    for instruction in code.iter() {
+        offsets.push(offset);
+        offset += instruction.op.size();
+    }
+    offsets.push(offset);
+    offsets
+}
+
+fn targeted(code: &[Instruction]) -> Vec<bool> {
+    let mut targeted = vec![false; code.len() + 1];
+    for instruction in code.iter() {
+        if let Some(t) = instruction.target {
+            targeted[t] = true;
+        }
+    }
+    targeted
+}
+
+// Drops the instructions that are not kept. A jump to a dropped instruction lands on
+// the next one that is kept.
+fn retain(code: &mut Vec<Instruction>, keep: &[bool]) -> bool {
+    let mut mapping = Vec::with_capacity(code.len() + 1);
+    let mut kept = 0;
+    for &k in keep.iter() {
+        mapping.push(kept);
+        if k {
+            kept += 1;
+        }
+    }
+    mapping.push(kept);
+    if kept == code.len() {
+        return false;
+    }
+
+    let mut i = 0;
+    code.retain(|_| {
+        i += 1;
+        keep[i - 1]
+    });
+    for instruction in code.iter_mut() {
+        instruction.target = instruction.target.map(|t| mapping[t]);
+    }
+    true
+}
+
+// Folds operators applied to literals and drops literals that are popped right away,
+// looking at the tail of the rewritten code after every instruction.
+fn peephole(code: &mut Vec<Instruction>, values: &mut Vec<Value>) -> bool {
+    let targeted = targeted(code);
+    let mut mapping = Vec::with_capacity(code.len() + 1);
+    let mut out: Vec<Instruction> = Vec::with_capacity(code.len());
+    // whether a jump lands on each rewritten instruction; only the first instruction
+    // of a folded sequence may be a jump target
+    let mut landing: Vec<bool> = Vec::with_capacity(code.len());
+    // a jump target that was dropped, so the next instruction takes its place
+    let mut carried = false;
+    let mut changed = false;
+
+    for (i, instruction) in code.iter().enumerate() {
+        mapping.push(out.len());
+        out.push(instruction.clone());
+        landing.push(targeted[i] || carried);
+        carried = false;
+        let n = out.len();
+
+        if n >= 3 && !landing[n - 2] && !landing[n - 1] {
+            let folded = match (literal(&out[n - 3].op, values), literal(&out[n - 2].op, values)) {
+                (Some(a), Some(b)) => fold_binary(&out[n - 1].op, a, b),
+                _ => None,
+            };
+            if let Some(result) = folded {
+                let op = literal_op(result, values);
+                let size = out[n - 3..].iter().map(|i| i.op.size()).sum::<usize>();
+                if op.size() <= size {
+                    out.truncate(n - 3);
+                    out.push(Instruction { op, line: instruction.line, target: None });
+                    landing.truncate(n - 2);
+                    changed = true;
+                    continue;
+                }
+            }
+        }
+
+        if n >= 2 && !landing[n - 1] {
+            let folded = match literal(&out[n - 2].op, values) {
+                Some(a) => fold_unary(&out[n - 1].op, a),
+                None => None,
+            };
+            if let Some(result) = folded {
+                let op = literal_op(result, values);
+                if op.size() <= out[n - 2].op.size() + out[n - 1].op.size() {
+                    out.truncate(n - 2);
+                    out.push(Instruction { op, line: instruction.line, target: None });
+                    landing.truncate(n - 1);
+                    changed = true;
+                    continue;
+                }
+            }
+            if out[n - 1].op == OpCode::Pop && literal(&out[n - 2].op, values).is_some() {
+                carried = landing[n - 2];
+                out.truncate(n - 2);
+                landing.truncate(n - 2);
+                changed = true;
+            }
+        }
+    }
+    mapping.push(out.len());
+
+    for instruction in out.iter_mut() {
+        instruction.target = instruction.target.map(|t| mapping[t]);
+    }
+    *code = out;
+    changed
+}
+
+// The value an instruction pushes, if it pushes a literal.
+fn literal(op: &OpCode, values: &[Value]) -> Option<Value> {
+    match op {
+        OpCode::Nil => Some(Value::nil()),
+        OpCode::True => Some(Value::boolean(true)),
+        OpCode::False => Some(Value::boolean(false)),
+        OpCode::Constant { index } => match values[*index].unpack() {
+            Unpacked::Number(_) | Unpacked::Object(ObjType::String(_)) => Some(values[*index].clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The instruction that pushes a literal, reusing an equal constant if there is one.
+fn literal_op(value: Value, values: &mut Vec<Value>) -> OpCode {
+    let same = |v: &Value| match (v.unpack(), value.unpack()) {
+        // 0 and -0 are equal, but print differently
+        (Unpacked::Number(a), Unpacked::Number(b)) => a.to_bits() == b.to_bits(),
+        (Unpacked::Object(ObjType::String(a)), Unpacked::Object(ObjType::String(b))) => a == b,
+        _ => false,
+    };
+    match value.unpack() {
+        Unpacked::Nil => OpCode::Nil,
+        Unpacked::Bool(true) => OpCode::True,
+        Unpacked::Bool(false) => OpCode::False,
+        _ => match values.iter().position(same) {
+            Some(index) => OpCode::Constant { index },
+            None => {
+                values.push(value);
+                OpCode::Constant { index: values.len() - 1 }
+            }
+        },
+    }
+}
+
+// Only the operations that cannot fail at runtime are folded, so errors are still
+// reported when the program runs.
+fn fold_binary(op: &OpCode, left: Value, right: Value) -> Option<Value> {
+    match (op, left.unpack(), right.unpack()) {
+        // folded strings are not interned yet, so compare their contents
+        (OpCode::Equal, Unpacked::Object(ObjType::String(a)), Unpacked::Object(ObjType::String(b))) => {
+            Some(Value::boolean(a == b))
+        }
+        (OpCode::Equal, _, _) => Some(Value::boolean(left == right)),
+        (OpCode::Add, Unpacked::Object(ObjType::String(a)), Unpacked::Object(ObjType::String(b))) => {
+            Some(Value::object(ObjType::String(Rc::new(LoxString::new(format!("{}{}", a, b))))))
+        }
+        (op, Unpacked::Number(a), Unpacked::Number(b)) => match op {
+            OpCode::Add => Some(Value::number(a + b)),
+            OpCode::Subtract => Some(Value::number(a - b)),
+            OpCode::Multiply => Some(Value::number(a * b)),
+            OpCode::Divide => Some(Value::number(a / b)),
+            OpCode::Greater => Some(Value::boolean(a > b)),
+            OpCode::Less => Some(Value::boolean(a < b)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn fold_unary(op: &OpCode, a: Value) -> Option<Value> {
+    match (op, a.unpack()) {
+        (OpCode::Negate, Unpacked::Number(n)) => Some(Value::number(-n)),
+        (OpCode::Not, Unpacked::Nil) | (OpCode::Not, Unpacked::Bool(false)) => Some(Value::boolean(true)),
+        (OpCode::Not, _) => Some(Value::boolean(false)),
+        _ => None,
+    }
+}
+
+// Points jumps that land on an unconditional jump straight at its target. A conditional
+// jump landing on another one also takes it, as the value it tests is still the same.
+fn thread_jumps(code: &mut [Instruction]) -> bool {
+    let offsets = offsets(code);
+    let mut changed = false;
+    for i in 0..code.len() {
+        let target = match code[i].target {
+            Some(target) => target,
+            None => continue,
+        };
+        let conditional = matches!(code[i].op, OpCode::JumpIfFalse { .. });
+        let mut t = target;
+        for _ in 0..code.len() {
+            match (&code.get(t).map(|next| &next.op), code.get(t).and_then(|next| next.target)) {
+                (Some(OpCode::Jump { .. }), Some(next)) | (Some(OpCode::Loop { .. }), Some(next)) => t = next,
+                (Some(OpCode::JumpIfFalse { .. }), Some(next)) if conditional => t = next,
+                _ => break,
+            }
+        }
+        // there is no backward conditional jump, and distances must fit two bytes
+        let distance = (offsets[t] as isize - offsets[i + 1] as isize).unsigned_abs();
+        if t == target || (conditional && t <= i) || distance > u16::MAX as usize {
+            continue;
+        }
+        if !conditional {
+            code[i].op = if t > i { OpCode::Jump { jump: 0 } } else { OpCode::Loop { jump: 0 } };
+        }
+        code[i].target = Some(t);
+        changed = true;
+    }
+    changed
+}
+
+// Jumps to the very next instruction do nothing: `JUMP_IF_FALSE` only peeks at its operand.
+fn remove_empty_jumps(code: &mut Vec<Instruction>) -> bool {
+    let keep = code.iter().enumerate()
+        .map(|(i, instruction)| match instruction.op {
+            OpCode::Jump { .. } | OpCode::JumpIfFalse { .. } => instruction.target != Some(i + 1),
+            _ => true,
+        })
+        .collect::<Vec<_>>();
+    retain(code, &keep)
+}
+
+// Drops the code no path reaches, such as what follows a `return`.
+fn remove_unreachable(code: &mut Vec<Instruction>) -> bool {
+    let mut reached = vec![false; code.len()];
+    let mut pending = vec![0];
+    while let Some(i) = pending.pop() {
+        if i >= code.len() || reached[i] {
+            continue;
+        }
+        reached[i] = true;
+        match (&code[i].op, code[i].target) {
+            (OpCode::Return, _) => {}
+            (OpCode::Jump { .. }, Some(t)) | (OpCode::Loop { .. }, Some(t)) => pending.push(t),
+            (OpCode::JumpIfFalse { .. }, Some(t)) => {
+                pending.push(t);
+                pending.push(i + 1);
+            }
+            _ => pending.push(i + 1),
+        }
+    }
+    retain(code, &reached)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(ops: &[OpCode], values: &[Value]) -> Function {
+        let mut function = Function::main();
+        function.chunk = Chunk::from_parts(Vec::new(), values.to_vec(), Vec::new());
+        for (line, op) in ops.iter().enumerate() {
+            function.chunk.write(op.clone(), line + 1);
+        }
+        function
+    }
+
+    fn ops(chunk: &Chunk) -> Vec<OpCode> {
+        decode(chunk).into_iter().map(|instruction| instruction.op).collect()
+    }
+
+    fn constants(chunk: &Chunk) -> Vec<String> {
+        chunk.values.iter().map(Value::fmt).collect()
+    }
+
+    fn jump(op: OpCode, target: usize) -> Instruction {
+        Instruction { op, line: 1, target: Some(target) }
+    }
+
+    fn plain(op: OpCode) -> Instruction {
+        Instruction { op, line: 1, target: None }
+    }
+
+    fn targets(code: &[Instruction]) -> Vec<Option<usize>> {
+        code.iter().map(|instruction| instruction.target).collect()
+    }
+
+    #[test]
+    fn folds_constant_expressions() {
+        // print -(1 + 2) * 4;
+        let mut function = script(
+            &[
+                OpCode::Constant { index: 0 },
+                OpCode::Constant { index: 1 },
+                OpCode::Add,
+                OpCode::Negate,
+                OpCode::Constant { index: 2 },
+                OpCode::Multiply,
+                OpCode::Print,
+                OpCode::Nil,
+                OpCode::Return,
+            ],
+            &[Value::number(1.0), Value::number(2.0), Value::number(4.0)],
+        );
+        optimize(&mut function);
+        assert_eq!(ops(&function.chunk), vec![OpCode::Constant { index: 0 }, OpCode::Print, OpCode::Nil, OpCode::Return]);
+        assert_eq!(constants(&function.chunk), vec!["-12"]);
+        // the folded value takes the line of the last instruction it replaces
+        assert_eq!(function.chunk.line_at(0), 6);
+    }
+
+    #[test]
+    fn keeps_operations_that_fail_at_runtime() {
+        // print -"a"; print 1 + nil;
+        let code = [
+            OpCode::Constant { index: 0 },
+            OpCode::Negate,
+            OpCode::Print,
+            OpCode::Constant { index: 1 },
+            OpCode::Nil,
+            OpCode::Add,
+            OpCode::Print,
+            OpCode::Nil,
+            OpCode::Return,
+        ];
+        let mut function = script(&code, &[Value::object(ObjType::String(Rc::new(LoxString::new(String::from("a"))))), Value::number(1.0)]);
+        optimize(&mut function);
+        assert_eq!(ops(&function.chunk), code.to_vec());
+    }
+
+    #[test]
+    fn threads_jumps_to_jumps() {
+        let mut code = vec![
+            plain(OpCode::True),
+            jump(OpCode::JumpIfFalse { jump: 0 }, 4),
+            jump(OpCode::Jump { jump: 0 }, 3),
+            jump(OpCode::Jump { jump: 0 }, 6),
+            jump(OpCode::JumpIfFalse { jump: 0 }, 6),
+            plain(OpCode::Pop),
+            plain(OpCode::Nil),
+            plain(OpCode::Return),
+        ];
+        assert!(thread_jumps(&mut code));
+        // the first conditional jump lands on a second one, so it takes it as well
+        assert_eq!(targets(&code), vec![None, Some(6), Some(6), Some(6), Some(6), None, None, None]);
+        assert!(!thread_jumps(&mut code));
+    }
+
+    #[test]
+    fn removes_jumps_to_the_next_instruction() {
+        let mut code = vec![
+            plain(OpCode::True),
+            jump(OpCode::JumpIfFalse { jump: 0 }, 2),
+            plain(OpCode::Pop),
+            jump(OpCode::Jump { jump: 0 }, 4),
+            plain(OpCode::Nil),
+            plain(OpCode::Return),
+        ];
+        assert!(remove_empty_jumps(&mut code));
+        let left = code.iter().map(|instruction| instruction.op.clone()).collect::<Vec<_>>();
+        assert_eq!(left, vec![OpCode::True, OpCode::Pop, OpCode::Nil, OpCode::Return]);
+    }
+
+    #[test]
+    fn removes_unreachable_code() {
+        // the code after the return is only reached by the jump over it
+        let mut code = vec![
+            jump(OpCode::Jump { jump: 0 }, 4),
+            plain(OpCode::Nil),
+            plain(OpCode::Return),
+            plain(OpCode::Print),
+            plain(OpCode::Nil),
+            plain(OpCode::Return),
+            plain(OpCode::Pop),
+        ];
+        assert!(remove_unreachable(&mut code));
+        let left = code.iter().map(|instruction| instruction.op.clone()).collect::<Vec<_>>();
+        assert_eq!(left, vec![OpCode::Jump { jump: 0 }, OpCode::Nil, OpCode::Return]);
+        assert_eq!(targets(&code), vec![Some(1), None, None]);
+    }
+
+    #[test]
+    fn compacts_constants_in_order_of_use() {
+        let mut code = vec![
+            plain(OpCode::Constant { index: 2 }),
+            plain(OpCode::Constant { index: 0 }),
+            plain(OpCode::Constant { index: 2 }),
+        ];
+        let values = vec![Value::number(0.0), Value::number(1.0), Value::number(2.0)];
+        let values = compact_constants(&mut code, values);
+        assert_eq!(values.iter().map(Value::fmt).collect::<Vec<_>>(), vec!["2", "0"]);
+        let left = code.iter().map(|instruction| instruction.op.clone()).collect::<Vec<_>>();
+        assert_eq!(left, vec![OpCode::Constant { index: 0 }, OpCode::Constant { index: 1 }, OpCode::Constant { index: 0 }]);
+    }
+}
@@ -0,0 +1,820 @@
+use std::cmp::PartialOrd;
+use std::rc::Rc;
+
+use ast::Decl;
+use ast::DeclKind;
+use ast::Expr;
+use ast::ExprKind;
+use ast::Function;
+use ast::Program;
+use ast::Span;
+use ast::Stmt;
+use ast::StmtKind;
+
+use diagnostic::Diagnostic;
+
+use limits::Limits;
+
+use memory::Memory;
+
+use scanner::Scanner;
+use scanner::Token;
+use scanner::TokenType;
+
+use symbols::Symbols;
+
+#[derive(PartialEq, PartialOrd)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl Precedence {
+    fn from_index(index: usize) -> Option<Precedence> {
+        match index {
+            0 => Some(Precedence::None),
+            1 => Some(Precedence::Assignment),
+            2 => Some(Precedence::Or),
+            3 => Some(Precedence::And),
+            4 => Some(Precedence::Equality),
+            5 => Some(Precedence::Comparison),
+            6 => Some(Precedence::Term),
+            7 => Some(Precedence::Factor),
+            8 => Some(Precedence::Unary),
+            9 => Some(Precedence::Call),
+            10 => Some(Precedence::Primary),
+            _ => None,
+        }
+    }
+}
+
+// A place in the source, for putting the errors of the parser and of the code
+// generator in order. The end of a declaration comes before a token that starts
+// right there, and the end of a nested declaration before that of the one around it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    offset: usize,
+    rank: isize,
+}
+
+impl Position {
+    const START: Position = Position {
+        offset: 0,
+        rank: isize::MIN,
+    };
+
+    pub const END: Position = Position {
+        offset: usize::MAX,
+        rank: 0,
+    };
+
+    pub fn at(token: &Token) -> Position {
+        Position {
+            offset: token.start,
+            rank: 0,
+        }
+    }
+
+    // Before what is found at a token: the parser reports the tokens the scanner
+    // rejects as it looks ahead, before the token ahead of them is made sense of.
+    fn before(token: &Token) -> Position {
+        Position {
+            offset: token.start,
+            rank: -1,
+        }
+    }
+
+    // After what is found at a token.
+    pub fn past(token: &Token) -> Position {
+        Position {
+            offset: token.start,
+            rank: 1,
+        }
+    }
+
+    // The end of a declaration nested `depth` deep, once it is finished.
+    pub fn after(span: &Span, depth: usize) -> Position {
+        Position {
+            offset: span.end(),
+            rank: -2 * depth as isize,
+        }
+    }
+
+    // The end of a declaration, before it is finished.
+    pub fn within(span: &Span, depth: usize) -> Position {
+        Position {
+            offset: span.end(),
+            rank: -2 * depth as isize - 1,
+        }
+    }
+}
+
+type PrefixRule = fn(&mut Parser, bool) -> ExprKind;
+
+type InfixRule = fn(&mut Parser, Expr) -> ExprKind;
+
+struct ParseRule {
+    prefix: PrefixRule,
+    infix: InfixRule,
+    precedence: Precedence,
+}
+
+// Parses the source into a program, and holds what compiling it produces besides
+// code: diagnostics, warnings and symbols.
+pub struct Parser {
+    scanner: Scanner,
+    current: Token,
+    previous: Token,
+    panic_mode: bool,
+    // number of enclosing `{ }` blocks, so that recovery can stop at a `}`
+    blocks: usize,
+    // number of enclosing declarations
+    depth: usize,
+    diagnostics: Vec<Diagnostic>,
+    // for each diagnostic, where it belongs in the source: at its token, or for one
+    // found at the token after a declaration, at the end of the declaration
+    found: Vec<Position>,
+    // for each diagnostic, where the parser found its footing again; errors before
+    // it are consequences of the first
+    recovered: Vec<Position>,
+    limits: Limits,
+    // global slots and interned strings, shared with the VM that runs the code
+    memory: Memory,
+    symbols: Symbols,
+    // lint findings, which do not stop the code from compiling
+    warnings: Vec<Diagnostic>,
+}
+
+impl ParseRule {
+    fn new(prefix: PrefixRule, infix: InfixRule, precedence: Precedence) -> ParseRule {
+        ParseRule {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+
+    fn of_token(tpe: &TokenType) -> ParseRule {
+        match tpe {
+            TokenType::LeftParen => ParseRule::new(Parser::grouping, Parser::call, Precedence::Call),
+            TokenType::Minus => ParseRule::new(Parser::unary, Parser::binary, Precedence::Term),
+            TokenType::Plus => ParseRule::new(Parser::err, Parser::binary, Precedence::Term),
+            TokenType::Slash => ParseRule::new(Parser::err, Parser::binary, Precedence::Factor),
+            TokenType::Star => ParseRule::new(Parser::err, Parser::binary, Precedence::Factor),
+            TokenType::Bang => ParseRule::new(Parser::unary, Parser::invalid, Precedence::None),
+            TokenType::BangEqual => {
+                ParseRule::new(Parser::err, Parser::binary, Precedence::Equality)
+            }
+            TokenType::EqualEqual => {
+                ParseRule::new(Parser::err, Parser::binary, Precedence::Equality)
+            }
+            TokenType::Greater => {
+                ParseRule::new(Parser::err, Parser::binary, Precedence::Comparison)
+            }
+            TokenType::GreaterEqual => {
+                ParseRule::new(Parser::err, Parser::binary, Precedence::Comparison)
+            }
+            TokenType::Less => ParseRule::new(Parser::err, Parser::binary, Precedence::Comparison),
+            TokenType::LessEqual => {
+                ParseRule::new(Parser::err, Parser::binary, Precedence::Comparison)
+            }
+            TokenType::Identifier => {
+                ParseRule::new(Parser::variable, Parser::invalid, Precedence::None)
+            }
+            TokenType::String => ParseRule::new(Parser::string, Parser::invalid, Precedence::None),
+            TokenType::Number => ParseRule::new(Parser::number, Parser::invalid, Precedence::None),
+            TokenType::And => ParseRule::new(Parser::err, Parser::logical, Precedence::And),
+            TokenType::False => ParseRule::new(Parser::literal, Parser::invalid, Precedence::None),
+            TokenType::Nil => ParseRule::new(Parser::literal, Parser::invalid, Precedence::None),
+            TokenType::Or => ParseRule::new(Parser::err, Parser::logical, Precedence::Or),
+            TokenType::True => ParseRule::new(Parser::literal, Parser::invalid, Precedence::None),
+            _ => ParseRule::new(Parser::err, Parser::invalid, Precedence::None),
+        }
+    }
+}
+
+impl Parser {
+    pub fn new(source: String, limits: Limits, memory: Memory) -> Parser {
+        Parser {
+            scanner: Scanner::new(source),
+            current: Token {
+                tpe: TokenType::Start,
+                text: String::from(""),
+                line: 0,
+                start: 0,
+                length: 0,
+            },
+            previous: Token {
+                tpe: TokenType::Start,
+                text: String::from(""),
+                line: 0,
+                start: 0,
+                length: 0,
+            },
+            panic_mode: false,
+            blocks: 0,
+            depth: 0,
+            diagnostics: Vec::new(),
+            found: Vec::new(),
+            recovered: Vec::new(),
+            limits,
+            memory,
+            symbols: Symbols::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn parse(&mut self) -> Program {
+        self.advance();
+        let mut declarations = Vec::new();
+        while !self.matches(TokenType::Eof) {
+            declarations.push(self.declaration());
+        }
+        Program {
+            declarations,
+            eof: self.previous.clone(),
+        }
+    }
+
+    // Parses source that is a single expression, for the debugger to evaluate.
+    pub fn parse_expression(&mut self) -> Expr {
+        self.advance();
+        let expr = self.expression();
+        self.consume(TokenType::Eof, "Expect end of expression.");
+        expr
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn memory(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    // Hands the memory back, with the globals and strings of this source added.
+    pub fn take_memory(&mut self) -> Memory {
+        std::mem::replace(&mut self.memory, Memory::new())
+    }
+
+    pub fn had_error(&self) -> bool {
+        !self.diagnostics.is_empty()
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    // Adds the errors found generating code, each with where it belongs in the
+    // source and where the code after it makes sense again, in order with the syntax
+    // errors. As when both were found in one pass, an error that follows another
+    // before that is left out.
+    pub fn add_errors(&mut self, mut errors: Vec<(Diagnostic, Position, Position)>) {
+        let mut syntax_errors = self
+            .diagnostics
+            .drain(..)
+            .zip(self.found.drain(..))
+            .zip(self.recovered.drain(..))
+            .map(|((diagnostic, found), recovered)| (diagnostic, found, recovered))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .peekable();
+        errors.sort_by_key(|(_, found, _)| *found);
+        let mut errors = errors.into_iter().peekable();
+
+        // the syntax errors are already left out after each other
+        let mut until = Position::START;
+        let mut until_syntax = Position::START;
+        loop {
+            let syntax = match (syntax_errors.peek(), errors.peek()) {
+                (Some(a), Some(b)) => a.1 <= b.1,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            let (diagnostic, found, recovered) = if syntax {
+                syntax_errors.next()
+            } else {
+                errors.next()
+            }
+            .unwrap();
+            if found < if syntax { until_syntax } else { until } {
+                continue;
+            }
+            until = until.max(recovered);
+            if !syntax {
+                until_syntax = until_syntax.max(recovered);
+            }
+            self.diagnostics.push(diagnostic);
+            self.found.push(found);
+            self.recovered.push(recovered);
+        }
+    }
+
+    pub fn warn(&mut self, diagnostic: Diagnostic) {
+        self.warnings.push(diagnostic);
+    }
+
+    pub fn warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    pub fn symbols(&mut self) -> &mut Symbols {
+        &mut self.symbols
+    }
+
+    // Hands over where the names of the source are declared and used.
+    pub fn take_symbols(&mut self) -> Symbols {
+        std::mem::replace(&mut self.symbols, Symbols::new())
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.current.clone();
+
+        loop {
+            self.current = self.scanner.scan();
+            if self.current.tpe != TokenType::Error {
+                break;
+            }
+
+            if !self.panic_mode {
+                let found = Position::before(&self.previous);
+                self.report(self.current.clone(), &self.current.text.clone(), found);
+            }
+        }
+    }
+
+    fn consume(&mut self, tpe: TokenType, message: &str) {
+        if self.current.tpe == tpe {
+            self.advance();
+            return;
+        }
+
+        self.error_at_current(message);
+    }
+
+    fn matches(&mut self, tpe: TokenType) -> bool {
+        if !self.check(tpe) {
+            false
+        } else {
+            self.advance();
+            true
+        }
+    }
+
+    fn check(&mut self, tpe: TokenType) -> bool {
+        self.current.tpe == tpe
+    }
+
+    fn span(&self, first: Token) -> Span {
+        Span::new(first, self.previous.clone())
+    }
+
+    fn grouping(&mut self, _can_assign: bool) -> ExprKind {
+        let expr = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        ExprKind::Grouping(Box::new(expr))
+    }
+
+    fn number(&mut self, _can_assign: bool) -> ExprKind {
+        ExprKind::Number(self.previous.text.parse::<f64>().unwrap())
+    }
+
+    fn string(&mut self, _can_assign: bool) -> ExprKind {
+        let text = &self.previous.text;
+        ExprKind::String(text[1..text.len() - 1].to_string())
+    }
+
+    fn literal(&mut self, _can_assign: bool) -> ExprKind {
+        match self.previous.tpe {
+            TokenType::False => ExprKind::False,
+            TokenType::True => ExprKind::True,
+            _ => ExprKind::Nil,
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) -> ExprKind {
+        let name = self.previous.clone();
+        if can_assign && self.matches(TokenType::Equal) {
+            let value = self.expression();
+            ExprKind::Assign {
+                name,
+                value: Box::new(value),
+            }
+        } else {
+            ExprKind::Variable(name)
+        }
+    }
+
+    fn unary(&mut self, _can_assign: bool) -> ExprKind {
+        let operator = self.previous.clone();
+        let operand = self.parse_precedence(Precedence::Unary);
+        ExprKind::Unary {
+            operator,
+            operand: Box::new(operand),
+        }
+    }
+
+    fn binary(&mut self, left: Expr) -> ExprKind {
+        let operator = self.previous.clone();
+        let rule = ParseRule::of_token(&operator.tpe);
+        let next_rule = rule.precedence as usize + 1;
+        let next_prec = Precedence::from_index(next_rule).expect("No match for given index");
+        let right = self.parse_precedence(next_prec);
+        ExprKind::Binary {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn logical(&mut self, left: Expr) -> ExprKind {
+        let operator = self.previous.clone();
+        let precedence = ParseRule::of_token(&operator.tpe).precedence;
+        let right = self.parse_precedence(precedence);
+        ExprKind::Logical {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn call(&mut self, callee: Expr) -> ExprKind {
+        let mut arguments = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                arguments.push(self.expression());
+                if arguments.len() > self.limits.arguments {
+                    let message = format!("Can't have more than {} arguments.", self.limits.arguments);
+                    self.error(&message);
+                }
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        ExprKind::Call {
+            callee: Box::new(callee),
+            arguments,
+        }
+    }
+
+    fn err(&mut self, _can_assign: bool) -> ExprKind {
+        self.error("Expect expression.");
+        ExprKind::Invalid
+    }
+
+    // Tokens that cannot follow an operand are never parsed as infix operators.
+    fn invalid(&mut self, _left: Expr) -> ExprKind {
+        ExprKind::Invalid
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Expr {
+        self.advance();
+        let first = self.previous.clone();
+        let prefix_rule = ParseRule::of_token(&first.tpe).prefix;
+
+        let can_assign = precedence <= Precedence::Assignment;
+        let kind = prefix_rule(self, can_assign);
+        let mut expr = Expr {
+            kind,
+            span: self.span(first.clone()),
+        };
+
+        while precedence <= ParseRule::of_token(&self.current.tpe).precedence {
+            self.advance();
+            let infix_rule = ParseRule::of_token(&self.previous.tpe).infix;
+            let kind = infix_rule(self, expr);
+            expr = Expr {
+                kind,
+                span: self.span(first.clone()),
+            };
+        }
+
+        if can_assign && self.matches(TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
+        expr
+    }
+
+    fn expression(&mut self) -> Expr {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn block(&mut self) -> Vec<Decl> {
+        self.blocks += 1;
+        let mut declarations = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            declarations.push(self.declaration());
+        }
+        self.blocks -= 1;
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+        declarations
+    }
+
+    fn function(&mut self) -> Function {
+        let name = self.previous.clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+
+        let mut params = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if params.len() >= self.limits.arguments {
+                    let message = format!("Can't have more than {} parameters.", self.limits.arguments);
+                    self.error_at_current(&message);
+                }
+                self.consume(TokenType::Identifier, "Expect parameter name.");
+                params.push(self.previous.clone());
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        // the code generator rejects a parameter list with a repeated name or one too
+        // many, and what follows it is made sense of as after a syntax error
+        let rejected = params.len() >= self.limits.locals
+            || params
+                .iter()
+                .enumerate()
+                .any(|(i, p)| params[..i].iter().any(|q| q.text == p.text));
+        if self.panic_mode || rejected {
+            // skip the rest of a broken parameter list, but still parse the body
+            while !self.check(TokenType::LeftBrace)
+                && !self.check(TokenType::RightBrace)
+                && !self.check(TokenType::Eof)
+            {
+                self.advance();
+            }
+            if self.check(TokenType::LeftBrace) {
+                self.recover();
+            } else {
+                self.panic_mode = true;
+            }
+        }
+        let open = self.current.clone();
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        let body = self.block();
+
+        Function {
+            name,
+            params,
+            open,
+            body,
+        }
+    }
+
+    fn fun_declaration(&mut self) -> DeclKind {
+        self.consume(TokenType::Identifier, "Expect function name.");
+        DeclKind::Fun(Rc::new(self.function()))
+    }
+
+    fn var_declaration(&mut self) -> DeclKind {
+        self.consume(TokenType::Identifier, "Expect variable name.");
+        let name = self.previous.clone();
+
+        let initializer = if self.matches(TokenType::Equal) {
+            Some(Box::new(self.expression()))
+        } else {
+            None
+        };
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        DeclKind::Var { name, initializer }
+    }
+
+    fn expression_statement(&mut self) -> StmtKind {
+        let expr = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        StmtKind::Expression(expr)
+    }
+
+    fn for_statement(&mut self) -> StmtKind {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        let first = self.current.clone();
+        let initializer = if self.matches(TokenType::Semicolon) {
+            None
+        } else {
+            let kind = if self.matches(TokenType::Var) {
+                self.var_declaration()
+            } else {
+                let kind = self.expression_statement();
+                DeclKind::Statement(Box::new(Stmt {
+                    kind,
+                    span: self.span(first.clone()),
+                }))
+            };
+            Some(Box::new(Decl {
+                kind,
+                span: self.span(first),
+            }))
+        };
+
+        let mut condition = None;
+        if !self.matches(TokenType::Semicolon) {
+            condition = Some(Box::new(self.expression()));
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+        }
+        let semicolon = self.previous.clone();
+
+        let mut increment = None;
+        if !self.matches(TokenType::RightParen) {
+            increment = Some(Box::new(self.expression()));
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+        }
+        let paren = self.previous.clone();
+
+        StmtKind::For {
+            initializer,
+            condition,
+            semicolon,
+            increment,
+            paren,
+            body: Box::new(self.statement()),
+        }
+    }
+
+    fn if_statement(&mut self) -> StmtKind {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        let condition = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        let paren = self.previous.clone();
+
+        let then = Box::new(self.statement());
+        let otherwise = if self.matches(TokenType::Else) {
+            Some(Box::new(self.statement()))
+        } else {
+            None
+        };
+        StmtKind::If {
+            condition,
+            paren,
+            then,
+            otherwise,
+        }
+    }
+
+    fn print_statement(&mut self) -> StmtKind {
+        let expr = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        StmtKind::Print(expr)
+    }
+
+    fn return_statement(&mut self) -> StmtKind {
+        if self.matches(TokenType::Semicolon) {
+            StmtKind::Return(None)
+        } else {
+            let value = self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            StmtKind::Return(Some(value))
+        }
+    }
+
+    fn while_statement(&mut self) -> StmtKind {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        let condition = self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        let paren = self.previous.clone();
+
+        StmtKind::While {
+            condition,
+            paren,
+            body: Box::new(self.statement()),
+        }
+    }
+
+    fn synchronize(&mut self) {
+        self.recover();
+
+        // braces opened by the broken code: the statements inside them are skipped
+        // as a whole, as they would be compiled in the wrong scope otherwise
+        let mut skipped = if self.previous.tpe == TokenType::LeftBrace { 1 } else { 0 };
+
+        while self.current.tpe != TokenType::Eof {
+            if skipped == 0 && self.previous.tpe == TokenType::Semicolon {
+                return;
+            }
+            match self.current.tpe {
+                TokenType::LeftBrace => {
+                    skipped += 1;
+                }
+                TokenType::RightBrace if skipped > 0 => {
+                    skipped -= 1;
+                }
+                // leave the `}` to the enclosing block, so that its scope is closed
+                TokenType::RightBrace if self.blocks > 0 => {
+                    return;
+                }
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return if skipped == 0 => {
+                    return;
+                }
+                _ => {}
+            }
+
+            self.advance();
+        }
+    }
+
+    // Leaves panic mode, recording where for the error that started it.
+    fn recover(&mut self) {
+        self.panic_mode = false;
+        if let Some(recovered) = self.recovered.last_mut() {
+            if *recovered == Position::END {
+                *recovered = Position::at(&self.current);
+            }
+        }
+    }
+
+    fn declaration(&mut self) -> Decl {
+        let first = self.current.clone();
+        let errors = self.diagnostics.len();
+        self.depth += 1;
+        let kind = if self.matches(TokenType::Fun) {
+            self.fun_declaration()
+        } else if self.matches(TokenType::Var) {
+            self.var_declaration()
+        } else {
+            DeclKind::Statement(Box::new(self.statement()))
+        };
+        let span = self.span(first);
+        let within = Position::within(&span, self.depth);
+        for found in &mut self.found[errors..] {
+            *found = (*found).min(within);
+        }
+        self.depth -= 1;
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+        Decl { kind, span }
+    }
+
+    fn statement(&mut self) -> Stmt {
+        let first = self.current.clone();
+        let kind = if self.matches(TokenType::Print) {
+            self.print_statement()
+        } else if self.matches(TokenType::For) {
+            self.for_statement()
+        } else if self.matches(TokenType::If) {
+            self.if_statement()
+        } else if self.matches(TokenType::Return) {
+            self.return_statement()
+        } else if self.matches(TokenType::While) {
+            self.while_statement()
+        } else if self.matches(TokenType::LeftBrace) {
+            StmtKind::Block(self.block())
+        } else {
+            self.expression_statement()
+        };
+        Stmt {
+            kind,
+            span: self.span(first),
+        }
+    }
+
+    fn error(&mut self, message: &str) {
+        self.error_at(self.previous.clone(), message);
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        self.error_at(self.current.clone(), message);
+    }
+
+    fn error_at(&mut self, token: Token, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+        let found = Position::at(&token);
+        self.report(token, message, found);
+    }
+
+    fn report(&mut self, token: Token, message: &str, found: Position) {
+        self.panic_mode = true;
+
+        self.found.push(found);
+        self.diagnostics.push(Diagnostic::new(token, message));
+        self.recovered.push(Position::END);
+    }
+}
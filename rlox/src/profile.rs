@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+use std::time::Instant;
+
+use chunk::opcode_name;
+use object::Function;
+
+// What `--profile` collects while the VM runs: how often each kind of opcode and
+// each source line ran, and how long was spent in each function.
+pub struct Profiler {
+    opcodes: HashMap<&'static str, u64>,
+    // instructions run per line, by function
+    lines: HashMap<String, HashMap<usize, u64>>,
+    functions: HashMap<String, Timing>,
+    // time spent at the top of each call stack, keyed by "script;outer;inner"
+    stacks: HashMap<String, Duration>,
+    active: Vec<Activation>,
+    // up to when time has been charged to the stacks
+    charged: Instant,
+}
+
+#[derive(Default)]
+struct Timing {
+    calls: u64,
+    inclusive: Duration,
+    exclusive: Duration,
+}
+
+struct Activation {
+    name: String,
+    stack: String,
+    entered: Instant,
+}
+
+// The name a function goes by in profiles and stack traces.
+pub fn function_name(function: &Function) -> &str {
+    function.name.as_deref().unwrap_or("script")
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            opcodes: HashMap::new(),
+            lines: HashMap::new(),
+            functions: HashMap::new(),
+            stacks: HashMap::new(),
+            active: Vec::new(),
+            charged: Instant::now(),
+        }
+    }
+
+    // Counts the instruction at `ip`, before it runs.
+    pub fn instruction(&mut self, function: &Function, ip: usize) {
+        *self.opcodes.entry(opcode_name(function.chunk.code[ip])).or_insert(0) += 1;
+        let name = function_name(function);
+        let line = function.chunk.line_at(ip);
+        match self.lines.get_mut(name) {
+            Some(lines) => *lines.entry(line).or_insert(0) += 1,
+            None => {
+                let mut lines = HashMap::new();
+                lines.insert(line, 1);
+                self.lines.insert(name.to_string(), lines);
+            }
+        }
+    }
+
+    // A call frame was pushed, or a native function is about to run.
+    pub fn enter(&mut self, name: &str) {
+        let now = Instant::now();
+        self.charge(now);
+        let stack = match self.active.last() {
+            Some(caller) => format!("{};{}", caller.stack, name),
+            None => name.to_string(),
+        };
+        self.functions.entry(name.to_string()).or_default().calls += 1;
+        self.active.push(Activation { name: name.to_string(), stack, entered: now });
+    }
+
+    // The innermost call returned.
+    pub fn leave(&mut self) {
+        let now = Instant::now();
+        self.charge(now);
+        if let Some(activation) = self.active.pop() {
+            // a recursive call is already inside the time of the outer one
+            if self.active.iter().all(|a| a.name != activation.name) {
+                self.functions.get_mut(&activation.name).unwrap().inclusive += now - activation.entered;
+            }
+        }
+    }
+
+    // A runtime error threw away every frame.
+    pub fn leave_all(&mut self) {
+        while !self.active.is_empty() {
+            self.leave();
+        }
+    }
+
+    // Gives the time since the last call or return to whatever was running.
+    fn charge(&mut self, now: Instant) {
+        if let Some(top) = self.active.last() {
+            let elapsed = now - self.charged;
+            *self.stacks.entry(top.stack.clone()).or_default() += elapsed;
+            self.functions.get_mut(&top.name).unwrap().exclusive += elapsed;
+        }
+        self.charged = now;
+    }
+
+    // The tables printed after a profiled run.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+
+        let mut functions = self.functions.iter().collect::<Vec<_>>();
+        functions.sort_by(|a, b| b.1.exclusive.cmp(&a.1.exclusive).then(a.0.cmp(b.0)));
+        writeln!(out, "== functions ==").unwrap();
+        writeln!(out, "{:<24} {:>10} {:>14} {:>14}", "function", "calls", "inclusive", "exclusive").unwrap();
+        for (name, timing) in functions {
+            writeln!(
+                out,
+                "{:<24} {:>10} {:>12.3}ms {:>12.3}ms",
+                name, timing.calls, ms(timing.inclusive), ms(timing.exclusive)
+            ).unwrap();
+        }
+
+        let total = self.opcodes.values().sum::<u64>().max(1);
+        let mut opcodes = self.opcodes.iter().collect::<Vec<_>>();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        writeln!(out, "\n== opcodes ==").unwrap();
+        for (name, count) in opcodes {
+            writeln!(out, "{:<24} {:>12} {:>6.1}%", name, count, *count as f64 * 100.0 / total as f64).unwrap();
+        }
+
+        let mut lines = self.lines
+            .iter()
+            .flat_map(|(name, lines)| lines.iter().map(move |(line, count)| (name, *line, *count)))
+            .collect::<Vec<_>>();
+        lines.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(b.0)).then(a.1.cmp(&b.1)));
+        writeln!(out, "\n== lines ==").unwrap();
+        for (name, line, count) in lines.into_iter().take(HOT_LINES) {
+            writeln!(out, "{:<24} {:>12}", format!("{}:{}", name, line), count).unwrap();
+        }
+        out
+    }
+
+    // One "script;outer;inner <microseconds>" line per call stack, the collapsed
+    // format that flamegraph.pl and similar tools read.
+    pub fn collapsed_stacks(&self) -> String {
+        let mut stacks = self.stacks
+            .iter()
+            .map(|(stack, time)| (stack, time.as_micros()))
+            .filter(|(_, micros)| *micros > 0)
+            .collect::<Vec<_>>();
+        stacks.sort();
+        let mut out = String::new();
+        for (stack, micros) in stacks {
+            writeln!(out, "{} {}", stack, micros).unwrap();
+        }
+        out
+    }
+}
+
+// How many of the busiest lines the report lists.
+const HOT_LINES: usize = 20;
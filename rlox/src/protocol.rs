@@ -0,0 +1,46 @@
+use std::io::BufRead;
+use std::io::Write;
+
+use json;
+use json::Json;
+
+// DAP and LSP both send JSON messages, each after a `Content-Length` header.
+
+// The next message, or None once the other side has gone.
+pub fn read_message(input: &mut dyn BufRead) -> Option<Json> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    let text = String::from_utf8(body).ok()?;
+    match json::parse(&text) {
+        Ok(message) => Some(message),
+        Err(e) => {
+            eprintln!("Invalid message: {}", e);
+            None
+        }
+    }
+}
+
+// A JSON object with its members in order.
+pub fn object(members: Vec<(&str, Json)>) -> Json {
+    Json::Object(members.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+}
+
+pub fn write_message(out: &mut dyn Write, message: &Json) {
+    let text = message.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", text.len(), text).unwrap();
+    out.flush().unwrap();
+}
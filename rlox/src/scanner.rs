@@ -1,9 +1,19 @@
 pub struct Scanner {
-    source: String,
     chars: Vec<char>,
     start: usize,
     current: usize,
     line: usize,
+    // what `skip_whitespace` passed over since the last token, if kept
+    trivia: Option<Vec<Trivia>>,
+}
+
+// What lies between tokens that the formatter keeps: line breaks and comments.
+// Other whitespace is dropped.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Trivia {
+    Newline,
+    // the text from `//` to the end of the line, and where it starts
+    Comment { text: String, line: usize, start: usize },
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -68,6 +78,9 @@ pub struct Token {
     pub tpe: TokenType,
     pub text: String,
     pub line: usize,
+    // span in the source, in chars
+    pub start: usize,
+    pub length: usize,
 }
 
 impl Clone for Token {
@@ -76,6 +89,8 @@ impl Clone for Token {
             tpe: self.tpe.clone(),
             text: self.text.clone(),
             line: self.line,
+            start: self.start,
+            length: self.length,
         }
     }
 }
@@ -83,14 +98,26 @@ impl Clone for Token {
 impl Scanner {
     pub fn new(source: String) -> Scanner {
         Scanner {
-            source: source.to_string(),
             chars: source.chars().collect::<Vec<_>>(),
             start: 0,
             current: 0,
             line: 1,
+            trivia: None,
         }
     }
 
+    // A scanner that keeps the trivia before each token, for `take_trivia`.
+    pub fn with_trivia(source: String) -> Scanner {
+        let mut scanner = Scanner::new(source);
+        scanner.trivia = Some(Vec::new());
+        scanner
+    }
+
+    // The trivia before the token scanned last.
+    pub fn take_trivia(&mut self) -> Vec<Trivia> {
+        self.trivia.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
     pub fn advance(&mut self) -> Option<&char> {
         let c = self.chars.get(self.current);
         self.current += 1;
@@ -124,7 +151,7 @@ impl Scanner {
             None => self.make_eof(),
             Some(&c) => match c {
                 d if '_' == d || d.is_alphabetic() => self.identifier(),
-                d if d.is_digit(10) => self.number(),
+                d if d.is_ascii_digit() => self.number(),
                 '(' => self.make_token(TokenType::LeftParen),
                 ')' => self.make_token(TokenType::RightParen),
                 '{' => self.make_token(TokenType::LeftBrace),
@@ -159,9 +186,13 @@ impl Scanner {
                 '\n' => {
                     self.line += 1;
                     self.advance();
+                    if let Some(trivia) = &mut self.trivia {
+                        trivia.push(Trivia::Newline);
+                    }
                 }
                 '/' => {
                     if let Some('/') = self.peek_next() {
+                        let start = self.current;
                         while let Some(&c) = self.peek() {
                             if c == '\n' {
                                 break;
@@ -169,6 +200,13 @@ impl Scanner {
                                 self.advance();
                             }
                         }
+                        if let Some(trivia) = &mut self.trivia {
+                            let comment = self.chars[start..self.current].iter().collect::<String>();
+                            let text = comment.trim_end().to_string();
+                            trivia.push(Trivia::Comment { text, line: self.line, start });
+                        }
+                    } else {
+                        return;
                     }
                 }
                 _ => {
@@ -180,7 +218,7 @@ impl Scanner {
 
     fn check_keyword(&self, start: usize, length: usize, rest: &str, tpe: TokenType) -> TokenType {
         if self.current - self.start == start + length
-            && &self.source[self.start + start..self.start + start + length] == rest
+            && self.chars[self.start + start..self.start + start + length].iter().cloned().eq(rest.chars())
         {
             tpe
         } else {
@@ -219,7 +257,7 @@ impl Scanner {
     fn identifier(&mut self) -> Token {
         loop {
             match self.peek() {
-                Some(c) if c.is_digit(10) || c.is_alphabetic() || *c == '_' => {
+                Some(c) if c.is_ascii_digit() || c.is_alphabetic() || *c == '_' => {
                     self.advance();
                 }
                 _ => return self.make_token(self.identifier_type()),
@@ -229,7 +267,7 @@ impl Scanner {
 
     fn number(&mut self) -> Token {
         while let Some(d) = self.peek() {
-            if d.is_digit(10) {
+            if d.is_ascii_digit() {
                 self.advance();
             } else {
                 break;
@@ -237,12 +275,12 @@ impl Scanner {
         }
         // Look for a fractional part.
         if let (Some('.'), Some(d)) = (self.peek(), self.peek_next()) {
-            if d.is_digit(10) {
+            if d.is_ascii_digit() {
                 // Consume the ".".
                 self.advance();
 
                 while let Some(d) = self.peek() {
-                    if d.is_digit(10) {
+                    if d.is_ascii_digit() {
                         self.advance();
                     } else {
                         break;
@@ -284,14 +322,18 @@ impl Scanner {
             tpe: TokenType::Eof,
             text: String::from(""),
             line: self.line,
+            start: self.chars.len(),
+            length: 0,
         }
     }
 
     fn make_token(&self, tpe: TokenType) -> Token {
         Token {
-            tpe: tpe,
-            text: String::from(&self.source[self.start..self.current]),
+            tpe,
+            text: self.chars[self.start..self.current].iter().collect(),
             line: self.line,
+            start: self.start,
+            length: self.current - self.start,
         }
     }
 
@@ -300,6 +342,8 @@ impl Scanner {
             tpe: TokenType::Error,
             text: String::from(message),
             line: self.line,
+            start: self.start,
+            length: self.current - self.start,
         }
     }
 }
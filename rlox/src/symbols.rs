@@ -0,0 +1,80 @@
+use scanner::Token;
+
+// What a name was declared as.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolKind {
+    Variable,
+    Parameter,
+    Function { arity: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Definition {
+    pub token: Token,
+    pub kind: SymbolKind,
+    pub global: bool,
+}
+
+// A use of a name. Locals and upvalues are resolved as they are compiled; globals
+// are late bound, so they are matched by name once the whole script is known.
+#[derive(Debug, Clone)]
+struct Reference {
+    token: Token,
+    definition: Option<usize>,
+}
+
+// Where the names of a script are declared and used, as the parser resolved them,
+// for the language server.
+pub struct Symbols {
+    definitions: Vec<Definition>,
+    references: Vec<Reference>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols { definitions: Vec::new(), references: Vec::new() }
+    }
+
+    pub fn define(&mut self, token: Token, global: bool) -> usize {
+        self.definitions.push(Definition { token, kind: SymbolKind::Variable, global });
+        self.definitions.len() - 1
+    }
+
+    pub fn set_kind(&mut self, definition: usize, kind: SymbolKind) {
+        self.definitions[definition].kind = kind;
+    }
+
+    // The most recent declaration.
+    pub fn last(&self) -> usize {
+        self.definitions.len() - 1
+    }
+
+    // Records a use of a local or an upvalue, or with `None` of a global.
+    pub fn refer(&mut self, token: Token, definition: Option<usize>) {
+        self.references.push(Reference { token, definition });
+    }
+
+    pub fn definitions(&self) -> &[Definition] {
+        &self.definitions
+    }
+
+    fn resolve(&self, reference: &Reference) -> Option<usize> {
+        let global = |d: &Definition| d.global && d.token.text == reference.token.text;
+        reference.definition.or_else(|| self.definitions.iter().position(global))
+    }
+
+    // The declaration of the name at a character offset of the source, whether the
+    // offset is on the declaration itself or on a use.
+    pub fn definition_at(&self, offset: usize) -> Option<usize> {
+        let covers = |token: &Token| token.start <= offset && offset <= token.start + token.length;
+        if let Some(definition) = self.definitions.iter().position(|d| covers(&d.token)) {
+            return Some(definition);
+        }
+        self.references.iter().find(|r| covers(&r.token)).and_then(|r| self.resolve(r))
+    }
+
+    // Every use of a declaration, in the order they appear.
+    pub fn uses(&self, definition: usize) -> Vec<&Token> {
+        self.references.iter().filter(|r| self.resolve(r) == Some(definition)).map(|r| &r.token).collect()
+    }
+}
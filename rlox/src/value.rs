@@ -2,35 +2,55 @@ use object::ObjType;
 use object::Function;
 use object::Native;
 use object::Closure;
+use object::TreeFunction;
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum Value {
+pub use self::repr::Value;
+
+// What a value holds, borrowed from it so that it can be matched on. `Value` itself
+// is opaque: with the `nan-boxing` feature it is a single `u64`.
+#[derive(Debug, Clone, Copy)]
+pub enum Unpacked<'a> {
     Nil,
     Bool(bool),
     Number(f64),
-    Object(ObjType),
+    Object(&'a ObjType),
 }
 
 impl Value {
-    fn fmt(&self) -> String {
-        return match self {
-            Value::Nil => String::from("nil"),
-            Value::Bool(b) => format!("{}", b),
-            Value::Number(n) => format!("{}", n),
-            Value::Object(ObjType::String(s)) => format!("{}", s),
-            Value::Object(ObjType::Upvalue(s)) => s.value.fmt(),
-            Value::Object(ObjType::Function(Function{ arity, name, .. })) =>
+    pub fn as_number(&self) -> Option<f64> {
+        match self.unpack() {
+            Unpacked::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&ObjType> {
+        match self.unpack() {
+            Unpacked::Object(o) => Some(o),
+            _ => None,
+        }
+    }
+
+    pub fn fmt(&self) -> String {
+        match self.unpack() {
+            Unpacked::Nil => String::from("nil"),
+            Unpacked::Bool(b) => b.to_string(),
+            Unpacked::Number(n) => format!("{}", n),
+            Unpacked::Object(ObjType::String(s)) => s.to_string(),
+            Unpacked::Object(ObjType::Function(Function{ arity, name, .. })) =>
                 match name {
                     Some(name) => format!("<fn {}/{}>", name, arity),
-                    None => format!("<script>"),
+                    None => String::from("<script>"),
                 },
-            Value::Object(ObjType::Closure(Closure{ function, .. })) =>
+            Unpacked::Object(ObjType::Closure(Closure{ function, .. })) =>
                 match function.name.clone() {
                     Some(name) => format!("<fn {}/{}>", name, function.arity),
-                    None => format!("<script>"),
+                    None => String::from("<script>"),
                 },
+            Unpacked::Object(ObjType::TreeFunction(TreeFunction{ declaration, .. })) =>
+                format!("<fn {}/{}>", declaration.name.text, declaration.params.len()),
 
-            Value::Object(ObjType::NativeFn( Native { arity, name, .. } )) =>
+            Unpacked::Object(ObjType::NativeFn( Native { arity, name, .. } )) =>
                 format!("<native fn {}/{}>", name, arity),
         }
     }
@@ -38,3 +58,158 @@ impl Value {
         print!("{}", self.fmt());
     }
 }
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.unpack(), other.unpack()) {
+            (Unpacked::Nil, Unpacked::Nil) => true,
+            (Unpacked::Bool(a), Unpacked::Bool(b)) => a == b,
+            (Unpacked::Number(a), Unpacked::Number(b)) => a == b,
+            (Unpacked::Object(a), Unpacked::Object(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.unpack().fmt(f)
+    }
+}
+
+// Objects are shared: copying a value that holds one only bumps a reference count.
+#[cfg(not(feature = "nan-boxing"))]
+mod repr {
+    use std::rc::Rc;
+
+    use object::ObjType;
+    use super::Unpacked;
+
+    #[derive(Clone)]
+    pub struct Value(Repr);
+
+    #[derive(Clone)]
+    enum Repr {
+        Nil,
+        Bool(bool),
+        Number(f64),
+        Object(Rc<ObjType>),
+    }
+
+    impl Value {
+        pub fn nil() -> Value {
+            Value(Repr::Nil)
+        }
+
+        pub fn boolean(b: bool) -> Value {
+            Value(Repr::Bool(b))
+        }
+
+        pub fn number(n: f64) -> Value {
+            Value(Repr::Number(n))
+        }
+
+        pub fn object(o: ObjType) -> Value {
+            Value(Repr::Object(Rc::new(o)))
+        }
+
+        pub fn unpack(&self) -> Unpacked<'_> {
+            match &self.0 {
+                Repr::Nil => Unpacked::Nil,
+                Repr::Bool(b) => Unpacked::Bool(*b),
+                Repr::Number(n) => Unpacked::Number(*n),
+                Repr::Object(o) => Unpacked::Object(o),
+            }
+        }
+    }
+}
+
+// A value packed in the 64 bits of a double, as in clox. Anything that is not a
+// quiet NaN is a number; quiet NaNs carry either a small tag for nil and the
+// booleans or, with the sign bit set, the address of an `Rc<ObjType>`.
+#[cfg(feature = "nan-boxing")]
+mod repr {
+    use std::rc::Rc;
+
+    use object::ObjType;
+    use super::Unpacked;
+
+    #[cfg(not(target_pointer_width = "64"))]
+    compile_error!("The nan-boxing feature needs 64-bit pointers.");
+
+    const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+    const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+    const TAG_NIL: u64 = 1;
+    const TAG_FALSE: u64 = 2;
+    const TAG_TRUE: u64 = 3;
+
+    const NIL: u64 = QNAN | TAG_NIL;
+    const FALSE: u64 = QNAN | TAG_FALSE;
+    const TRUE: u64 = QNAN | TAG_TRUE;
+
+    pub struct Value(u64);
+
+    impl Value {
+        pub fn nil() -> Value {
+            Value(NIL)
+        }
+
+        pub fn boolean(b: bool) -> Value {
+            Value(if b { TRUE } else { FALSE })
+        }
+
+        pub fn number(n: f64) -> Value {
+            // a NaN computed at runtime could look like a tagged value
+            if n.is_nan() {
+                Value(f64::NAN.to_bits())
+            } else {
+                Value(n.to_bits())
+            }
+        }
+
+        pub fn object(o: ObjType) -> Value {
+            let ptr = Rc::into_raw(Rc::new(o)) as u64;
+            debug_assert_eq!(ptr & (SIGN_BIT | QNAN), 0, "Object address does not fit in 48 bits");
+            Value(SIGN_BIT | QNAN | ptr)
+        }
+
+        pub fn unpack(&self) -> Unpacked<'_> {
+            match self.pointer() {
+                // the value holds a strong reference for as long as it is borrowed
+                Some(ptr) => Unpacked::Object(unsafe { &*ptr }),
+                None => match self.0 {
+                    NIL => Unpacked::Nil,
+                    FALSE => Unpacked::Bool(false),
+                    TRUE => Unpacked::Bool(true),
+                    bits => Unpacked::Number(f64::from_bits(bits)),
+                },
+            }
+        }
+
+        fn pointer(&self) -> Option<*const ObjType> {
+            if self.0 & (SIGN_BIT | QNAN) == SIGN_BIT | QNAN {
+                Some((self.0 & !(SIGN_BIT | QNAN)) as *const ObjType)
+            } else {
+                None
+            }
+        }
+    }
+
+    impl Clone for Value {
+        fn clone(&self) -> Value {
+            if let Some(ptr) = self.pointer() {
+                unsafe { Rc::increment_strong_count(ptr) };
+            }
+            Value(self.0)
+        }
+    }
+
+    impl Drop for Value {
+        fn drop(&mut self) {
+            if let Some(ptr) = self.pointer() {
+                unsafe { drop(Rc::from_raw(ptr)) };
+            }
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use chunk::OpCode;
+use compiler::Upvalue;
+use object::Function;
+use object::ObjType;
+use value::Value;
+
+// Checks a script and every function nested in its constants before the VM runs them,
+// so that malformed bytecode fails with an error instead of a panic in `VM::run`.
+// `global_count` is the number of global slots the script names.
+pub fn verify(function: &Function, global_count: usize) -> Result<(), String> {
+    verify_function(function, 0, global_count)
+}
+
+fn verify_function(function: &Function, upvalue_count: usize, global_count: usize) -> Result<(), String> {
+    let name = match &function.name {
+        Some(name) => format!("{}()", name),
+        None => String::from("script"),
+    };
+    let fail = |offset: usize, message: String| -> Result<(), String> {
+        Err(format!("in {} at {:04}: {}", name, offset, message))
+    };
+    let chunk = &function.chunk;
+    let code_len = chunk.code.len();
+    let values = &chunk.values;
+
+    // decode the whole chunk once to find where instructions start
+    let mut instructions = Vec::new();
+    let mut starts = vec![false; code_len];
+    let mut offset = 0;
+    while offset < code_len {
+        match chunk.try_decode(offset) {
+            Ok((op, next)) => {
+                starts[offset] = true;
+                instructions.push((offset, op, next));
+                offset = next;
+            }
+            Err(message) => return fail(offset, message.to_string()),
+        }
+    }
+
+    // operands that do not depend on the stack
+    let mut enclosed = vec![false; values.len()];
+    for (offset, op, next) in instructions.iter() {
+        let (offset, next) = (*offset, *next);
+        match op {
+            OpCode::Constant { index } if *index >= values.len() => {
+                return fail(offset, format!("Constant {} is out of range ({} constants).", index, values.len()));
+            }
+            OpCode::GetGlobal { index }
+            | OpCode::DefineGlobal { index }
+            | OpCode::SetGlobal { index } if *index >= global_count => {
+                return fail(offset, format!("Global {} is out of range ({} globals).", index, global_count));
+            }
+            OpCode::GetUpvalue { index } | OpCode::SetUpvalue { index } if *index >= upvalue_count => {
+                return fail(offset, format!("Upvalue {} is out of range ({} upvalues).", index, upvalue_count));
+            }
+            OpCode::Jump { jump } | OpCode::JumpIfFalse { jump } => {
+                let target = next + jump;
+                if target >= code_len || !starts[target] {
+                    return fail(offset, format!("Jump target {:04} is not an instruction.", target));
+                }
+            }
+            OpCode::Loop { jump } if *jump > next || next - jump >= code_len || !starts[next - jump] => {
+                return fail(offset, format!("Loop target {} bytes back is not an instruction.", jump));
+            }
+            OpCode::Closure { index, upvalues } => {
+                let nested = match values.get(*index).map(Value::as_object) {
+                    Some(Some(ObjType::Function(nested))) => nested,
+                    Some(_) => return fail(offset, format!("Closure constant {} is not a function.", index)),
+                    None => return fail(offset, format!("Constant {} is out of range ({} constants).", index, values.len())),
+                };
+                for upvalue in upvalues.iter() {
+                    if let Upvalue::Nonlocal(index) = upvalue {
+                        if *index >= upvalue_count {
+                            return fail(offset, format!("Captured upvalue {} is out of range ({} upvalues).", index, upvalue_count));
+                        }
+                    }
+                }
+                enclosed[*index] = true;
+                verify_function(nested, upvalues.len(), global_count)?;
+            }
+            _ => {}
+        }
+    }
+
+    // functions loaded as plain constants run without upvalues
+    for (index, value) in values.iter().enumerate() {
+        if let Some(ObjType::Function(nested)) = value.as_object() {
+            if !enclosed[index] {
+                verify_function(nested, 0, global_count)?;
+            }
+        }
+    }
+
+    // follow every path, tracking the stack depth above the frame's base; slot zero
+    // holds the callee and is followed by the parameters
+    let mut depths: Vec<Option<usize>> = vec![None; code_len];
+    let mut pending = vec![(0, 0, 1 + function.arity as usize)];
+    while let Some((from, offset, depth)) = pending.pop() {
+        if offset >= code_len {
+            return fail(from, String::from("Execution runs past the end of the code."));
+        }
+        match depths[offset] {
+            Some(known) if known == depth => continue,
+            Some(known) => {
+                return fail(offset, format!("Stack depth is {} on one path and {} on another.", known, depth));
+            }
+            None => depths[offset] = Some(depth),
+        }
+
+        let (op, next) = chunk.decode(offset);
+        let (pops, pushes) = stack_effect(&op);
+        if pops >= depth {
+            return fail(offset, format!("Instruction pops {} values but the stack holds {}.", pops, depth - 1));
+        }
+        match &op {
+            OpCode::GetLocal { index } | OpCode::SetLocal { index } if *index >= depth => {
+                return fail(offset, format!("Local slot {} is out of range (stack depth {}).", index, depth));
+            }
+            // a local function captures itself from the slot its closure is about to take
+            OpCode::Closure { upvalues, .. } => for upvalue in upvalues.iter() {
+                if let Upvalue::Local(index) = upvalue {
+                    if *index > depth {
+                        return fail(offset, format!("Captured local slot {} is out of range (stack depth {}).", index, depth));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        let depth = depth - pops + pushes;
+        match op {
+            OpCode::Return => {}
+            OpCode::Jump { jump } => pending.push((offset, next + jump, depth)),
+            OpCode::Loop { jump } => pending.push((offset, next - jump, depth)),
+            OpCode::JumpIfFalse { jump } => {
+                pending.push((offset, next + jump, depth));
+                pending.push((offset, next, depth));
+            }
+            _ => pending.push((offset, next, depth)),
+        }
+    }
+    Ok(())
+}
+
+// Values an instruction pops and pushes.
+fn stack_effect(op: &OpCode) -> (usize, usize) {
+    match op {
+        OpCode::Constant { .. }
+        | OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::GetLocal { .. }
+        | OpCode::GetGlobal { .. }
+        | OpCode::GetUpvalue { .. }
+        | OpCode::Closure { .. } => (0, 1),
+        OpCode::Pop
+        | OpCode::DefineGlobal { .. }
+        | OpCode::Print
+        | OpCode::CloseUpvalue
+        | OpCode::Return => (1, 0),
+        // these peek at the top of the stack
+        OpCode::SetLocal { .. }
+        | OpCode::SetGlobal { .. }
+        | OpCode::SetUpvalue { .. }
+        | OpCode::JumpIfFalse { .. } => (1, 1),
+        OpCode::Negate | OpCode::Not => (1, 1),
+        OpCode::Equal
+        | OpCode::Greater
+        | OpCode::Less
+        | OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide => (2, 1),
+        OpCode::Call { argc } => (*argc as usize + 1, 1),
+        OpCode::Jump { .. } | OpCode::Loop { .. } => (0, 0),
+    }
+}
@@ -1,16 +1,25 @@
-use chunk::Chunk;
+use std::io;
+use std::io::Write;
+
 use chunk::OpCode;
-use compiler::Parser;
 use compiler::Compiler;
 use compiler::Upvalue::{Local, Nonlocal};
+use coverage::Coverage;
+use debugger::Debugger;
+use diagnostic::DiagnosticFormat;
+use limits::Limits;
 use memory::Memory;
 use object::Function;
+use optimizer;
+use parser::Parser;
+use profile;
+use profile::Profiler;
 use object::ObjType;
 use object::Native;
 use object::Closure;
 use object::Upvalue;
+use value::Unpacked;
 use value::Value;
-use value::Value::Object;
 
 #[derive(Clone)]
 pub struct CallFrame {
@@ -34,6 +43,24 @@ pub struct VM {
     stack: Vec<Value>,
     open_upvalues: Vec<Upvalue>,
     memory: Memory,
+    diagnostics: DiagnosticFormat,
+    limits: Limits,
+    optimize: bool,
+    // where `print` writes
+    out: Box<dyn Write>,
+    // where runtime errors are reported
+    errors: Box<dyn Write>,
+    // instructions run so far, for benchmarks
+    instructions: u64,
+    // set by `--profile`
+    profiler: Option<Profiler>,
+    // set by `--coverage`
+    coverage: Option<Coverage>,
+    // set by `rlox debug`, and taken out while the program is paused
+    debugger: Option<Debugger>,
+    // frames below this one belong to a paused program, while the debugger evaluates
+    // an expression on top of them
+    base: usize,
 }
 
 pub enum InterpretResult {
@@ -44,210 +71,342 @@ pub enum InterpretResult {
 
 impl VM {
     pub fn new() -> VM {
-        VM {
+        let mut vm = VM {
             frames: Vec::new(),
             stack: Vec::new(),
             open_upvalues: Vec::new(),
             memory: Memory::new(),
+            diagnostics: DiagnosticFormat::Plain,
+            limits: Limits::new(),
+            optimize: false,
+            out: Box::new(io::stdout()),
+            errors: Box::new(io::stderr()),
+            instructions: 0,
+            profiler: None,
+            coverage: None,
+            debugger: None,
+            base: 0,
+        };
+        for native in natives() {
+            vm.define_native(native);
         }
+        vm
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn set_output(&mut self, out: Box<dyn Write>) {
+        self.out = out;
+    }
+
+    pub fn set_error_output(&mut self, errors: Box<dyn Write>) {
+        self.errors = errors;
+    }
+
+    pub fn instruction_count(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn enable_profiling(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn set_debugger(&mut self, debugger: Debugger) {
+        self.debugger = Some(debugger);
+    }
+
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    pub fn set_diagnostics(&mut self, format: DiagnosticFormat) {
+        self.diagnostics = format;
     }
 
-    fn native_clock(_args: &[Value]) -> Value {
-        let t = std::time::SystemTime::now();
-        let elapsed = t.duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as f64;
-        Value::Number(elapsed)
+    pub fn diagnostics(&self) -> DiagnosticFormat {
+        self.diagnostics
     }
 
     pub fn interpret(&mut self, source: &str) -> InterpretResult {
-        let parser = &mut Parser::new(source.to_string());
+        match self.compile(source) {
+            Some(function) => self.execute(function),
+            None => InterpretResult::CompileError,
+        }
+    }
+
+    // Compiles the source, printing diagnostics to the error output if it fails.
+    pub fn compile(&mut self, source: &str) -> Option<Function> {
+        let memory = std::mem::replace(&mut self.memory, Memory::new());
+        let parser = &mut Parser::new(source.to_string(), self.limits, memory);
         let mut compiler = Compiler::new(parser);
-        if let Some(function) = compiler.compile() {
-            self.stack.push(Value::Object(ObjType::Function(function.clone())));
-            let frame = CallFrame::new(Closure::new(function.clone()), 0);
-            let emitter = compiler.state();
-            self.memory = emitter.memory;
-            // FIXME ensure native functions are defined because memory is being overwrittend
-            self.define_native(Native::named("clock".to_string(), 0, VM::native_clock));
-            self.frames.push(frame);
-            
-            self.run()
+        let result = compiler.compile();
+        if result.is_none() {
+            let source = source.chars().collect::<Vec<_>>();
+            for diagnostic in compiler.diagnostics() {
+                writeln!(self.errors, "{}", diagnostic.render(self.diagnostics, &source)).unwrap();
+            }
+        }
+        self.memory = parser.take_memory();
+
+        let mut function = result?;
+        if self.optimize {
+            optimizer::optimize(&mut function);
+            // folding may have made new strings
+            function = self.intern_constants(&function);
+        }
+        Some(function)
+    }
+
+    // The globals and strings known so far, for compiling without running.
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    // Names of the global slots known so far, indexed by slot.
+    pub fn global_names(&self) -> &[String] {
+        self.memory.globals.names()
+    }
+
+    // Moves a script compiled elsewhere, whose slots are named by `names`, onto the
+    // global slots of this VM and interns its strings.
+    pub fn link(&mut self, function: Function, names: &[String]) -> Function {
+        let slots = names.iter().map(|name| self.memory.globals.resolve(name)).collect::<Vec<_>>();
+        let function = if slots.iter().enumerate().all(|(i, slot)| i == *slot) {
+            function
         } else {
-            return InterpretResult::CompileError;
+            relocate(&function, &slots)
+        };
+        self.intern_constants(&function)
+    }
+
+    // Replaces the string constants of a function and its nested functions with the
+    // interned ones.
+    fn intern_constants(&mut self, function: &Function) -> Function {
+        let mut function = function.clone();
+        for value in function.chunk.values.iter_mut() {
+            let interned = match value.unpack() {
+                Unpacked::Object(ObjType::String(s)) => ObjType::String(self.memory.strings.adopt(s)),
+                Unpacked::Object(ObjType::Function(nested)) => ObjType::Function(self.intern_constants(nested)),
+                _ => continue,
+            };
+            *value = Value::object(interned);
+        }
+        function
+    }
+
+    // Runs a compiled script, either fresh from `compile` or loaded from a `.loxc` file.
+    pub fn execute(&mut self, function: Function) -> InterpretResult {
+        self.stack.push(Value::object(ObjType::Function(function.clone())));
+        if let Some(profiler) = &mut self.profiler {
+            profiler.enter(profile::function_name(&function));
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.add(&function);
         }
+        let frame = CallFrame::new(Closure::new(function), 0);
+        self.frames.push(frame);
 
+        self.run()
     }
 
-    fn is_falsey(value: Value) -> bool {
-        match value {
-            Value::Nil => true,
-            Value::Bool(b) => !b,
+    fn is_falsey(value: &Value) -> bool {
+        match value.unpack() {
+            Unpacked::Nil => true,
+            Unpacked::Bool(b) => !b,
             _ => false,
         }
     }
 
-    fn binary_op(&mut self, op: fn(f64, f64) -> f64) {
-        if let (&Value::Number(b), &Value::Number(a)) = (
-            self.stack.last().unwrap(),
-            self.stack.get(self.stack.len() - 2).unwrap(),
+    fn binary_op(&mut self, op: fn(f64, f64) -> f64) -> bool {
+        if let (Some(b), Some(a)) = (
+            self.stack.last().unwrap().as_number(),
+            self.stack.get(self.stack.len() - 2).unwrap().as_number(),
         ) {
             self.stack.pop();
             self.stack.pop();
-            self.stack.push(Value::Number(op(a, b)))
+            self.stack.push(Value::number(op(a, b)));
+            true
+        } else {
+            self.runtime_error("Operands must be numbers.");
+            false
         }
     }
 
-    fn bool_op(&mut self, op: fn(f64, f64) -> bool) {
-        let bb = self.stack.last().unwrap();
-        let aa = self.stack.get(self.stack.len() - 2).unwrap();
-        if let (&Value::Number(b), &Value::Number(a)) = (bb, aa) {
+    fn bool_op(&mut self, op: fn(f64, f64) -> bool) -> bool {
+        let bb = self.stack.last().unwrap().as_number();
+        let aa = self.stack.get(self.stack.len() - 2).unwrap().as_number();
+        if let (Some(b), Some(a)) = (bb, aa) {
             self.stack.pop();
             self.stack.pop();
-            self.stack.push(Value::Bool(op(a, b)))
+            self.stack.push(Value::boolean(op(a, b)));
+            true
+        } else {
+            self.runtime_error("Operands must be numbers.");
+            false
         }
     }
 
     fn run(&mut self) -> InterpretResult {
         loop {
+            if self.debugger.is_some() && !self.debug() {
+                // the debugger quit
+                self.stack.clear();
+                self.frames.clear();
+                return InterpretResult::Ok;
+            }
+
+            let depth = self.frames.len() - 1;
             let frame = self.frames.last_mut().unwrap();
-            let instruction = frame.closure.function.chunk.fetch(frame.ip);
 
-            print!("          ");
-            for slot in self.stack.iter() {
-                print!("[ ");
-                slot.print();
-                print!(" ]");
+            if cfg!(feature = "debug-trace-execution") {
+                print!("          ");
+                for slot in self.stack.iter() {
+                    print!("[ ");
+                    slot.print();
+                    print!(" ]");
+                }
+                println!();
+
+                frame.closure.function.chunk.disassemble_instruction(frame.ip, self.memory.globals.names());
             }
-            println!();
 
-            frame.closure.function.chunk.disassemble_instruction(frame.ip);
+            if let Some(profiler) = &mut self.profiler {
+                profiler.instruction(&frame.closure.function, frame.ip);
+            }
+            if let Some(coverage) = &mut self.coverage {
+                coverage.instruction(&frame.closure.function, frame.ip, depth);
+            }
 
-            frame.ip += 1;
+            let at = frame.ip;
+            let (instruction, next) = frame.closure.function.chunk.decode_operands(at);
+            frame.ip = next;
+            self.instructions += 1;
 
             match instruction {
                 OpCode::Constant { index } => {
                     let value = frame.closure.function.chunk.read_constant(index);
                     self.stack.push(value);
                 }
-                OpCode::Nil => self.stack.push(Value::Nil),
-                OpCode::True => self.stack.push(Value::Bool(true)),
-                OpCode::False => self.stack.push(Value::Bool(false)),
+                OpCode::Nil => self.stack.push(Value::nil()),
+                OpCode::True => self.stack.push(Value::boolean(true)),
+                OpCode::False => self.stack.push(Value::boolean(false)),
                 OpCode::Pop => {
                     self.stack.pop();
                 }
                 OpCode::GetLocal { index } => {
-                    // self.stack.iter().for_each(|e| println!("STACK: {:?}", e));
                     self.stack.push(self.stack[frame.slot + index].clone());
                 }
                 OpCode::SetLocal { index } => {
-                    self.stack[frame.slot + index-1] = self.stack.last().unwrap().clone()
+                    self.stack[frame.slot + index] = self.stack.last().unwrap().clone()
                 }
-                OpCode::GetGlobal { index } => {
-                    let value = frame.closure.function.chunk.read_constant(index);
-
-                    if let Value::Object(ObjType::String(s)) = value {
-                        let k = s.to_string();
-                        match self.memory.get_global(k) {
-                            None => {
-                                let ss = format!("Undefined variable '{}'.", s);
-                                self.runtime_error(&ss);
-                                return InterpretResult::RuntimeError;
-                            }
-                            Some(v) => self.stack.push(v.clone()),
-                        }
+                OpCode::GetGlobal { index } => match self.memory.globals.get(index) {
+                    Some(v) => self.stack.push(v.clone()),
+                    None => {
+                        let ss = format!("Undefined variable '{}'.", self.memory.globals.names()[index]);
+                        self.runtime_error(&ss);
+                        return InterpretResult::RuntimeError;
                     }
-                }
+                },
                 OpCode::DefineGlobal { index } => {
-                    let value = frame.closure.function.chunk.read_constant(index);
-
-                    if let Value::Object(ObjType::String(s)) = value {
-                        self.memory
-                            .set_global(s.to_string(), self.stack.last().unwrap().clone());
-                        self.stack.pop();
-                    }
+                    let value = self.stack.pop().unwrap();
+                    self.memory.globals.define(index, value);
                 }
                 OpCode::SetGlobal { index } => {
-                    let value = frame.closure.function.chunk.read_constant(index);
-
-                    if let Value::Object(ObjType::String(s)) = value {
-                        if self
-                            .memory
-                            .set_global(s.to_string(), self.stack.last().unwrap().clone())
-                        {
-                            self.memory.delete_global(s.to_string());
-                            self.runtime_error(&format!("Undefined variable '{}'.", s));
-                            return InterpretResult::RuntimeError;
-                        }
+                    if !self.memory.globals.set(index, self.stack.last().unwrap().clone()) {
+                        let ss = format!("Undefined variable '{}'.", self.memory.globals.names()[index]);
+                        self.runtime_error(&ss);
+                        return InterpretResult::RuntimeError;
                     }
                 }
                 OpCode::GetUpvalue { index } => {
-                    if frame.closure.upvalues.len() <= index {
-                        panic!("BAD INDEX {} < {}", frame.closure.upvalues.len(), index);
-                    }
-                    let up = frame.closure.upvalues[index].clone();
-                    self.stack.push(Object(ObjType::Upvalue(up)));
+                    let value = frame.closure.upvalues[index].get(&self.stack);
+                    self.stack.push(value);
                 }
                 OpCode::SetUpvalue { index } => {
-                    if let Some(value) = self.stack.last_mut() {
-                        let v = &mut frame.closure.upvalues[index].value.as_mut();
-                        *v = value;
-                    } else {
-                        panic!("Error SetUpValue: Cannot find value at index {}", index);
-                    }
+                    let value = self.stack.last().unwrap().clone();
+                    frame.closure.upvalues[index].set(&mut self.stack, value);
                 }
                 OpCode::Equal => {
                     let b = self.stack.pop().unwrap();
                     let a = self.stack.pop().unwrap();
-                    self.stack.push(Value::Bool(a == b))
+                    self.stack.push(Value::boolean(a == b))
+                }
+                OpCode::Greater => if !self.bool_op(|a, b| a > b) {
+                    return InterpretResult::RuntimeError;
+                }
+                OpCode::Less => if !self.bool_op(|a, b| a < b) {
+                    return InterpretResult::RuntimeError;
                 }
-                OpCode::Greater => self.bool_op(|a, b| a > b),
-                OpCode::Less => self.bool_op(|a, b| a < b),
                 OpCode::Add => {
-                    match (self.stack.last().unwrap().clone(),
-                            self.stack.get(self.stack.len() - 2).unwrap().clone()) {
-                        (Value::Object(bref), Value::Object(aref)) => match (aref, bref) {
-                            (ObjType::String(a), ObjType::String(b)) => {
-                                self.stack.pop();
-                                self.stack.pop();
-                                let owned = format!("{}{}", a, b);
-                                self.stack.push(Value::Object(ObjType::String(owned)));
-                            }
-                            _ => self.runtime_error("Cannot concatenate")
-                        },
-                        (Value::Number(b), Value::Number(a)) => {
+                    let b = self.stack.last().unwrap();
+                    let a = self.stack.get(self.stack.len() - 2).unwrap();
+                    let sum = match (b.unpack(), a.unpack()) {
+                        (Unpacked::Object(ObjType::String(b)), Unpacked::Object(ObjType::String(a))) => {
+                            let s = self.memory.strings.intern(format!("{}{}", a, b));
+                            Some(Value::object(ObjType::String(s)))
+                        }
+                        (Unpacked::Number(b), Unpacked::Number(a)) => Some(Value::number(a + b)),
+                        _ => None,
+                    };
+                    match sum {
+                        Some(sum) => {
                             self.stack.pop();
                             self.stack.pop();
-                            self.stack.push(Value::Number(a + b))
+                            self.stack.push(sum)
                         }
-                        _ => {
+                        None => {
                             self.runtime_error("Operands must be two numbers or two strings.");
                             return InterpretResult::RuntimeError;
                         }
                     }
                 }
-                OpCode::Subtract => self.binary_op(|a, b| a - b),
-                OpCode::Multiply => self.binary_op(|a, b| a * b),
-                OpCode::Divide => self.binary_op(|a, b| a / b),
+                OpCode::Subtract => if !self.binary_op(|a, b| a - b) {
+                    return InterpretResult::RuntimeError;
+                }
+                OpCode::Multiply => if !self.binary_op(|a, b| a * b) {
+                    return InterpretResult::RuntimeError;
+                }
+                OpCode::Divide => if !self.binary_op(|a, b| a / b) {
+                    return InterpretResult::RuntimeError;
+                }
                 OpCode::Not => {
                     let v = self.stack.pop().unwrap();
-                    self.stack.push(Value::Bool(VM::is_falsey(v)))
+                    self.stack.push(Value::boolean(VM::is_falsey(&v)))
                 }
                 OpCode::Negate => {
-                    if let Value::Number(n) = self.stack.pop().unwrap() {
-                        self.stack.push(Value::Number(-n));
+                    if let Some(n) = self.stack.pop().unwrap().as_number() {
+                        self.stack.push(Value::number(-n));
                     } else {
                         self.runtime_error("Operand must be a number.");
                         return InterpretResult::RuntimeError;
                     }
                 }
                 OpCode::Print => {
-                    self.stack.pop().unwrap().print();
-                    println!();
+                    let value = self.stack.pop().unwrap();
+                    writeln!(self.out, "{}", value.fmt()).unwrap();
                 }
                 OpCode::JumpIfFalse { jump } => {
-                    let x = self.stack.last().unwrap().clone();
-                    if VM::is_falsey(x) {
+                    let falsey = VM::is_falsey(self.stack.last().unwrap());
+                    if falsey {
                         frame.ip += jump;
                     }
+                    if let Some(coverage) = &mut self.coverage {
+                        coverage.branch(&frame.closure.function, at, falsey);
+                    }
                 }
                 OpCode::Jump { jump } => {
                     frame.ip += jump;
@@ -256,137 +415,301 @@ impl VM {
                     frame.ip -= jump;
                 }
                 OpCode::Call { argc } => {
-                    let up = self.stack.len() - 1;
-                    let a = argc as usize;
-                    let offset = up - a;
-                    let args = &self.stack[offset..up+1];
-                    let callee = args[0].clone();
-
-                    match callee {
-                        Value::Object(ObjType::Function(f)) => 
-                            if argc == f.arity {
-                                self.frames.push(CallFrame::new(Closure::new(f.clone()), up));                          
-                            } else {
-                                self.runtime_error(& format!("Expected {} arguments but got {}.", f.arity, argc));
-                            }
-                        Value::Object(ObjType::NativeFn(f)) =>
-                            if argc == f.arity {
-                                let result = (f.fun)(args);
-                                self.stack.push(result);                 
-                            } else {
-                                self.runtime_error(& format!("Expected {} arguments but got {}.", f.arity, argc));
-                            }
-                        Value::Object(ObjType::Closure(cl)) =>
-                            if argc == cl.function.arity {
-                                self.frames.push(CallFrame::new(cl, up));
-                            } else {
-                                self.runtime_error(& format!("Expected {} arguments but got {}.", cl.function.arity, argc));
-                            }
-                        w => {
-                            println!("GOT {:?}", w);
-
-                            self.runtime_error("Can only call functions and classes");
-                            return InterpretResult::RuntimeError;
-                        }
+                    // the callee's slot is local zero of the new frame
+                    let offset = self.stack.len() - 1 - argc as usize;
+                    let callee = self.stack[offset].clone();
+                    if !self.call_value(callee, argc, offset) {
+                        return InterpretResult::RuntimeError;
                     }
                 }
-                OpCode::Closure { index, upvalues } => {
+                OpCode::Closure { index, .. } => {
                     let fc = frame.closure.function.chunk.read_constant(index);
-                    if let Value::Object(ObjType::Function(function)) = fc {
-                        let s = &self.stack;
-                        let mut us= Vec::new();
-                        for u in upvalues.iter() {
+                    if let Some(ObjType::Function(function)) = fc.as_object() {
+                        let function = function.clone();
+                        let mut us = Vec::new();
+                        for u in frame.closure.function.chunk.upvalues_at(at) {
                             let r = match u {
-                                Local(index) => {
-                                    let v = s[frame.slot + index].clone();
-                                    Upvalue::capture(v)
-                                    // Upvalue::capture(s[frame.slot + index].clone()),
-                                }
-                                Nonlocal(index) => {
-                                    frame.closure.upvalues[*index].clone()
-                                }
+                                // a local function captures the slot its closure is about to take
+                                Local(index) => capture_upvalue(&mut self.open_upvalues, frame.slot + index),
+                                Nonlocal(index) => frame.closure.upvalues[index].clone(),
                             };
                             us.push(r);
                         }
 
-                        for u in us.iter() {
-                            self.capture_upvalue(u.clone());
-                        }
-
-
                         let closure = Closure {
                             function,
                             upvalues: us
                         };
 
-                        self.stack.push(Value::Object(ObjType::Closure(closure)));
+                        self.stack.push(Value::object(ObjType::Closure(closure)));
                     } else {
                         panic!("I was expecting a function.");
                     }
                 }
                 OpCode::CloseUpvalue => {
-                    //         closeUpvalues(vm.stackTop - 1);
-                    //        pop();
-                    self.close_upvalues(self.stack.len());
+                    self.close_upvalues(self.stack.len() - 1);
                     self.stack.pop();
                 }
                 OpCode::Return => {
                     if let Some(result) = self.stack.pop() {
-
-                        // closeUpvalues(frame->slots)
                         let slot = frame.slot;
                         self.close_upvalues(slot);
                         self.frames.pop();
-                        for i in slot..self.stack.len() {
-                            // println!("{}/{}", i, slot);
-                            self.stack.pop();
+                        if let Some(profiler) = &mut self.profiler {
+                            profiler.leave();
                         }
-                        if self.frames.len() == 0 {
+                        self.stack.truncate(slot);
+                        if self.frames.is_empty() {
                             self.stack.pop();
                             // Exit interpreter.
                             return InterpretResult::Ok;
                         }
-                        self.stack.push(result);        
+                        self.stack.push(result);
+                        if self.frames.len() == self.base {
+                            // an expression the debugger evaluated
+                            return InterpretResult::Ok;
+                        }
                     }
                 }
             }
         }
     }
 
-    fn capture_upvalue(&mut self, value: Upvalue) -> Upvalue {
-        for i in self.open_upvalues.iter() {
-            if i.value == value.value {
-                return i.clone();
-            }
+    // Gives the debugger a look before each instruction. Returns false if it quit.
+    fn debug(&mut self) -> bool {
+        let mut debugger = self.debugger.take().unwrap();
+        let frame = self.frames.last().unwrap();
+        let function = &frame.closure.function;
+        let line = function.chunk.line_at(frame.ip);
+        let mut running = true;
+        if let Some(reason) = debugger.should_pause(profile::function_name(function), frame.ip, line, self.frames.len() - 1) {
+            running = debugger.pause(self, reason);
         }
+        self.debugger = Some(debugger);
+        running
+    }
+
+    // The frame `level` calls out from the innermost one, and the offset of the
+    // instruction it is on: the one about to run, or the call it is waiting in.
+    fn paused_frame(&self, level: usize) -> Option<(&CallFrame, usize)> {
+        let index = self.frames.len().checked_sub(level + 1)?;
+        let frame = &self.frames[index];
+        let ip = if level == 0 { frame.ip } else { frame.ip - 1 };
+        Some((frame, ip))
+    }
+
+    // The function and line of every frame, innermost first.
+    pub fn backtrace(&self) -> Vec<(String, usize)> {
+        (0..self.frames.len())
+            .filter_map(|level| self.paused_frame(level))
+            .map(|(frame, ip)| {
+                let function = &frame.closure.function;
+                (profile::function_name(function).to_string(), function.chunk.line_at(ip))
+            })
+            .collect()
+    }
+
+    // The locals in scope in a frame, innermost last, with their values.
+    pub fn locals(&self, level: usize) -> Vec<(String, Value)> {
+        let (frame, ip) = match self.paused_frame(level) {
+            Some(paused) => paused,
+            None => return Vec::new(),
+        };
+        let mut locals = frame.closure.function.chunk.locals_at(ip);
+        locals.sort_by_key(|local| local.slot);
+        locals
+            .into_iter()
+            .filter_map(|local| {
+                let value = self.stack.get(frame.slot + local.slot)?;
+                Some((local.name.clone(), value.clone()))
+            })
+            .collect()
+    }
+
+    // The variables a frame's closure captured, with their values.
+    pub fn upvalues(&self, level: usize) -> Vec<(String, Value)> {
+        let (frame, _) = match self.paused_frame(level) {
+            Some(paused) => paused,
+            None => return Vec::new(),
+        };
+        let names = frame.closure.function.chunk.upvalue_names();
+        names
+            .iter()
+            .zip(frame.closure.upvalues.iter())
+            .map(|(name, upvalue)| (name.clone(), upvalue.get(&self.stack)))
+            .collect()
+    }
 
-        self.open_upvalues.push(value.clone());
+    // Evaluates an expression as if it were written in a paused frame. It sees the
+    // frame's variables but assigning to them does not change the frame. A runtime
+    // error leaves the paused program as it was.
+    pub fn evaluate(&mut self, source: &str, level: usize) -> Option<Value> {
+        let mut variables = self.upvalues(level);
+        variables.extend(self.locals(level));
+        // the innermost of two variables with the same name hides the other
+        let mut seen = Vec::new();
+        variables.reverse();
+        variables.retain(|(name, _)| {
+            let first = !seen.contains(name);
+            seen.push(name.clone());
+            first
+        });
+        variables.reverse();
+        let names = variables.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>();
+
+        let memory = std::mem::replace(&mut self.memory, Memory::new());
+        let parser = &mut Parser::new(source.to_string(), self.limits, memory);
+        let mut compiler = Compiler::new(parser);
+        let result = compiler.compile_expression(&names);
+        if result.is_none() {
+            let source = source.chars().collect::<Vec<_>>();
+            for diagnostic in compiler.diagnostics() {
+                eprintln!("{}", diagnostic.render(self.diagnostics, &source));
+            }
+        }
+        self.memory = parser.take_memory();
+        let function = result?;
+
+        let frames = self.frames.clone();
+        let stack = self.stack.clone();
+        let open_upvalues = self.open_upvalues.clone();
+        let base = std::mem::replace(&mut self.base, self.frames.len());
+        let slot = self.stack.len();
+        self.stack.push(Value::object(ObjType::Function(function.clone())));
+        self.stack.extend(variables.into_iter().map(|(_, value)| value));
+        let value = if self.call(Closure::new(function), names.len() as u32, slot) {
+            match self.run() {
+                InterpretResult::Ok => self.stack.pop(),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        self.base = base;
+        self.frames = frames;
+        self.stack = stack;
+        self.open_upvalues = open_upvalues;
         value
     }
 
-    fn close_upvalues(&mut self, index: usize) {
-        let mut iter = self.open_upvalues.len();
-        loop {
-            if iter == 0 /*|| self.open_upvalues.last().unwrap().index < index */{
-                break;
-            } 
+    fn call_value(&mut self, callee: Value, argc: u32, slot: usize) -> bool {
+        match callee.as_object() {
+            Some(ObjType::Function(f)) => self.call(Closure::new(f.clone()), argc, slot),
+            Some(ObjType::Closure(cl)) => self.call(cl.clone(), argc, slot),
+            Some(ObjType::NativeFn(f)) => {
+                if argc != f.arity {
+                    self.runtime_error(&format!("Expected {} arguments but got {}.", f.arity, argc));
+                    return false;
+                }
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.enter(&f.name);
+                }
+                let result = (f.fun)(&self.stack[slot..]);
+                if let Some(profiler) = &mut self.profiler {
+                    profiler.leave();
+                }
+                self.stack.truncate(slot);
+                self.stack.push(result);
+                true
+            }
+            _ => {
+                self.runtime_error("Can only call functions and classes.");
+                false
+            }
+        }
+    }
 
-            // self.open_upvalues.pop();
-            
-            iter -= 1;
+    fn call(&mut self, closure: Closure, argc: u32, slot: usize) -> bool {
+        if argc != closure.function.arity {
+            self.runtime_error(&format!("Expected {} arguments but got {}.", closure.function.arity, argc));
+            return false;
+        }
+        if self.frames.len() >= self.limits.frames || self.stack.len() > self.limits.stack {
+            self.runtime_error("Stack overflow.");
+            return false;
         }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.enter(profile::function_name(&closure.function));
+        }
+        self.frames.push(CallFrame::new(closure, slot));
+        true
+    }
+
+    // closes the upvalues of the slots from `slot` up, which are about to go away
+    fn close_upvalues(&mut self, slot: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| match upvalue.slot() {
+            Some(open) if open >= slot => {
+                upvalue.close(stack);
+                false
+            }
+            _ => true,
+        });
     }
 
     fn runtime_error(&mut self, message: &str) {
-        eprintln!("{}", message);
-        // let line = self.chunk.line_at(self.ip);
-        let line = -1;
-        eprint!("[line {}] in script\n", line);
+        writeln!(self.errors, "{}", message).unwrap();
+        // an expression the debugger evaluated shows only the calls it made
+        let first = if self.base > 0 { self.base + 1 } else { 0 };
+        for frame in self.frames[first..].iter().rev() {
+            let function = &frame.closure.function;
+            let line = function.chunk.line_at(frame.ip - 1);
+            match &function.name {
+                Some(name) => writeln!(self.errors, "[line {}] in {}()", line, name).unwrap(),
+                None => writeln!(self.errors, "[line {}] in script", line).unwrap(),
+            }
+        }
 
         self.stack.clear();
+        self.frames.clear();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.leave_all();
+        }
+        self.open_upvalues.clear();
     }
 
     fn define_native(&mut self, fun: Native) {
-        self.memory.set_global(fun.name.to_string(), Value::Object(ObjType::NativeFn(fun)));
+        let slot = self.memory.globals.resolve(&fun.name);
+        self.memory.globals.define(slot, Value::object(ObjType::NativeFn(fun)));
+    }
+}
+
+// The functions every program starts with, for both the VM and the tree-walking
+// interpreter.
+pub fn natives() -> Vec<Native> {
+    vec![Native::named("clock".to_string(), 0, native_clock)]
+}
+
+fn native_clock(_args: &[Value]) -> Value {
+    let t = std::time::SystemTime::now();
+    let elapsed = t.duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as f64;
+    Value::number(elapsed)
+}
+
+// The upvalue for a stack slot, shared with the closures that captured it before.
+fn capture_upvalue(open_upvalues: &mut Vec<Upvalue>, slot: usize) -> Upvalue {
+    if let Some(upvalue) = open_upvalues.iter().find(|upvalue| upvalue.slot() == Some(slot)) {
+        return upvalue.clone();
+    }
+    let upvalue = Upvalue::open(slot);
+    open_upvalues.push(upvalue.clone());
+    upvalue
+}
+
+// Rewrites the global slots of a function and of every function among its constants.
+fn relocate(function: &Function, slots: &[usize]) -> Function {
+    let mut chunk = function.chunk.rewrite(|op| match op {
+        OpCode::GetGlobal { index } => OpCode::GetGlobal { index: slots[index] },
+        OpCode::DefineGlobal { index } => OpCode::DefineGlobal { index: slots[index] },
+        OpCode::SetGlobal { index } => OpCode::SetGlobal { index: slots[index] },
+        op => op,
+    });
+    for value in chunk.values.iter_mut() {
+        let relocated = match value.as_object() {
+            Some(ObjType::Function(nested)) => relocate(nested, slots),
+            _ => continue,
+        };
+        *value = Value::object(ObjType::Function(relocated));
     }
+    Function { chunk, ..function.clone() }
 }
@@ -0,0 +1,40 @@
+// Assembly listings: running the fixtures under `test/bytecode/`, and reading back what
+// `rlox compile` writes to a `.loxasm` file.
+mod common;
+
+use std::fs;
+
+use common::*;
+
+#[test]
+fn runs_the_listings() {
+    let files = corpus_files("loxasm");
+    assert!(files.len() >= 3, "only {} listings", files.len());
+    for file in files {
+        let expected = Expected::of(&fs::read_to_string(&file).unwrap());
+        assert_eq!(expected.check(&rlox(&[&file])), Ok(()), "{}", file);
+    }
+}
+
+// Assembling the listing of a compiled file gives back the bytecode it lists.
+#[test]
+fn reassembles_the_corpus() {
+    let compiled = Scratch::new("compiled.loxc");
+    let listing = Scratch::new("listing.loxasm");
+    let assembled = Scratch::new("assembled.loxc");
+    let mut checked = 0;
+    for file in lox_files() {
+        if status(&rlox(&["compile", &file, "-o", &compiled.path()])) != 0 {
+            continue;
+        }
+        let output = rlox(&["compile", &file, "-o", &listing.path()]);
+        assert_eq!(status(&output), 0, "{}", file);
+        let output = rlox(&["compile", &listing.path(), "-o", &assembled.path()]);
+        assert_eq!(stderr(&output), "", "{}", file);
+        assert_eq!(status(&output), 0, "{}", file);
+
+        assert!(fs::read(&compiled.0).unwrap() == fs::read(&assembled.0).unwrap(), "{}", file);
+        checked += 1;
+    }
+    assert!(checked > 100, "only {} files assembled", checked);
+}
@@ -0,0 +1,89 @@
+// `rlox bench`: its baselines and the comparison with them.
+mod common;
+
+use std::fs;
+
+use common::*;
+
+fn benchmark() -> String {
+    corpus("closure/nested_closure.lox")
+}
+
+// A baseline for the benchmark with the given median and instruction count.
+fn baseline(name: &str, median: f64, instructions: u64) -> Scratch {
+    let file = Scratch::new(name);
+    let summary = format!(
+        "{{\"min_ms\":{0},\"median_ms\":{0},\"max_ms\":{0},\"instructions\":{1}}}",
+        median, instructions
+    );
+    let text = format!("{{\"runs\":1,\"optimize\":false,\"benchmarks\":{{\"nested_closure\":{}}}}}", summary);
+    fs::write(&file.0, text).unwrap();
+    file
+}
+
+#[test]
+fn saves_a_baseline() {
+    let saved = Scratch::new("saved.json");
+    let output = rlox(&["bench", "--runs=3", &format!("--save={}", saved.path()), &benchmark()]);
+    assert_eq!(stderr(&output), "");
+    assert_eq!(status(&output), 0);
+    let lines = stdout(&output).lines().map(String::from).collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("benchmark "));
+    assert!(lines[1].starts_with("nested_closure ") && lines[1].ends_with(" 41"), "{}", lines[1]);
+    assert_eq!(lines[2], "Times are of running each benchmark once it is compiled.");
+
+    let text = fs::read_to_string(&saved.0).unwrap();
+    let start = "{\"runs\":3,\"optimize\":false,\"benchmarks\":{\"nested_closure\":{\"min_ms\":";
+    assert!(text.starts_with(start), "{}", text);
+    assert!(text.ends_with(",\"instructions\":41}}}\n"), "{}", text);
+}
+
+#[test]
+fn flags_a_slower_median() {
+    let previous = baseline("fast.json", 0.000001, 41);
+    let output = rlox(&["bench", "--runs=1", &format!("--baseline={}", previous.path()), &benchmark()]);
+    assert!(stdout(&output).contains("% vs 0.0ms  REGRESSION\n"), "{}", stdout(&output));
+    assert_eq!(status(&output), 1);
+}
+
+// the instruction count changing is reported, but only time counts as a regression
+#[test]
+fn reports_a_change_in_instructions() {
+    let previous = baseline("slow.json", 1000000.0, 40);
+    let output = rlox(&["bench", "--runs=1", &format!("--baseline={}", previous.path()), &benchmark()]);
+    assert!(stdout(&output).contains("% vs 1000000.0ms, +1 instructions\n"), "{}", stdout(&output));
+    assert!(!stdout(&output).contains("REGRESSION"));
+    assert_eq!(status(&output), 0);
+}
+
+#[test]
+fn rejects_an_invalid_baseline() {
+    let previous = Scratch::new("invalid.json");
+    fs::write(&previous.0, "{}").unwrap();
+    let output = rlox(&["bench", &format!("--baseline={}", previous.path()), &benchmark()]);
+    assert_eq!(stdout(&output), "");
+    assert_eq!(stderr(&output), format!("Invalid baseline \"{}\": missing \"benchmarks\".\n", previous.path()));
+    assert_eq!(status(&output), 74);
+}
+
+// a failed benchmark reports its first run's errors once, and is kept in the baseline
+#[test]
+fn reports_a_failed_benchmark() {
+    let broken = Scratch::new("broken.lox");
+    fs::write(&broken.0, "fun f() { return -\"x\"; }\nf();\n").unwrap();
+    let saved = Scratch::new("failed.json");
+    let output = rlox(&["bench", "--runs=3", &format!("--save={}", saved.path()), &broken.path(), &benchmark()]);
+    assert_eq!(stderr(&output), "Operand must be a number.\n[line 1] in f()\n[line 2] in script\n");
+    let name = broken.0.file_stem().unwrap().to_string_lossy().to_string();
+    assert!(stdout(&output).contains(&format!("\n{:<20} failed with a runtime error\n", name)), "{}", stdout(&output));
+    assert_eq!(status(&output), 1);
+
+    let text = fs::read_to_string(&saved.0).unwrap();
+    assert!(text.contains(&format!("\"{}\":{{\"failed\":\"runtime error\"}}", name)), "{}", text);
+
+    // the failed entry has nothing to compare with, but the run still fails
+    let output = rlox(&["bench", "--runs=1", &format!("--baseline={}", saved.path()), &broken.path()]);
+    assert!(stdout(&output).contains("failed with a runtime error\n"), "{}", stdout(&output));
+    assert_eq!(status(&output), 1);
+}
@@ -0,0 +1,245 @@
+// Helpers shared by the tests, which run the rlox binary on the files under `test/`.
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Output;
+use std::process::Stdio;
+
+// The root of the repository, where `make` builds clox.
+pub fn repository() -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("..").to_string_lossy().to_string()
+}
+
+// A file or directory under the repository's `test/`.
+pub fn corpus(path: &str) -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../test").join(path).to_string_lossy().to_string()
+}
+
+// Every `.lox` file under `test/`, except the benchmarks, which take too long.
+pub fn lox_files() -> Vec<String> {
+    corpus_files("lox")
+}
+
+// Every file under `test/` with the extension, except the benchmarks.
+pub fn corpus_files(extension: &str) -> Vec<String> {
+    fn walk(dir: &Path, extension: &str, files: &mut Vec<String>) {
+        let mut entries = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect::<Vec<_>>();
+        entries.sort();
+        for path in entries {
+            if path.is_dir() && !path.ends_with("benchmark") {
+                walk(&path, extension, files);
+            } else if path.extension().is_some_and(|ext| ext == extension) {
+                files.push(path.to_string_lossy().to_string());
+            }
+        }
+    }
+    let mut files = Vec::new();
+    walk(Path::new(&corpus("")), extension, &mut files);
+    files
+}
+
+pub fn rlox(args: &[&str]) -> Output {
+    rlox_with_input(args, "")
+}
+
+// Runs rlox with what it reads from stdin, as the REPL and the servers do.
+pub fn rlox_with_input(args: &[&str], input: &str) -> Output {
+    rlox_in(".", args, input)
+}
+
+// Runs rlox from a directory, for the paths it reads and writes.
+pub fn rlox_in(dir: &str, args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .current_dir(dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("could not run rlox");
+    // a program that exits without reading its input closes the pipe early
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+// Replays a session under `test/` against one of rlox's servers, run from the
+// repository root. A transcript lists the messages to send, `-> ` lines, and every
+// message expected back, `<- ` lines, in order.
+pub fn replay(command: &str, transcript: &str) {
+    let text = fs::read_to_string(corpus(transcript)).unwrap();
+    let mut input = String::new();
+    let mut expected = Vec::new();
+    for line in text.lines() {
+        if let Some(request) = line.strip_prefix("-> ") {
+            input.push_str(&format!("Content-Length: {}\r\n\r\n{}", request.len(), request));
+        } else if let Some(response) = line.strip_prefix("<- ") {
+            expected.push(response.to_string());
+        }
+    }
+
+    let output = rlox_in(&repository(), &[command], &input);
+    let mut received = Vec::new();
+    let mut rest = stdout(&output);
+    while let Some(end) = rest.find("\r\n\r\n") {
+        let length = rest[..end].trim_start_matches("Content-Length: ").parse::<usize>().unwrap();
+        let body = end + 4;
+        received.push(rest[body..body + length].to_string());
+        rest = rest[body + length..].to_string();
+    }
+    assert_eq!(rest, "", "output that is not a message");
+
+    for (i, (expected, received)) in expected.iter().zip(received.iter()).enumerate() {
+        assert_eq!(received, expected, "message {} of {}", i + 1, transcript);
+    }
+    assert_eq!(received.len(), expected.len(), "number of messages in {}", transcript);
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+pub fn status(output: &Output) -> i32 {
+    output.status.code().unwrap_or(-1)
+}
+
+// A scratch file that is removed when the test is done with it.
+pub struct Scratch(pub PathBuf);
+
+impl Scratch {
+    pub fn new(name: &str) -> Scratch {
+        let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+        Scratch(dir.join(format!("{}-{}", std::process::id(), name)))
+    }
+
+    pub fn path(&self) -> String {
+        self.0.to_string_lossy().to_string()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+// What a corpus file's comments say running it prints and how it ends: `// expect: `
+// lines of output, `// Error ...` compile errors, or one `// expect runtime error: `.
+pub struct Expected {
+    pub output: Vec<String>,
+    pub errors: Vec<String>,
+    // the message and the line it is raised on
+    pub runtime_error: Option<(String, usize)>,
+    pub status: i32,
+}
+
+impl Expected {
+    pub fn of(source: &str) -> Expected {
+        let mut expected = Expected { output: Vec::new(), errors: Vec::new(), runtime_error: None, status: 0 };
+        for (i, line) in source.lines().enumerate() {
+            if let Some((_, output)) = line.split_once("// expect: ") {
+                expected.output.push(output.to_string());
+            } else if let Some((_, error)) = line.split_once("// expect runtime error: ") {
+                expected.runtime_error = Some((error.to_string(), i + 1));
+                expected.status = 70;
+            } else if let Some((_, error)) = line.split_once("// [line ").or(line.split_once("// [c line ")) {
+                expected.errors.push(format!("[line {}", error));
+                expected.status = 65;
+            } else if let Some((_, error)) = line.split_once("// Error") {
+                expected.errors.push(format!("[line {}] Error{}", i + 1, error));
+                expected.status = 65;
+            }
+        }
+        expected
+    }
+
+    // Compares a run with what was expected; of a runtime error, only the message and
+    // the line of the innermost call are.
+    pub fn check(&self, output: &Output) -> Result<(), String> {
+        let printed = stdout(output).lines().map(String::from).collect::<Vec<_>>();
+        if printed != self.output {
+            return Err(format!("printed {:?}, expected {:?}", printed, self.output));
+        }
+        let errors = stderr(output).lines().map(String::from).collect::<Vec<_>>();
+        match &self.runtime_error {
+            Some((message, line)) => {
+                let trace = format!("[line {}] in ", line);
+                if errors.first() != Some(message) || !errors.get(1).is_some_and(|l| l.starts_with(&trace)) {
+                    return Err(format!("failed with {:?}, expected {:?} on line {}", errors, message, line));
+                }
+            }
+            None if errors != self.errors => {
+                return Err(format!("failed with {:?}, expected {:?}", errors, self.errors));
+            }
+            None => {}
+        }
+        if status(output) != self.status {
+            return Err(format!("exited with {}, expected {}", status(output), self.status));
+        }
+        Ok(())
+    }
+}
+
+// Corpus files whose comments rlox does not follow, and why.
+pub const UNEXPECTED: &[(&str, &str)] = &[
+    // the chapters before the bytecode VM, which test the scanner and the parser alone
+    ("scanning/", "prints tokens"),
+    ("expressions/", "prints syntax trees"),
+    ("class/", "needs classes"),
+    ("constructor/", "needs classes"),
+    ("field/", "needs classes"),
+    ("inheritance/", "needs classes"),
+    ("method/", "needs classes"),
+    ("super/", "needs classes"),
+    ("this/", "needs classes"),
+    ("function/missing_comma_in_parameters.lox", "recovers without the error at the end; see recovery/"),
+    ("function/print.lox", "prints a function with its arity"),
+    ("function/too_many_arguments.lox", "expects jlox's old limit of 8 arguments; see limit/"),
+    ("function/too_many_parameters.lox", "expects jlox's old limit of 8 parameters; see limit/"),
+    ("number/decimal_point_at_eof.lox", "has no property access to expect a name after '.'"),
+    ("number/trailing_dot.lox", "has no property access to expect a name after '.'"),
+    ("return/at_top_level.lox", "words the error its own way"),
+    ("variable/collide_with_parameter.lox", "words the error its own way"),
+    ("variable/duplicate_local.lox", "words the error its own way"),
+    ("variable/duplicate_parameter.lox", "words the error its own way"),
+    ("variable/use_local_in_initializer.lox", "words the error its own way"),
+];
+
+// The files under `test/` whose comments say how running them ends, leaving out those
+// that use classes elsewhere in the corpus.
+pub fn expected_files() -> Vec<String> {
+    let root = corpus("");
+    lox_files()
+        .into_iter()
+        .filter(|file| {
+            let relative = &file[root.len()..];
+            !UNEXPECTED.iter().any(|(path, _)| relative.starts_with(path))
+        })
+        .filter(|file| {
+            let source = fs::read_to_string(file).unwrap();
+            let mut words = source.split(|c: char| !c.is_alphanumeric() && c != '_');
+            !words.any(|word| word == "class" || word == "this" || word == "super")
+        })
+        .collect()
+}
+
+// Runs each file with the options and lists the ones that did not end as expected.
+pub fn failures(files: &[String], options: &[&str]) -> Vec<String> {
+    let mut failures = Vec::new();
+    for file in files {
+        let expected = Expected::of(&fs::read_to_string(file).unwrap());
+        let mut args = options.to_vec();
+        args.push(file);
+        if let Err(e) = expected.check(&rlox(&args)) {
+            failures.push(format!("{}: {}", file, e));
+        }
+    }
+    failures
+}
@@ -0,0 +1,25 @@
+// The files under `test/`, run on the VM and checked against what their comments expect.
+mod common;
+
+use std::fs;
+
+use common::*;
+
+// The parser has to report the errors the single-pass compiler did, with the same
+// messages, on the same lines and in the same order.
+#[test]
+fn reports_the_expected_compile_errors() {
+    let files = expected_files()
+        .into_iter()
+        .filter(|file| Expected::of(&fs::read_to_string(file).unwrap()).status == 65)
+        .collect::<Vec<_>>();
+    assert!(files.len() > 25, "only {} files", files.len());
+    assert_eq!(failures(&files, &[]), Vec::<String>::new());
+}
+
+#[test]
+fn runs_the_corpus() {
+    let files = expected_files();
+    assert!(files.len() > 140, "only {} files", files.len());
+    assert_eq!(failures(&files, &[]), Vec::<String>::new());
+}
@@ -0,0 +1,94 @@
+// `--coverage` and `--annotate`: the lcov record and the annotated source of a run.
+mod common;
+
+use std::fs;
+
+use common::*;
+
+// `f` only ever takes its else path and `unused` is never called.
+const PROGRAM: &str = "fun f(n) {
+  if (n > 1) {
+    return \"big\";
+  }
+  return \"small\";
+}
+print f(0);
+print f(0);
+fun unused() {
+  print \"never\";
+}
+";
+
+#[test]
+fn writes_lines_and_branches_as_lcov() {
+    let script = Scratch::new("lcov.lox");
+    fs::write(&script.0, PROGRAM).unwrap();
+    let info = Scratch::new("lcov.info");
+    let output = rlox(&["--coverage", &info.path(), &script.path()]);
+    assert_eq!(stdout(&output), "small\nsmall\n");
+    assert_eq!(status(&output), 0);
+
+    // the script's implicit return, on the line after the last one, is left out
+    let expected = format!(
+        "TN:\nSF:{}\n\
+         BRDA:2,0,0,0\nBRDA:2,0,1,2\nBRF:2\nBRH:1\n\
+         DA:2,2\nDA:3,0\nDA:4,2\nDA:5,2\nDA:6,1\nDA:7,1\nDA:8,1\nDA:10,0\nDA:11,1\n\
+         LF:9\nLH:7\nend_of_record\n",
+        script.path()
+    );
+    assert_eq!(fs::read_to_string(&info.0).unwrap(), expected);
+}
+
+#[test]
+fn annotates_the_source_with_line_counts() {
+    let script = Scratch::new("annotate.lox");
+    fs::write(&script.0, PROGRAM).unwrap();
+    let annotated = Scratch::new("annotate.txt");
+    let output = rlox(&["--annotate", &annotated.path(), &script.path()]);
+    assert_eq!(stdout(&output), "small\nsmall\n");
+    assert_eq!(status(&output), 0);
+
+    let expected = "        -:    1:fun f(n) {
+        2:    2:  if (n > 1) {
+    #####:    3:    return \"big\";
+        2:    4:  }
+        2:    5:  return \"small\";
+        1:    6:}
+        1:    7:print f(0);
+        1:    8:print f(0);
+        -:    9:fun unused() {
+    #####:   10:  print \"never\";
+        1:   11:}
+";
+    assert_eq!(fs::read_to_string(&annotated.0).unwrap(), expected);
+}
+
+// a loop on one line counts each backward jump onto it: the for loop jumps back to
+// its increment and then to its condition on each of its three passes
+#[test]
+fn counts_each_pass_of_a_loop_on_one_line() {
+    let script = Scratch::new("loops.lox");
+    let source = "var s = 0;\nfor (var i = 0; i < 3; i = i + 1) s = s + i;\nwhile (s > 0) s = s - 1;\n";
+    fs::write(&script.0, source).unwrap();
+    let info = Scratch::new("loops.info");
+    let output = rlox(&["--coverage", &info.path(), &script.path()]);
+    assert_eq!(status(&output), 0);
+    let record = fs::read_to_string(&info.0).unwrap();
+    assert!(record.contains("\nDA:1,1\nDA:2,7\nDA:3,4\nLF:3\n"), "{}", record);
+}
+
+// what ran before a runtime error is still counted
+#[test]
+fn reports_a_run_that_fails() {
+    let info = Scratch::new("failing.info");
+    let file = corpus("assignment/undefined.lox");
+    let output = rlox(&["--coverage", &info.path(), &file]);
+    assert_eq!(status(&output), 70);
+    // the record names the file by its canonical path
+    let path = fs::canonicalize(&file).unwrap();
+    let expected = format!(
+        "TN:\nSF:{}\nBRF:0\nBRH:0\nDA:1,1\nLF:1\nLH:1\nend_of_record\n",
+        path.display()
+    );
+    assert_eq!(fs::read_to_string(&info.0).unwrap(), expected);
+}
@@ -0,0 +1,9 @@
+// Replays the sessions under `test/dap/` against `rlox dap`.
+mod common;
+
+use common::*;
+
+#[test]
+fn add() {
+    replay("dap", "dap/add.transcript");
+}
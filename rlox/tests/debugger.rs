@@ -0,0 +1,91 @@
+// `rlox debug`: sessions driven through stdin, checked against everything the debugger
+// printed. It pauses first on the line of the script's first instruction, which for
+// this program is the end of the function declaration.
+mod common;
+
+use std::fs;
+
+use common::*;
+
+const PROGRAM: &str = "fun add(a, b) {
+  var sum = a + b;
+  return sum;
+}
+var x = add(1, 2);
+print x;
+print add(x, 4);
+";
+
+fn debug(name: &str, commands: &str) -> (String, i32) {
+    let script = Scratch::new(name);
+    fs::write(&script.0, PROGRAM).unwrap();
+    let output = rlox_with_input(&["debug", &script.path()], commands);
+    assert_eq!(stderr(&output), "");
+    (stdout(&output), status(&output))
+}
+
+#[test]
+fn steps_over_into_and_out_of_calls() {
+    let (out, code) = debug("stepping.lox", "next\nnext\nstep\nstep\nfinish\nnext\nquit\n");
+    assert_eq!(
+        out,
+        "script at line 4
+   4  }
+(rlox) script at line 5
+   5  var x = add(1, 2);
+(rlox) script at line 6
+   6  print x;
+(rlox) 3
+script at line 7
+   7  print add(x, 4);
+(rlox) add() at line 2
+   2    var sum = a + b;
+(rlox) script at line 7
+   7  print add(x, 4);
+(rlox) 7
+script at line 8
+(rlox) "
+    );
+    assert_eq!(code, 0);
+}
+
+#[test]
+fn stops_at_breakpoints_and_shows_the_frame() {
+    let commands = "break 2\ncontinue\nlocals\nbacktrace\nprint a + b\nnext\nlocals\nup\nlocals\n\
+                    delete 2\ndelete 2\ncontinue\n";
+    let (out, code) = debug("breakpoints.lox", commands);
+    assert_eq!(
+        out,
+        "script at line 4
+   4  }
+(rlox) Breakpoint at line 2.
+(rlox) add() at line 2
+   2    var sum = a + b;
+(rlox) a = 1
+b = 2
+(rlox) *#0   add() at line 2
+ #1   script at line 5
+(rlox) 3
+(rlox) add() at line 3
+   3    return sum;
+(rlox) a = 1
+b = 2
+sum = 3
+(rlox) script at line 5
+   5  var x = add(1, 2);
+(rlox) None.
+(rlox) (rlox) No breakpoint at 2.
+(rlox) 3
+7
+"
+    );
+    assert_eq!(code, 0);
+}
+
+// the end of the input ends the session, and with it the program
+#[test]
+fn stops_at_the_end_of_input() {
+    let (out, code) = debug("input.lox", "finish\n");
+    assert_eq!(out, "script at line 4\n   4  }\n(rlox) The script has no caller to return to.\n(rlox) \n");
+    assert_eq!(code, 0);
+}
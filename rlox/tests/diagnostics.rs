@@ -0,0 +1,82 @@
+// Compile errors as `--diagnostics=pretty` and `--diagnostics=json` print them.
+mod common;
+
+use std::fs;
+
+use common::*;
+
+// An error at a string that spans two lines, and one with a note on the line before
+// it, whose number is a digit shorter.
+const SOURCE: &str = "\
+// Errors for the diagnostics tests.
+print 1 \"one
+two\";
+
+fun f() {
+  print 1;
+  print 2;
+  print 3;
+  var a = 1;
+  var a = 2;
+}
+";
+
+fn diagnostics(format: &str) -> String {
+    let script = Scratch::new(&format!("diagnostics_{}.lox", format));
+    fs::write(&script.0, SOURCE).unwrap();
+    let output = rlox(&[&format!("--diagnostics={}", format), &script.path()]);
+    assert_eq!(stdout(&output), "");
+    assert_eq!(status(&output), 65);
+    stderr(&output)
+}
+
+#[test]
+fn pretty() {
+    assert_eq!(
+        diagnostics("pretty"),
+        "\
+error: Expect ';' after value.
+  --> 2:9
+  |
+2 | print 1 \"one
+  |         ^^^^
+
+error: Already variable with this name in this scope.
+   --> 10:7
+   |
+10 |   var a = 2;
+   |       ^
+note: variable declared here
+   --> 9:7
+   |
+ 9 |   var a = 1;
+   |       ^
+
+"
+    );
+}
+
+#[test]
+fn json() {
+    assert_eq!(
+        diagnostics("json"),
+        "\
+{\"severity\":\"error\",\"message\":\"Expect ';' after value.\",\"line\":2,\"column\":9,\"length\":9,\
+\"token\":\"\\\"one\\ntwo\\\"\",\"notes\":[]}
+{\"severity\":\"error\",\"message\":\"Already variable with this name in this scope.\",\"line\":10,\"column\":7,\
+\"length\":1,\"token\":\"a\",\"notes\":[{\"message\":\"variable declared here\",\"line\":9,\"column\":7,\"length\":1}]}
+"
+    );
+}
+
+#[test]
+fn plain() {
+    assert_eq!(
+        diagnostics("plain"),
+        "\
+[line 3] Error at '\"one
+two\"': Expect ';' after value.
+[line 10] Error at 'a': Already variable with this name in this scope.
+"
+    );
+}
@@ -0,0 +1,45 @@
+// `rlox difftest`, with rlox standing in for clox so that the two agree unless rlox is
+// run with options that change what it does.
+mod common;
+
+use common::*;
+
+fn clox() -> String {
+    format!("--clox={}", env!("CARGO_BIN_EXE_rlox"))
+}
+
+#[test]
+fn agrees_with_itself() {
+    let output = rlox(&["difftest", &clox(), &corpus("function")]);
+    assert_eq!(stdout(&output), "13 files, 0 differ\n");
+    assert_eq!(stderr(&output), "");
+    assert_eq!(status(&output), 0);
+}
+
+// with eight frames rlox cannot recurse deep enough
+#[test]
+fn lists_the_files_that_differ() {
+    let output = rlox(&["difftest", &clox(), "--limit-frames=8", &corpus("function")]);
+    let out = stdout(&output);
+    let lines = out.lines().map(|line| line.split_whitespace().collect::<Vec<_>>().join(" ")).collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        vec![
+            String::from("file clox rlox first difference"),
+            format!("{} ok runtime error output line 1: \"21\" vs nothing", corpus("function/local_recursion.lox")),
+            format!("{} ok runtime error output line 1: \"21\" vs nothing", corpus("function/recursion.lox")),
+            String::from("13 files, 2 differ"),
+        ]
+    );
+    assert_eq!(status(&output), 1);
+}
+
+#[test]
+fn needs_clox() {
+    let output = rlox(&["difftest", "--clox=/nonexistent/clox", &corpus("function")]);
+    assert_eq!(stdout(&output), "");
+    let errors = stderr(&output);
+    assert!(errors.starts_with("Could not run \"/nonexistent/clox\": "), "{}", errors);
+    assert!(errors.ends_with(" Build clox with make in clox/, or name it with --clox.\n"), "{}", errors);
+    assert_eq!(status(&output), 74);
+}
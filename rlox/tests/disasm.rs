@@ -0,0 +1,52 @@
+// `rlox disasm`, which lists the bytecode of a script and every function nested in it
+// without running any of it.
+mod common;
+
+use std::fs;
+
+use common::*;
+
+const SOURCE: &str = "\
+fun outer(a) {
+  var x = a;
+  fun middle(b) {
+    var y = b;
+    fun inner(c) {
+      print \"ran \" + x + y + c;
+    }
+    return inner;
+  }
+  return middle;
+}
+print outer(\"1\")(\"2\")(\"3\");
+";
+
+#[test]
+fn lists_every_nested_function() {
+    let script = Scratch::new("nested_closures.lox");
+    fs::write(&script.0, SOURCE).unwrap();
+    let output = rlox(&["disasm", &script.path()]);
+    assert_eq!(stderr(&output), "");
+    assert_eq!(status(&output), 0);
+    let listing = stdout(&output);
+
+    let headers = listing.lines().filter(|line| line.starts_with("== ")).collect::<Vec<_>>();
+    assert_eq!(headers, ["== <script> ==", "== outer/1 ==", "== middle/1 ==", "== inner/1 =="]);
+
+    // what each closure captures follows its CLOSURE instruction
+    let captures = listing
+        .split("== ")
+        .map(|function| {
+            function
+                .lines()
+                .filter(|line| line.contains("|                     "))
+                .map(|line| line.rsplit('|').next().unwrap().trim())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let none: &[&str] = &[];
+    assert_eq!(captures, [none, none, &["local 2"], &["upvalue 0", "local 2"], none]);
+
+    // the listing has the string constant, but not what printing it would
+    assert!(!listing.contains("ran 123"), "{}", listing);
+}
@@ -0,0 +1,49 @@
+// The tree-walking interpreter, and running a script on both engines to compare them.
+mod common;
+
+use std::fs;
+
+use common::*;
+
+// Calls nest on the native stack in the tree engine, which has to stop them before it
+// runs out, however many frames the limit allows.
+#[test]
+fn tree_reports_deep_recursion_as_a_stack_overflow() {
+    let script = Scratch::new("deep_recursion.lox");
+    fs::write(&script.0, "fun f(n) { return f(n + 1); }\nf(0);\n").unwrap();
+    let output = rlox(&["--engine=tree", "--limit-frames=1000000", &script.path()]);
+    assert_eq!(status(&output), 70);
+    let errors = stderr(&output);
+    assert!(errors.starts_with("Stack overflow.\n[line 1] in f()\n"), "{}", errors);
+    assert!(errors.ends_with("[line 1] in f()\n[line 2] in script\n"), "{}", errors);
+
+    // the VM runs out of stack slots first, so the traces differ
+    let output = rlox(&["--engine=diff", "--limit-frames=1000000", &script.path()]);
+    assert_eq!(status(&output), 1);
+    let report = stderr(&output);
+    assert!(report.starts_with("The engines differ at line "), "{}", report);
+    assert!(report.lines().next().unwrap().ends_with(" of the errors:"), "{}", report);
+}
+
+#[test]
+fn tree_runs_the_corpus() {
+    assert_eq!(failures(&expected_files(), &["--engine=tree"]), Vec::<String>::new());
+}
+
+// Both engines end every file of the corpus the same way, classes and all.
+#[test]
+fn engines_agree_on_the_corpus() {
+    let mut differences = Vec::new();
+    for file in lox_files() {
+        let output = rlox(&["--engine=diff", &file]);
+        if status(&output) == 1 || status(&output) == 101 {
+            differences.push(format!("{}: {}", file, stderr(&output)));
+        }
+    }
+    assert_eq!(differences, Vec::<String>::new());
+}
+
+#[test]
+fn diff_checks_the_corpus_expectations() {
+    assert_eq!(failures(&expected_files(), &["--engine=diff"]), Vec::<String>::new());
+}
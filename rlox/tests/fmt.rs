@@ -0,0 +1,51 @@
+// `rlox fmt` on every file of the corpus, each copied somewhere it may be rewritten.
+mod common;
+
+use std::fs;
+
+use common::*;
+
+// Formatting a file leaves one that `--check` accepts and that runs as the original
+// did; only a file with a token the scanner rejects may fail to format.
+#[test]
+fn formats_the_corpus() {
+    let copy = Scratch::new("fmt.lox");
+    let mut formatted = 0;
+    for file in lox_files() {
+        fs::copy(&file, &copy.0).unwrap();
+        let output = rlox(&["fmt", &copy.path()]);
+        if status(&output) != 0 {
+            let error = stderr(&output);
+            let could_not = format!("Could not format \"{}\":\n", copy.path());
+            assert!(error.starts_with(&could_not) && error.contains("Error"), "{}: {}", file, error);
+            continue;
+        }
+        assert_eq!(stderr(&output), "", "{}", file);
+
+        let output = rlox(&["fmt", "--check", &copy.path()]);
+        assert_eq!(stdout(&output), "", "{}", file);
+        assert_eq!(status(&output), 0, "{}", file);
+
+        // errors name lines, which formatting moves
+        let (original, copied) = (rlox(&[&file]), rlox(&[&copy.path()]));
+        assert_eq!(stdout(&copied), stdout(&original), "{}", file);
+        assert_eq!(status(&copied), status(&original), "{}", file);
+        formatted += 1;
+    }
+    assert!(formatted > 200, "only {} files formatted", formatted);
+}
+
+#[test]
+fn check_lists_unformatted_files() {
+    let copy = Scratch::new("unformatted.lox");
+    fs::write(&copy.0, "print 1+2;\n").unwrap();
+    let output = rlox(&["fmt", "--check", &copy.path()]);
+    assert_eq!(stdout(&output), format!("{}\n", copy.path()));
+    assert_eq!(status(&output), 1);
+    assert_eq!(fs::read_to_string(&copy.0).unwrap(), "print 1+2;\n");
+
+    let output = rlox(&["fmt", &copy.path()]);
+    assert_eq!(stdout(&output), "");
+    assert_eq!(status(&output), 0);
+    assert_eq!(fs::read_to_string(&copy.0).unwrap(), "print 1 + 2;\n");
+}
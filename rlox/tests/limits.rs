@@ -0,0 +1,58 @@
+// The `--limit-<name>` options, which lower the sizes clox gets from its operands and
+// fixed arrays.
+mod common;
+
+use std::fs;
+
+use common::*;
+
+// a limit of zero rejects the first local, upvalue or constant
+#[test]
+fn a_zero_limit_rejects_the_first_one() {
+    let script = Scratch::new("zero_limits.lox");
+    fs::write(&script.0, "{\n  var a;\n  fun f() { return a; }\n}\n").unwrap();
+    let cases = [
+        (
+            "--limit-locals=0",
+            "[line 2] Error at 'a': Too many local variables in function.\n\
+             [line 3] Error at 'f': Too many local variables in function.\n",
+        ),
+        ("--limit-upvalues=0", "[line 3] Error at 'a': Too many closure variables in function.\n"),
+        ("--limit-constants=0", "[line 3] Error at '}': Too many constants in one chunk.\n"),
+    ];
+    for (option, error) in cases {
+        let output = rlox(&[option, &script.path()]);
+        assert_eq!(stderr(&output), error, "{}", option);
+        assert_eq!(status(&output), 65, "{}", option);
+    }
+}
+
+#[test]
+fn lowers_the_argument_limit() {
+    let script = Scratch::new("arguments.lox");
+    fs::write(&script.0, "fun f(a, b, c) {}\nf(1, 2, 3);\n").unwrap();
+    let output = rlox(&["--limit-arguments=2", &script.path()]);
+    assert_eq!(
+        stderr(&output),
+        "[line 1] Error at 'c': Can't have more than 2 parameters.\n\
+         [line 2] Error at '3': Can't have more than 2 arguments.\n"
+    );
+    assert_eq!(status(&output), 65);
+}
+
+// the script itself needs a frame and a stack slot, and operands have a width
+#[test]
+fn rejects_limits_the_vm_cannot_honour() {
+    for option in ["--limit-frames=0", "--limit-stack=0", "--limit-arguments=256", "--limit-locals=257"] {
+        let output = rlox(&[option, &corpus("empty_file.lox")]);
+        assert_eq!(status(&output), 64, "{}", option);
+        assert!(stderr(&output).starts_with("Usage: rlox"), "{}", option);
+    }
+}
+
+#[test]
+fn allows_the_smallest_limits_that_run_a_script() {
+    let output = rlox(&["--limit-frames=1", "--limit-stack=1", &corpus("empty_file.lox")]);
+    assert_eq!(stderr(&output), "");
+    assert_eq!(status(&output), 0);
+}
@@ -0,0 +1,44 @@
+// `rlox lint` on the whole corpus, and on what only the binary knows about.
+mod common;
+
+use std::fs;
+
+use common::*;
+
+// Every file either lints clean or is listed with its findings, each on a line of its own.
+#[test]
+fn lints_the_corpus() {
+    let output = rlox(&["lint", &corpus("")]);
+    assert_eq!(stderr(&output), "");
+    assert_eq!(status(&output), 1);
+    let mut files = 0;
+    for line in stdout(&output).lines() {
+        if line.ends_with(".lox:") {
+            files += 1;
+        } else {
+            let (_, finding) = line.split_once("] ").unwrap();
+            let known = finding.starts_with("Error") || finding.starts_with("Warning");
+            assert!(line.starts_with("[line ") && known, "{}", line);
+        }
+    }
+    assert!(files > 50, "only {} files with findings", files);
+}
+
+#[test]
+fn prints_a_json_object_per_finding() {
+    let output = rlox(&["lint", "--diagnostics=json", &corpus("")]);
+    assert_eq!(status(&output), 1);
+    for line in stdout(&output).lines() {
+        assert!(line.starts_with("{\"severity\":\"") && line.ends_with('}'), "{}", line);
+    }
+}
+
+// the natives count as declared globals
+#[test]
+fn knows_the_natives() {
+    let script = Scratch::new("natives.lox");
+    fs::write(&script.0, "clock = nil;\nprint clock;\n").unwrap();
+    let output = rlox(&["lint", &script.path()]);
+    assert_eq!(stdout(&output), "");
+    assert_eq!(status(&output), 0);
+}
@@ -0,0 +1,159 @@
+// Compiled `.loxc` files: writing and reading them back, and the checks a file has to
+// pass before the VM runs it.
+mod common;
+
+use common::*;
+
+// A local function that calls itself captures the slot its closure is about to take.
+#[test]
+fn local_recursion() {
+    let output = rlox(&[&corpus("bytecode/local_recursion.loxc")]);
+    assert_eq!(stderr(&output), "");
+    assert_eq!(stdout(&output), "21\n");
+    assert_eq!(status(&output), 0);
+}
+
+// Each file under `test/bytecode/invalid/` has a valid header and checksum around a
+// script the verifier has to turn down.
+fn rejects(name: &str, message: &str) {
+    let path = corpus(&format!("bytecode/invalid/{}.loxc", name));
+    let output = rlox(&[&path]);
+    assert_eq!(stdout(&output), "");
+    assert_eq!(stderr(&output), format!("Invalid bytecode in \"{}\": {}\n", path, message));
+    assert_eq!(status(&output), 65);
+}
+
+// CONSTANT 1 with a single constant
+#[test]
+fn rejects_bad_constant() {
+    rejects("bad_constant", "in script at 0000: Constant 1 is out of range (1 constants).");
+}
+
+// GET_GLOBAL 1 with a single global name
+#[test]
+fn rejects_bad_global() {
+    rejects("bad_global", "in script at 0000: Global 1 is out of range (1 globals).");
+}
+
+// f() reads upvalue 0 but its CLOSURE captures nothing
+#[test]
+fn rejects_bad_upvalue() {
+    rejects("bad_upvalue", "in f() at 0000: Upvalue 0 is out of range (0 upvalues).");
+}
+
+// CLOSURE captures local slot 5 of a script holding only itself
+#[test]
+fn rejects_bad_capture() {
+    rejects("bad_capture", "in script at 0000: Captured local slot 5 is out of range (stack depth 1).");
+}
+
+// JUMP 16 past the end of a five byte script
+#[test]
+fn rejects_jump_out_of_range() {
+    rejects("bad_jump", "in script at 0000: Jump target 0019 is not an instruction.");
+}
+
+// LOOP 10 from offset 5, before the start of the script
+#[test]
+fn rejects_loop_out_of_range() {
+    rejects("bad_loop", "in script at 0002: Loop target 10 bytes back is not an instruction.");
+}
+
+// JUMP_IF_FALSE skips a NIL, so its target is reached with two stack depths
+#[test]
+fn rejects_stack_depth_mismatch() {
+    rejects("depth_mismatch", "in script at 0005: Stack depth is 3 on one path and 2 on another.");
+}
+
+// NIL, PRINT and nothing after
+#[test]
+fn rejects_missing_return() {
+    rejects("missing_return", "in script at 0001: Execution runs past the end of the code.");
+}
+
+// Compiling a file and running what was written gives what running the source does.
+#[test]
+fn round_trips_the_corpus() {
+    let compiled = Scratch::new("round_trip.loxc");
+    let mut checked = 0;
+    for file in lox_files() {
+        let source = rlox(&[&file]);
+        if status(&source) == 65 {
+            continue;
+        }
+        let output = rlox(&["compile", &file, "-o", &compiled.path()]);
+        assert_eq!(stderr(&output), "", "{}", file);
+        assert_eq!(status(&output), 0, "{}", file);
+
+        let loaded = rlox(&[&compiled.path()]);
+        assert_eq!(stdout(&loaded), stdout(&source), "{}", file);
+        assert_eq!(stderr(&loaded), stderr(&source), "{}", file);
+        assert_eq!(status(&loaded), status(&source), "{}", file);
+        checked += 1;
+    }
+    assert!(checked > 100, "only {} files compiled", checked);
+}
+
+// 32-bit FNV-1a, which the header checksums the payload with.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
+
+// Compiles a small script and loads it again after `damage` has changed the bytes.
+fn load_damaged<F>(name: &str, damage: F) -> (String, i32) where F: FnOnce(&mut Vec<u8>) {
+    let compiled = Scratch::new(name);
+    let output = rlox(&["compile", &corpus("closure/nested_closure.lox"), "-o", &compiled.path()]);
+    assert_eq!(status(&output), 0);
+    let mut bytes = std::fs::read(&compiled.0).unwrap();
+    damage(&mut bytes);
+    std::fs::write(&compiled.0, &bytes).unwrap();
+
+    let output = rlox(&[&compiled.path()]);
+    assert_eq!(stdout(&output), "");
+    let message = format!("Could not load \"{}\": ", compiled.path());
+    (stderr(&output).replacen(&message, "", 1), status(&output))
+}
+
+#[test]
+fn rejects_a_truncated_header() {
+    let (error, code) = load_damaged("truncated_header.loxc", |bytes| bytes.truncate(10));
+    assert_eq!(error, "Truncated header.\n");
+    assert_eq!(code, 65);
+}
+
+#[test]
+fn rejects_a_truncated_payload() {
+    let (error, code) = load_damaged("truncated_payload.loxc", |bytes| {
+        bytes.pop();
+    });
+    assert_eq!(error, "Payload length does not match the header.\n");
+    assert_eq!(code, 65);
+}
+
+#[test]
+fn rejects_another_version() {
+    let (error, code) = load_damaged("version.loxc", |bytes| bytes[5] += 1);
+    assert_eq!(error, "Unsupported bytecode version.\n");
+    assert_eq!(code, 65);
+}
+
+#[test]
+fn rejects_a_changed_payload() {
+    let (error, code) = load_damaged("changed_payload.loxc", |bytes| *bytes.last_mut().unwrap() ^= 1);
+    assert_eq!(error, "Checksum mismatch.\n");
+    assert_eq!(code, 65);
+}
+
+// a byte after the script, with the header's length and checksum counting it
+#[test]
+fn rejects_trailing_bytes() {
+    let (error, code) = load_damaged("trailing_bytes.loxc", |bytes| {
+        bytes.push(0);
+        let length = (bytes.len() - 14) as u32;
+        let sum = checksum(&bytes[14..]);
+        bytes[6..10].copy_from_slice(&sum.to_be_bytes());
+        bytes[10..14].copy_from_slice(&length.to_be_bytes());
+    });
+    assert_eq!(error, "Trailing bytes after the script.\n");
+    assert_eq!(code, 65);
+}
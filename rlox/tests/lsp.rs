@@ -0,0 +1,9 @@
+// Replays the sessions under `test/lsp/` against `rlox lsp`.
+mod common;
+
+use common::*;
+
+#[test]
+fn add() {
+    replay("lsp", "lsp/add.transcript");
+}
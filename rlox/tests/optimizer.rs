@@ -0,0 +1,17 @@
+// The optimizer must not change what any program does.
+mod common;
+
+use common::*;
+
+#[test]
+fn corpus_runs_the_same_optimized() {
+    let mut differ = Vec::new();
+    for file in lox_files() {
+        let plain = rlox(&["-O0", &file]);
+        let optimized = rlox(&["-O1", &file]);
+        if plain != optimized {
+            differ.push(file);
+        }
+    }
+    assert!(differ.is_empty(), "-O1 changes the outcome of {:?}", differ);
+}
@@ -0,0 +1,91 @@
+// `--profile`: the counts in its report and the stacks it writes for flame graphs.
+// Times differ from run to run, so only their shape is checked.
+mod common;
+
+use std::fs;
+
+use common::*;
+
+// The lines of a section of the report, from its heading up to the blank line after it.
+fn section<'a>(report: &'a str, heading: &str) -> Vec<&'a str> {
+    report.lines().skip_while(|line| *line != heading).skip(1).take_while(|line| !line.is_empty()).collect()
+}
+
+fn columns(line: &str) -> Vec<&str> {
+    line.split_whitespace().collect()
+}
+
+#[test]
+fn counts_calls_opcodes_and_lines() {
+    let output = rlox(&["--profile", &corpus("closure/nested_closure.lox")]);
+    assert_eq!(stdout(&output), "a\nb\nc\n");
+    assert_eq!(status(&output), 0);
+    let report = stderr(&output);
+
+    let functions = section(&report, "== functions ==");
+    assert_eq!(columns(functions[0]), vec!["function", "calls", "inclusive", "exclusive"]);
+    let mut calls = functions[1..].iter().map(|line| columns(line)[..2].join(" ")).collect::<Vec<_>>();
+    calls.sort();
+    assert_eq!(calls, vec!["f1 1", "f2 1", "f3 1", "f4 1", "script 1"]);
+    for line in &functions[1..] {
+        let columns = columns(line);
+        assert!(columns[2].ends_with("ms") && columns[3].ends_with("ms"), "{}", line);
+    }
+
+    // by count, then by name
+    let opcodes = section(&report, "== opcodes ==").iter().map(|line| columns(line).join(" ")).collect::<Vec<_>>();
+    assert_eq!(
+        opcodes,
+        vec![
+            "NIL 6 14.6%",
+            "POP 5 12.2%",
+            "RETURN 5 12.2%",
+            "CALL 4 9.8%",
+            "CLOSURE 4 9.8%",
+            "CONSTANT 3 7.3%",
+            "GET_LOCAL 3 7.3%",
+            "GET_UPVALUE 3 7.3%",
+            "PRINT 3 7.3%",
+            "DEFINE_GLOBAL 2 4.9%",
+            "GET_GLOBAL 2 4.9%",
+            "SET_GLOBAL 1 2.4%",
+        ]
+    );
+
+    let lines = section(&report, "== lines ==").iter().map(|line| columns(line).join(" ")).collect::<Vec<_>>();
+    assert_eq!(lines[..5], ["f1:18 3", "f2:16 3", "f3:14 3", "script:20 3", "script:22 3"]);
+    assert!(lines.contains(&String::from("f4:10 2")));
+}
+
+// Stacks that took no measurable time are left out, so the one function that does the work
+// loops a while.
+#[test]
+fn writes_collapsed_stacks() {
+    let script = Scratch::new("stacks.lox");
+    let program = "fun spin() { for (var i = 0; i < 500; i = i + 1) {} }\n\
+                   fun inner() { spin(); }\n\
+                   fun outer() { inner(); spin(); }\n\
+                   outer();\n\
+                   spin();\n";
+    fs::write(&script.0, program).unwrap();
+    let stacks = Scratch::new("stacks.txt");
+    let output = rlox(&[&format!("--profile={}", stacks.path()), &script.path()]);
+    assert_eq!(stdout(&output), "");
+    assert_eq!(status(&output), 0);
+
+    let text = fs::read_to_string(&stacks.0).unwrap();
+    let mut names = Vec::new();
+    for line in text.lines() {
+        let (stack, micros) = line.rsplit_once(' ').unwrap();
+        assert!(micros.parse::<u64>().unwrap() > 0, "{}", line);
+        names.push(stack);
+    }
+    let mut spinning = names.clone();
+    spinning.retain(|stack| stack.ends_with(";spin"));
+    assert_eq!(spinning, vec!["script;outer;inner;spin", "script;outer;spin", "script;spin"]);
+    // the callers took too little time of their own to be sure they show up
+    for stack in names {
+        let caller = ["script", "script;outer", "script;outer;inner"].contains(&stack);
+        assert!(caller || stack.ends_with(";spin"), "{}", stack);
+    }
+}
@@ -0,0 +1,37 @@
+// The REPL compiles each line on its own against the globals the earlier lines defined.
+mod common;
+
+use common::*;
+
+// What the REPL printed, without its prompts.
+fn printed(input: &str) -> (String, String) {
+    let output = rlox_with_input(&[], input);
+    assert_eq!(status(&output), 0);
+    (stdout(&output).replace("> ", ""), stderr(&output))
+}
+
+#[test]
+fn redefines_a_global() {
+    let (out, err) = printed("var a = 1;\nprint a;\nvar a = \"two\";\nprint a;\n");
+    assert_eq!(out, "1\ntwo\n\n");
+    assert_eq!(err, "");
+}
+
+// a function compiled before the global it reads is defined finds it once it is
+#[test]
+fn resolves_a_global_defined_on_a_later_line() {
+    let (out, err) = printed("fun f() { return b; }\nprint f();\nvar b = 3;\nprint f();\nb = 4;\nprint f();\n");
+    assert_eq!(out, "3\n4\n\n");
+    assert_eq!(err, "Undefined variable 'b'.\n[line 1] in f()\n[line 1] in script\n");
+}
+
+// an error leaves the globals as they were and the session going
+#[test]
+fn keeps_going_after_an_error() {
+    let (out, err) = printed("var a = 1;\nc = a;\nprint c;\nvar c = a + 1;\nprint c;\n");
+    assert_eq!(out, "2\n\n");
+    assert_eq!(
+        err,
+        "Undefined variable 'c'.\n[line 1] in script\nUndefined variable 'c'.\n[line 1] in script\n"
+    );
+}